@@ -1,10 +1,26 @@
-use anchor_lang::{prelude::*, solana_program::keccak};
+use anchor_lang::{
+    prelude::*,
+    solana_program::{
+        ed25519_program, keccak,
+        program::{set_return_data, MAX_RETURN_DATA},
+        sysvar::instructions::get_instruction_relative,
+    },
+    system_program::{
+        allocate, assign, create_account, transfer, Allocate, Assign, CreateAccount, Transfer,
+    },
+    Discriminator,
+};
+use anchor_spl::token::{Mint, TokenAccount};
+use miniz_oxide::deflate::compress_to_vec;
 use spl_account_compression::{
     cpi::{
-        accounts::{Initialize, Modify},
-        append, init_empty_merkle_tree,
+        accounts::{CloseTree, Initialize, Modify, VerifyLeaf},
+        append, close_empty_tree, init_empty_merkle_tree, replace_leaf, verify_leaf,
     },
     program::SplAccountCompression,
+    state::{
+        merkle_tree_get_size, ConcurrentMerkleTreeHeader, CONCURRENT_MERKLE_TREE_HEADER_SIZE_V1,
+    },
     wrap_application_data_v1, Noop,
 };
 declare_id!("TCxHVHUGREfiguKx9SuJsH9Dw6WQpFsRrEfHoXnNopT");
@@ -14,105 +30,4981 @@ pub mod anchor_compressed_notes {
     use super::*;
 
     // Instruction for creating a new note tree.
-    pub fn create_note_tree(
-        ctx: Context<NoteAccounts>,
-        max_depth: u32,       // Max depth of the merkle tree
-        max_buffer_size: u32, // Max buffer size of the merkle tree
-    ) -> Result<()> {
+    // The max depth must be at least MIN_DEPTH, unless a global config of the payer is passed as
+    // the first remaining account, in which case its min depth applies instead.
+    // The merkle tree account must be allocated with required_tree_space for the tree settings.
+    pub fn create_note_tree(ctx: Context<CreateNoteTree>, args: TreeConfigInitArgs) -> Result<()> {
+        // Check the tree authority pda wasn't passed as the payer
+        require_keys_neq!(
+            ctx.accounts.payer.key(),
+            ctx.accounts.tree_authority.key(),
+            NoteError::InvalidPayer
+        );
+
+        // Check the tree isn't shallower than the min depth
+        let min_depth = min_depth(ctx.remaining_accounts, &ctx.accounts.payer.key())?;
+        require_gte!(args.max_depth, min_depth, NoteError::DepthTooShallow);
+
+        // Check the merkle tree account was allocated with the space the tree needs, so a
+        // wrongly sized account is reported here rather than by the compression program
+        check_tree_account_size(&ctx.accounts.merkle_tree, &args)?;
+
         // Get the address for the merkle tree account
         let merkle_tree = ctx.accounts.merkle_tree.key();
+
+        // Save the tree settings to the config account
+        ctx.accounts.config.init(
+            ctx.accounts.payer.key(), // The payer who created the tree
+            merkle_tree,
+            *ctx.bumps.get("config").unwrap(),
+            None, // The tree authority is derived from the merkle tree
+            &args,
+        )?;
+
         // Define the seeds for pda signing
         let signer_seeds: &[&[&[u8]]] = &[&[
             merkle_tree.as_ref(), // The address of the merkle tree account as a seed
             &[*ctx.bumps.get("tree_authority").unwrap()], // The bump seed for the pda
         ]];
+        // CPI to initialize an empty merkle tree with given max depth and buffer size
+        init_tree(
+            &ctx.accounts.compression_program,
+            &ctx.accounts.tree_authority,
+            &ctx.accounts.merkle_tree,
+            &ctx.accounts.log_wrapper,
+            signer_seeds,
+            args.max_depth,
+            args.max_buffer_size,
+        )?;
 
-        // Create cpi context for init_empty_merkle_tree instruction.
-        let cpi_ctx = CpiContext::new_with_signer(
-            ctx.accounts.compression_program.to_account_info(), // The spl account compression program
-            Initialize {
-                authority: ctx.accounts.tree_authority.to_account_info(), // The authority for the merkle tree, using a PDA
-                merkle_tree: ctx.accounts.merkle_tree.to_account_info(), // The merkle tree account to be initialized
-                noop: ctx.accounts.log_wrapper.to_account_info(), // The noop program to log data
+        debug_log(1, "create_note_tree", &merkle_tree, None);
+
+        // Emit an event so indexers can discover the new tree without polling
+        emit!(TreeCreated {
+            tree: merkle_tree,
+            authority: ctx.accounts.payer.key(),
+            max_depth: args.max_depth,
+            max_buffer_size: args.max_buffer_size,
+            slot: now()?.1,
+        });
+
+        Ok(())
+    }
+
+    // Instruction for creating a new note tree with a genesis note as its first leaf, in one step,
+    // so the tree never exists without it. The genesis note is appended by the creator of the tree,
+    // so the allowlist isn't checked even for restricted trees, otherwise it's appended like any
+    // other note, see append_note_log.
+    pub fn create_note_tree_with_note<'info>(
+        ctx: Context<'_, '_, '_, 'info, CreateNoteTreeWithNote<'info>>,
+        args: TreeConfigInitArgs,
+        note: String,
+    ) -> Result<()> {
+        // Create the tree the same way as create_note_tree
+        create_note_tree(
+            Context::new(
+                ctx.program_id,
+                &mut ctx.accounts.create,
+                ctx.remaining_accounts,
+                ctx.bumps.clone(),
+            ),
+            args,
+        )?;
+
+        // Create a new "note log" from the note, tagged as the genesis note
+        let accounts = &mut ctx.accounts.create;
+        let mut note_log = NoteLog::new(note, accounts.config.hash_iterations)?;
+        note_log.genesis = true;
+
+        // Log the "note log" and append the genesis leaf node to the empty merkle tree, the same
+        // way as log_and_append without its payer checks, which create_note_tree already covers
+        append_note_log(
+            AppendAccounts {
+                author: accounts.payer.key(),
+                config: &mut accounts.config,
+                tree_authority: accounts.tree_authority.as_ref(),
+                merkle_tree: accounts.merkle_tree.as_ref(),
+                log_wrapper: accounts.log_wrapper.as_ref(),
+                compression_program: accounts.compression_program.as_ref(),
+                author_stats: &mut ctx.accounts.author_stats,
             },
-            signer_seeds, // The seeds for pda signing
+            *ctx.bumps.get("tree_authority").unwrap(),
+            &mut note_log,
+            LogCompression::None,
+        )
+    }
+
+    // Instruction for creating a global config, an authority that can create shared trees.
+    pub fn init_global_config(ctx: Context<InitGlobalConfig>) -> Result<()> {
+        ctx.accounts.global_config.set_inner(GlobalConfig {
+            authority: ctx.accounts.authority.key(),
+            bump: *ctx.bumps.get("global_config").unwrap(),
+            compression_program: None,
+            min_depth: MIN_DEPTH,
+        });
+        Ok(())
+    }
+
+    // Instruction for setting the min max depth of the trees created with a global config,
+    // for operators who want small trees, such as for testing.
+    pub fn set_min_depth(ctx: Context<UpdateGlobalConfig>, min_depth: u32) -> Result<()> {
+        ctx.accounts.global_config.min_depth = min_depth;
+        Ok(())
+    }
+
+    // Instruction for pointing the shared trees of a global config at a forked compression program,
+    // for clusters where it isn't deployed at the canonical address. None restores the canonical id.
+    // Only trees created after the change use it, the id is copied into each tree config at creation.
+    pub fn set_compression_program(
+        ctx: Context<UpdateGlobalConfig>,
+        compression_program: Option<Pubkey>,
+    ) -> Result<()> {
+        ctx.accounts.global_config.compression_program = compression_program;
+        Ok(())
+    }
+
+    // Instruction for creating a new note tree whose tree authority is shared with every other
+    // tree of the global config, only callable by the global config authority.
+    //
+    // Security: the shared tree authority pda is derived from the global config instead of the
+    // merkle tree, so one signature from it is valid for every shared tree of the global config.
+    // Signer seeds alone no longer bind an instruction to one tree, so each instruction must take
+    // the tree from an account validated against the config, as NoteAccounts does by deriving the
+    // config from the merkle tree and the tree authority from the config. Any future instruction
+    // signing with the shared authority without that check could modify every shared tree.
+    pub fn create_shared_tree(
+        ctx: Context<CreateSharedTree>,
+        args: TreeConfigInitArgs,
+    ) -> Result<()> {
+        // Get the addresses for the merkle tree and global config accounts
+        let merkle_tree = ctx.accounts.merkle_tree.key();
+        let global_config = ctx.accounts.global_config.key();
+
+        // Check the tree isn't shallower than the min depth of the global config
+        require_gte!(
+            args.max_depth,
+            ctx.accounts.global_config.min_depth,
+            NoteError::DepthTooShallow
         );
+        // Check the merkle tree account was allocated with the space the tree needs
+        check_tree_account_size(&ctx.accounts.merkle_tree, &args)?;
 
+        // Save the tree settings to the config account
+        ctx.accounts.config.init(
+            ctx.accounts.authority.key(), // The global config authority who created the tree
+            merkle_tree,
+            *ctx.bumps.get("config").unwrap(),
+            Some(global_config), // The tree authority is derived from the global config
+            &args,
+        )?;
+        // Use the compression program of the global config for the tree
+        ctx.accounts.config.compression_program = ctx.accounts.global_config.compression_program;
+
+        // Define the seeds for pda signing
+        let signer_seeds: &[&[&[u8]]] = &[&[
+            global_config.as_ref(), // The address of the global config account as a seed
+            &[*ctx.bumps.get("tree_authority").unwrap()], // The bump seed for the pda
+        ]];
         // CPI to initialize an empty merkle tree with given max depth and buffer size
-        init_empty_merkle_tree(cpi_ctx, max_depth, max_buffer_size)?;
+        init_tree(
+            &ctx.accounts.compression_program,
+            &ctx.accounts.tree_authority,
+            &ctx.accounts.merkle_tree,
+            &ctx.accounts.log_wrapper,
+            signer_seeds,
+            args.max_depth,
+            args.max_buffer_size,
+        )?;
+
+        // Emit an event so indexers can discover the new tree without polling
+        emit!(TreeCreated {
+            tree: merkle_tree,
+            authority: ctx.accounts.authority.key(),
+            max_depth: args.max_depth,
+            max_buffer_size: args.max_buffer_size,
+            slot: now()?.1,
+        });
 
         Ok(())
     }
 
     // Instruction for appending a note to a tree.
-    pub fn append_note(ctx: Context<NoteAccounts>, note: String) -> Result<()> {
-        // Hash the "note message" which will be stored as leaf node in the merkle tree
-        let leaf_node = keccak::hashv(&[note.as_bytes()]).to_bytes();
-        // Create a new "note log" using the leaf node hash and note.
-        let note_log = NoteLog::new(leaf_node.clone(), note);
-        // Log the "note log" data using noop program
-        wrap_application_data_v1(note_log.try_to_vec()?, &ctx.accounts.log_wrapper)?;
+    // When the tree has an auto pin interval and the note count reaches a multiple of it, the note
+    // is also pinned, see auto_pin_note, and the pinned note pda must be the first remaining account.
+    pub fn append_note<'info>(
+        ctx: Context<'_, '_, '_, 'info, NoteAccounts<'info>>,
+        note: String,
+    ) -> Result<()> {
+        // Create a new "note log" from the note, hashed into the leaf node stored in the merkle tree
+        let mut note_log = NoteLog::new(note, ctx.accounts.config.hash_iterations)?;
 
-        // Get the address for the merkle tree account
+        // Log the "note log" and append the leaf node to the merkle tree
+        log_and_append(
+            ctx.accounts,
+            *ctx.bumps.get("tree_authority").unwrap(),
+            &mut note_log,
+            LogCompression::None,
+        )?;
+        debug_log(
+            1,
+            "append_note",
+            &ctx.accounts.merkle_tree.key(),
+            Some(ctx.accounts.config.note_count - 1),
+        );
+
+        // Pin every auto pin interval-th note, paid by the payer. An interval of 0 has no remainder, so never pins
+        let auto_pin_interval = ctx.accounts.config.auto_pin_interval as u64;
+        if ctx
+            .accounts
+            .config
+            .note_count
+            .checked_rem(auto_pin_interval)
+            == Some(0)
+        {
+            let pinned_note = ctx
+                .remaining_accounts
+                .first()
+                .ok_or(NoteError::InvalidPinAccount)?;
+            auto_pin_note(ctx.accounts, pinned_note, ctx.program_id, &note_log)?;
+        }
+
+        // Return the serialized "note log" that was logged, without the compression header, so clients can cache what indexers see
+        set_note_log_return_data(&note_log)
+    }
+
+    // Instruction for appending a note that reports a rejected note with a status byte as return data,
+    // see AppendStatus, instead of failing the transaction, so a soft-fail batch of mixed-validity
+    // notes isn't rolled back entirely. Soft failures, returned as a status without appending, are
+    // the note checks: an empty note, a note past MAX_NOTE_LEN, control characters in a strict text
+    // tree and a note log past the tree's log budget. Everything else is still a hard failure that
+    // fails the transaction, such as a payer that isn't allowlisted, a duplicate leaf rejected by the
+    // tree, or a failed noop or compression program CPI. The program has no rate limits.
+    pub fn try_append_note(ctx: Context<NoteAccounts>, note: String) -> Result<()> {
+        let mut status = note_status(&ctx.accounts.config, &note);
+        if status == AppendStatus::Appended {
+            // Create a new "note log" from the note, hashed into the leaf node stored in the merkle tree
+            let mut note_log = NoteLog::new(note, ctx.accounts.config.hash_iterations)?;
+            // The logged bytes are the compression header and the serialized note log
+            if ctx
+                .accounts
+                .config
+                .fits_log_bytes(1 + note_log.try_to_vec()?.len())
+            {
+                // Log the "note log" and append the leaf node to the merkle tree
+                log_and_append(
+                    ctx.accounts,
+                    *ctx.bumps.get("tree_authority").unwrap(),
+                    &mut note_log,
+                    LogCompression::None,
+                )?;
+            } else {
+                status = AppendStatus::LogBudgetExceeded;
+            }
+        }
+        set_return_data_checked(&[status as u8])
+    }
+
+    // Instruction for appending a batch of notes, emitting one BatchAppended event summarizing the
+    // batch instead of one per note. The batch root commits to the leaf nodes of the batch in order,
+    // see batch_root, so consumers can verify the whole batch against one hash.
+    pub fn append_batch_summarized(ctx: Context<NoteAccounts>, notes: Vec<String>) -> Result<()> {
+        // Check the batch size is within the max batch size, bounding the compute spent on appends
+        require!(
+            (1..=MAX_BATCH_SIZE).contains(&notes.len()),
+            NoteError::InvalidBatchSize
+        );
+
+        let tree_authority_bump = *ctx.bumps.get("tree_authority").unwrap();
+        let mut leaf_nodes = Vec::with_capacity(notes.len());
+        for note in notes {
+            // Log the "note log" and append the leaf node to the merkle tree
+            let mut note_log = NoteLog::new(note, ctx.accounts.config.hash_iterations)?;
+            log_and_append(
+                ctx.accounts,
+                tree_authority_bump,
+                &mut note_log,
+                LogCompression::None,
+            )?;
+            leaf_nodes.push(note_log.leaf_node);
+        }
+
+        // Emit one event summarizing the batch, with the tree root after the last append
+        let (_, root) = current_root(&ctx.accounts.merkle_tree)?;
+        emit!(BatchAppended {
+            tree: ctx.accounts.merkle_tree.key(),
+            batch_root: batch_root(&leaf_nodes),
+            count: leaf_nodes.len() as u32,
+            root,
+        });
+        Ok(())
+    }
+
+    // Instruction for appending a note only if the tree hasn't moved too far from the given root,
+    // for clients that built state against that root. Fails with NoteError::ConcurrentWriteConflict
+    // once the root is no longer among the recent roots in the tree's change log buffer,
+    // in which case clients should refetch the root and retry.
+    pub fn append_note_at_root(
+        ctx: Context<NoteAccounts>,
+        root: [u8; 32],
+        note: String,
+    ) -> Result<()> {
+        // Check the root is still a recent root of the tree
+        require_recent_root(&ctx.accounts.merkle_tree, root)?;
+
+        // Create a new "note log" from the note, hashed into the leaf node stored in the merkle tree
+        let mut note_log = NoteLog::new(note, ctx.accounts.config.hash_iterations)?;
+
+        // Log the "note log" and append the leaf node to the merkle tree
+        log_and_append(
+            ctx.accounts,
+            *ctx.bumps.get("tree_authority").unwrap(),
+            &mut note_log,
+            LogCompression::None,
+        )
+    }
+
+    // Instruction for appending a note, returning its leaf index as little-endian u64 return data,
+    // for callers that would rather not parse the tree account. The index is the config's note
+    // count before the append, so it is only correct while every change to the tree goes through
+    // this program. A tree changed by CPIs bypassing it desyncs the count, and migrate_config
+    // reconciles it with the leaf count of the tree.
+    pub fn append_note_indexed(ctx: Context<NoteAccounts>, note: String) -> Result<()> {
+        // The leaf index of the note is the note count before the append
+        let index = ctx.accounts.config.note_count;
+
+        // Create a new "note log" from the note, hashed into the leaf node stored in the merkle tree
+        let mut note_log = NoteLog::new(note, ctx.accounts.config.hash_iterations)?;
+
+        // Log the "note log" and append the leaf node to the merkle tree
+        log_and_append(
+            ctx.accounts,
+            *ctx.bumps.get("tree_authority").unwrap(),
+            &mut note_log,
+            LogCompression::None,
+        )?;
+
+        set_return_data_checked(&index.to_le_bytes())
+    }
+
+    // Instruction for appending a genesis note, a note that can only be the first leaf of a tree.
+    // Fails with NoteError::NotEmpty if the tree already has a leaf, and tags the note log as genesis.
+    pub fn append_genesis_note(ctx: Context<NoteAccounts>, note: String) -> Result<()> {
+        // Check the tree has no leaves yet
+        require!(
+            rightmost_leaf(&ctx.accounts.merkle_tree)?.is_none(),
+            NoteError::NotEmpty
+        );
+
+        // Create a new "note log" from the note, tagged as the genesis note
+        let mut note_log = NoteLog::new(note, ctx.accounts.config.hash_iterations)?;
+        note_log.genesis = true;
+
+        // Log the "note log" and append the leaf node to the merkle tree
+        log_and_append(
+            ctx.accounts,
+            *ctx.bumps.get("tree_authority").unwrap(),
+            &mut note_log,
+            LogCompression::None,
+        )
+    }
+
+    // Instruction for appending a note and mirroring the new root of the tree into a root mirror pda,
+    // so other programs can read the current root directly without parsing the tree account.
+    // The root mirror is owned by this program and only written here, after the append signed by
+    // the tree authority, so it always holds a root the tree actually had.
+    pub fn append_note_mirrored(ctx: Context<MirroredNoteAccounts>, note: String) -> Result<()> {
+        // Create a new "note log" from the note, hashed into the leaf node stored in the merkle tree
+        let mut note_log = NoteLog::new(note, ctx.accounts.note_accounts.config.hash_iterations)?;
+
+        // Log the "note log" and append the leaf node to the merkle tree
+        log_and_append(
+            &mut ctx.accounts.note_accounts,
+            *ctx.bumps.get("tree_authority").unwrap(),
+            &mut note_log,
+            LogCompression::None,
+        )?;
+
+        // Mirror the root of the tree after the append
+        let (seq, root) = current_root(&ctx.accounts.note_accounts.merkle_tree)?;
+        let root_mirror = &mut ctx.accounts.root_mirror;
+        root_mirror.merkle_tree = ctx.accounts.note_accounts.merkle_tree.key();
+        root_mirror.seq = seq;
+        root_mirror.root = root;
+        Ok(())
+    }
+
+    // Instruction for a delegate appending a note authorized by its owner, so the owner doesn't sign or pay.
+    // The transaction must put an ed25519 program instruction right before this one, verifying the owner's
+    // signature over delegated_message of the tree, the note, the delegate and the owner's delegate nonce.
+    // The leaf node commits to the owner rather than the delegate, and the nonce is bumped so the
+    // authorization can't be replayed. The note is counted towards the owner, so the owner must
+    // be allowlisted on restricted trees, the delegate only pays. Fails with NoteError::NonceReused for the owner's last used
+    // authorization, and NoteError::InvalidSignature for any other authorization that doesn't match.
+    pub fn append_delegated(
+        ctx: Context<DelegatedNoteAccounts>,
+        note: String,
+        owner: Pubkey,
+        authorization_sig: [u8; 64],
+    ) -> Result<()> {
+        // Check the previous instruction verified the owner's signature over the current nonce
+        let delegate_nonce = &mut ctx.accounts.delegate_nonce;
+        let delegate = ctx.accounts.payer.key();
         let merkle_tree = ctx.accounts.merkle_tree.key();
+        let authorized =
+            |nonce| delegated_message(ctx.program_id, &merkle_tree, &note, &delegate, nonce);
+        let (pubkey, signature, message) = ed25519_instruction_parts(&ctx.accounts.instructions)?;
+        require!(
+            pubkey == owner.to_bytes() && signature == authorization_sig,
+            NoteError::InvalidSignature
+        );
+        if message != authorized(delegate_nonce.nonce) {
+            let reused = delegate_nonce
+                .nonce
+                .checked_sub(1)
+                .is_some_and(|nonce| message == authorized(nonce));
+            return Err(if reused {
+                NoteError::NonceReused
+            } else {
+                NoteError::InvalidSignature
+            }
+            .into());
+        }
+        delegate_nonce.owner = owner;
+        delegate_nonce.nonce += 1;
+
+        // Create a new "note log" from the owner and note, hashed into the leaf node stored in the merkle tree
+        let accounts = ctx.accounts;
+        let mut note_log = NoteLog::new_delegated(owner, note, accounts.config.hash_iterations)?;
+
+        // Check the tree authority pda wasn't passed as the payer, and the owner can append to the tree
+        require_keys_neq!(
+            accounts.payer.key(),
+            accounts.tree_authority.key(),
+            NoteError::InvalidPayer
+        );
+        check_allowlisted(&accounts.config, &accounts.allowlist, &owner)?;
+
+        // Log the "note log" and append the leaf node to the merkle tree, counted towards the owner
+        append_note_log(
+            AppendAccounts {
+                author: owner,
+                config: &mut accounts.config,
+                tree_authority: accounts.tree_authority.as_ref(),
+                merkle_tree: accounts.merkle_tree.as_ref(),
+                log_wrapper: accounts.log_wrapper.as_ref(),
+                compression_program: accounts.compression_program.as_ref(),
+                author_stats: &mut accounts.author_stats,
+            },
+            *ctx.bumps.get("tree_authority").unwrap(),
+            &mut note_log,
+            LogCompression::None,
+        )
+    }
+
+    // Instruction for appending a note stamped with the current wall clock time as its created_at,
+    // for append-only audit logs. The time is the Clock sysvar's unix timestamp, not the slot, and
+    // validator clock skew can move it backwards between appends, so the append fails with
+    // NoteError::NonMonotonicTime if it is before the last timestamped note of the tree. Timestamped
+    // notes are then logged in non-decreasing time order. Only the note is hashed into the leaf node.
+    pub fn append_timestamped_note(ctx: Context<NoteAccounts>, note: String) -> Result<()> {
+        // Create a new "note log" from the note stamped with the time, checking the time hasn't gone
+        // backwards since the last timestamped note
+        let config = &ctx.accounts.config;
+        let mut note_log =
+            NoteLog::new_timestamped(note, config.hash_iterations, config.last_timestamp)?;
+        let created_at = note_log.created_at;
+
+        // Log the "note log" and append the leaf node to the merkle tree
+        log_and_append(
+            ctx.accounts,
+            *ctx.bumps.get("tree_authority").unwrap(),
+            &mut note_log,
+            LogCompression::None,
+        )?;
+        ctx.accounts.config.last_timestamp = created_at;
+        Ok(())
+    }
+
+    // Instruction for appending a note with its note log deflate compressed before logging.
+    // Compressing on-chain costs extra compute, but roughly halves the logged bytes of text heavy notes.
+    // The leaf node is still the hash of the uncompressed note.
+    pub fn append_note_compressed(ctx: Context<NoteAccounts>, note: String) -> Result<()> {
+        // Create a new "note log" from the note, hashed into the leaf node stored in the merkle tree
+        let mut note_log = NoteLog::new(note, ctx.accounts.config.hash_iterations)?;
+
+        // Log the compressed "note log" and append the leaf node to the merkle tree
+        log_and_append(
+            ctx.accounts,
+            *ctx.bumps.get("tree_authority").unwrap(),
+            &mut note_log,
+            LogCompression::Deflate,
+        )
+    }
+
+    // Instruction for appending a note with a priority, logged for clients to sort notes by.
+    // The priority is only metadata in the note log, it isn't part of the leaf node hash.
+    pub fn append_note_with_priority(
+        ctx: Context<NoteAccounts>,
+        note: String,
+        priority: u8,
+    ) -> Result<()> {
+        // Create a new "note log" from the note, then set its priority
+        let mut note_log = NoteLog::new(note, ctx.accounts.config.hash_iterations)?;
+        note_log.priority = priority;
+
+        // Log the "note log" and append the leaf node to the merkle tree
+        log_and_append(
+            ctx.accounts,
+            *ctx.bumps.get("tree_authority").unwrap(),
+            &mut note_log,
+            LogCompression::None,
+        )
+    }
+
+    // Instruction for appending a note tagged with its content type, for renderers to branch on.
+    // The content type is only metadata in the note log, it isn't part of the leaf node hash.
+    pub fn append_typed_note(
+        ctx: Context<NoteAccounts>,
+        content_type: ContentType,
+        note: String,
+    ) -> Result<()> {
+        // Check the note has the shape of its content type
+        require!(content_type.matches_shape(&note), NoteError::InvalidContent);
+
+        // Create a new "note log" from the note, then set its content type
+        let mut note_log = NoteLog::new(note, ctx.accounts.config.hash_iterations)?;
+        note_log.content_type = content_type;
+
+        // Log the "note log" and append the leaf node to the merkle tree
+        log_and_append(
+            ctx.accounts,
+            *ctx.bumps.get("tree_authority").unwrap(),
+            &mut note_log,
+            LogCompression::None,
+        )
+    }
+
+    // Instruction for appending a note with arbitrary key-value metadata, for clients to extend notes
+    // without program changes. The metadata is only logged in the note log, only the note is hashed
+    // into the leaf node. The metadata is capped in entries and serialized size.
+    pub fn append_with_metadata(
+        ctx: Context<NoteAccounts>,
+        note: String,
+        metadata: Vec<MetadataEntry>,
+    ) -> Result<()> {
+        // Check the metadata fits within its max lengths
+        require!(
+            metadata.len() <= MAX_METADATA_ENTRIES,
+            NoteError::MetadataTooLarge
+        );
+        require!(
+            metadata.try_to_vec()?.len() <= MAX_METADATA_LEN,
+            NoteError::MetadataTooLarge
+        );
+
+        // Create a new "note log" from the note, then set its metadata
+        let mut note_log = NoteLog::new(note, ctx.accounts.config.hash_iterations)?;
+        note_log.metadata = metadata;
+
+        // Log the "note log" and append the leaf node to the merkle tree
+        log_and_append(
+            ctx.accounts,
+            *ctx.bumps.get("tree_authority").unwrap(),
+            &mut note_log,
+            LogCompression::None,
+        )
+    }
+
+    // Instruction for appending a note with a numeric value, such as a rating, for indexers to aggregate.
+    // The value is only metadata in the note log, it isn't part of the leaf node hash. A running sum
+    // and count of values is kept in the config, so an on-chain average is available from get_config.
+    pub fn append_rated_note(ctx: Context<NoteAccounts>, note: String, value: i64) -> Result<()> {
+        // Create a new "note log" from the note, then set its value
+        let mut note_log = NoteLog::new(note, ctx.accounts.config.hash_iterations)?;
+        note_log.value = value;
+
+        // Add the value to the running aggregates of the tree
+        let config = &mut ctx.accounts.config;
+        config.rating_sum = config
+            .rating_sum
+            .checked_add(value)
+            .ok_or(NoteError::ArithmeticOverflow)?;
+        config.rating_count = config
+            .rating_count
+            .checked_add(1)
+            .ok_or(NoteError::ArithmeticOverflow)?;
+
+        // Log the "note log" and append the leaf node to the merkle tree
+        log_and_append(
+            ctx.accounts,
+            *ctx.bumps.get("tree_authority").unwrap(),
+            &mut note_log,
+            LogCompression::None,
+        )
+    }
+
+    // Instruction for appending a note under a namespace, for grouping the notes of tenants sharing
+    // a tree. The namespace is committed into the leaf node with the note, see frame_namespaced,
+    // and logged in the note log so clients can filter notes by namespace.
+    pub fn append_namespaced_note(
+        ctx: Context<NoteAccounts>,
+        namespace: String,
+        note: String,
+    ) -> Result<()> {
+        // Check the namespace fits within the max namespace length
+        require!(
+            (1..=MAX_NAMESPACE_LEN).contains(&namespace.len()),
+            NoteError::InvalidNamespace
+        );
+
+        // Create a new "note log" from the namespace and note, hashed into the leaf node stored in the merkle tree
+        let mut note_log =
+            NoteLog::new_namespaced(namespace, note, ctx.accounts.config.hash_iterations)?;
+
+        // Log the "note log" and append the leaf node to the merkle tree
+        log_and_append(
+            ctx.accounts,
+            *ctx.bumps.get("tree_authority").unwrap(),
+            &mut note_log,
+            LogCompression::None,
+        )
+    }
+
+    // Instruction for appending a note with a title. The title is committed into the leaf node with
+    // the body, framed the same way as namespaced notes but with its own leaf tag, see LEAF_TAG_TITLED,
+    // and logged in the note log.
+    pub fn append_titled_note(
+        ctx: Context<NoteAccounts>,
+        title: String,
+        body: String,
+    ) -> Result<()> {
+        // Check the title fits within the max title length
+        require!(
+            (1..=MAX_TITLE_LEN).contains(&title.len()),
+            NoteError::InvalidTitle
+        );
+
+        // Create a new "note log" from the title and body, hashed into the leaf node stored in the merkle tree
+        let mut note_log = NoteLog::new_titled(title, body, ctx.accounts.config.hash_iterations)?;
+
+        // Log the "note log" and append the leaf node to the merkle tree
+        log_and_append(
+            ctx.accounts,
+            *ctx.bumps.get("tree_authority").unwrap(),
+            &mut note_log,
+            LogCompression::None,
+        )
+    }
+
+    // Instruction for changing the title of a titled note, replacing its leaf node in place.
+    // The body is still required since both leaf nodes hash it. The old leaf node is recomputed
+    // from the old title and body, and checked against the root with the full proof, max_depth
+    // proof node accounts, before replacing it. Only callable by the config authority.
+    pub fn update_title<'info>(
+        ctx: Context<'_, '_, '_, 'info, ReplaceLeaf<'info>>,
+        index: u32,
+        root: [u8; 32],
+        old_title: String,
+        body: String,
+        new_title: String,
+    ) -> Result<()> {
+        require!(
+            (1..=MAX_TITLE_LEN).contains(&new_title.len()),
+            NoteError::InvalidTitle
+        );
+        // Check the new title has no control characters, if the tree is strict about text
+        require!(
+            !ctx.accounts.config.strict_text || is_clean_text(&new_title),
+            NoteError::InvalidCharacters
+        );
+        require_eq!(
+            ctx.remaining_accounts.len(),
+            ctx.accounts.config.max_depth as usize,
+            NoteError::InvalidProofAccounts
+        );
+        // Check the root is still a recent root of the tree, so a stale root reports a conflict
+        require_recent_root(&ctx.accounts.merkle_tree, root)?;
+
+        // Recompute the old and new leaf nodes from the titles and the body, both with the domain
+        // tag of the index so the new leaf verifies like the old one, see stored_leaf
+        let config = &ctx.accounts.config;
+        let old_leaf = config.stored_leaf(
+            index,
+            NoteLog::new_titled(old_title.clone(), body.clone(), config.hash_iterations)?.leaf_node,
+        );
+        let new_leaf = config.stored_leaf(
+            index,
+            NoteLog::new_titled(new_title.clone(), body, config.hash_iterations)?.leaf_node,
+        );
+
+        // Check the old leaf node is the one stored at the index, by recomputing the root from the proof
+        let proof: Vec<[u8; 32]> = ctx
+            .remaining_accounts
+            .iter()
+            .map(|node| node.key().to_bytes())
+            .collect();
+        require!(
+            compute_root(old_leaf, &proof, index) == root,
+            NoteError::LeafMismatch
+        );
+
         // Define the seeds for pda signing
+        let tree_authority_seed = ctx.accounts.config.tree_authority_seed();
         let signer_seeds: &[&[&[u8]]] = &[&[
-            merkle_tree.as_ref(), // The address of the merkle tree account as a seed
+            tree_authority_seed.as_ref(), // The tree authority seed of the config
             &[*ctx.bumps.get("tree_authority").unwrap()], // The bump seed for the pda
         ]];
+        // CPI to replace the old leaf node with the new one
+        replace_note_leaf(
+            &ctx.accounts.compression_program,
+            &ctx.accounts.tree_authority,
+            &ctx.accounts.merkle_tree,
+            &ctx.accounts.log_wrapper,
+            signer_seeds,
+            ctx.remaining_accounts,
+            root,
+            old_leaf,
+            new_leaf,
+            index,
+        )?;
 
-        // Create a new cpi context and append the leaf node to the merkle tree.
-        let cpi_ctx = CpiContext::new_with_signer(
-            ctx.accounts.compression_program.to_account_info(), // The spl account compression program
-            Modify {
-                authority: ctx.accounts.tree_authority.to_account_info(), // The authority for the merkle tree, using a PDA
-                merkle_tree: ctx.accounts.merkle_tree.to_account_info(), // The merkle tree account to be modified
-                noop: ctx.accounts.log_wrapper.to_account_info(), // The noop program to log data
-            },
-            signer_seeds, // The seeds for pda signing
+        // Emit an event so indexers can update the title of the note
+        emit!(NoteUpdated {
+            tree: ctx.accounts.merkle_tree.key(),
+            index,
+            old_title,
+            new_title,
+            leaf_node: new_leaf,
+        });
+        Ok(())
+    }
+
+    // Instruction for swapping the positions of two notes, such as to reorder them, by replacing
+    // each one's leaf node with the other's. Takes the same accounts as update_title, so it's only
+    // callable by the config authority. Both notes are checked against the root before either is
+    // replaced, so if either doesn't match the whole instruction fails and nothing is swapped.
+    // The remaining accounts are both full proofs against the root, the max_depth proof node
+    // accounts of index_a followed by the max_depth proof node accounts of index_b.
+    // Replacing the first leaf changes the root, and the node of the second proof where the two
+    // paths meet, so the second proof doesn't match the new root. The second replace is still
+    // submitted against the given root: the compression program finds it in the change log buffer
+    // and fast-forwards the proof over the first replace. The resulting root is then checked
+    // against the one computed here from both proofs.
+    pub fn swap_notes<'info>(
+        ctx: Context<'_, '_, '_, 'info, ReplaceLeaf<'info>>,
+        index_a: u32,
+        index_b: u32,
+        root: [u8; 32],
+        note_a: String,
+        note_b: String,
+    ) -> Result<()> {
+        require_neq!(index_a, index_b, NoteError::SameIndex);
+        let proof_len = ctx.accounts.config.max_depth as usize;
+        require_eq!(
+            ctx.remaining_accounts.len(),
+            2 * proof_len,
+            NoteError::InvalidProofAccounts
+        );
+        // Check the root is still a recent root of the tree, so a stale root reports a conflict
+        require_recent_root(&ctx.accounts.merkle_tree, root)?;
+
+        // Check both leaf nodes are the ones stored at their index, by recomputing the root from each proof
+        let config = &ctx.accounts.config;
+        let leaf_a = config.note_leaf(&note_a, index_a)?;
+        let leaf_b = config.note_leaf(&note_b, index_b)?;
+        let (proof_accounts_a, proof_accounts_b) = ctx.remaining_accounts.split_at(proof_len);
+        let proof_a: Vec<[u8; 32]> = proof_accounts_a
+            .iter()
+            .map(|node| node.key().to_bytes())
+            .collect();
+        let mut proof_b: Vec<[u8; 32]> = proof_accounts_b
+            .iter()
+            .map(|node| node.key().to_bytes())
+            .collect();
+        require!(
+            compute_root(leaf_a, &proof_a, index_a) == root
+                && compute_root(leaf_b, &proof_b, index_b) == root,
+            NoteError::LeafMismatch
+        );
+
+        // The leaf nodes of the notes moved to the other index, with the domain tag of that index,
+        // so a tree that rotated its tag swaps the notes rather than the leaf nodes
+        let moved_b = config.note_leaf(&note_b, index_a)?;
+        let moved_a = config.note_leaf(&note_a, index_b)?;
+
+        // The root after both replaces. After the first one, the second proof's node at the level
+        // the paths meet, the highest bit the indices differ in, is the first path's new node there
+        let meet_level = (u32::BITS - 1 - (index_a ^ index_b).leading_zeros()) as usize;
+        proof_b[meet_level] = compute_root(moved_b, &proof_a[..meet_level], index_a);
+        let swapped_root = compute_root(moved_a, &proof_b, index_b);
+
+        // Define the seeds for pda signing
+        let tree_authority_seed = ctx.accounts.config.tree_authority_seed();
+        let signer_seeds: &[&[&[u8]]] = &[&[
+            tree_authority_seed.as_ref(), // The tree authority seed of the config
+            &[*ctx.bumps.get("tree_authority").unwrap()], // The bump seed for the pda
+        ]];
+        // CPI to replace each leaf node with the other note's, both against the given root
+        for (proof, index, previous_leaf, new_leaf) in [
+            (proof_accounts_a, index_a, leaf_a, moved_b),
+            (proof_accounts_b, index_b, leaf_b, moved_a),
+        ] {
+            replace_note_leaf(
+                &ctx.accounts.compression_program,
+                &ctx.accounts.tree_authority,
+                &ctx.accounts.merkle_tree,
+                &ctx.accounts.log_wrapper,
+                signer_seeds,
+                proof,
+                root,
+                previous_leaf,
+                new_leaf,
+                index,
+            )?;
+        }
+        require!(
+            current_root(&ctx.accounts.merkle_tree)?.1 == swapped_root,
+            NoteError::LeafMismatch
         );
-        // CPI to append the leaf node to the merkle tree
-        append(cpi_ctx, leaf_node)?;
 
+        // Emit an event with both new leaf nodes so indexers can move both notes
+        emit!(NotesSwapped {
+            tree: ctx.accounts.merkle_tree.key(),
+            index_a,
+            index_b,
+            leaf_a: moved_b,
+            leaf_b: moved_a,
+        });
         Ok(())
     }
-}
 
-#[derive(Accounts)]
-pub struct NoteAccounts<'info> {
-    // The payer for the transaction
-    #[account(mut)]
-    pub payer: Signer<'info>,
+    // Instruction for appending a note to the hash chain of the tree, for tamper-evident sequential logs.
+    // The leaf node commits to the previous chain tip, so an auditor holding the chained notes can
+    // verify their continuity without merkle proofs. The chain starts from a zeroed tip and skips
+    // notes appended with the other instructions, and the hash iterations setting doesn't apply.
+    pub fn append_chained_note(ctx: Context<NoteAccounts>, note: String) -> Result<()> {
+        // Create a new "note log" from the note, hashed with the current chain tip into the leaf node
+        let mut note_log = NoteLog::new_chained(ctx.accounts.config.chain_tip, note)?;
 
-    // The pda authority for the merkle tree, only used for signing
-    #[account(
-        seeds = [merkle_tree.key().as_ref()],
-        bump,
-    )]
-    pub tree_authority: SystemAccount<'info>,
+        // Log the "note log" and append the leaf node to the merkle tree
+        log_and_append(
+            ctx.accounts,
+            *ctx.bumps.get("tree_authority").unwrap(),
+            &mut note_log,
+            LogCompression::None,
+        )?;
 
-    // The merkle tree account
-    /// CHECK: This account is validated by the spl account compression program
-    #[account(mut)]
-    pub merkle_tree: UncheckedAccount<'info>,
+        // The leaf node is the new chain tip
+        ctx.accounts.config.chain_tip = note_log.leaf_node;
+        Ok(())
+    }
 
-    // The noop program to log data
-    pub log_wrapper: Program<'info, Noop>,
+    // Instruction for appending a reply to a parent note, hashed with the parent leaf node into its leaf
+    // node under its own tag, see LEAF_TAG_REPLY. The reply count of the parent is kept in a pda
+    // derived from the parent leaf, created on the first reply, so threads get aggregate counts
+    // without touching the tree. The parent isn't verified, so a reply to any leaf node, even one that was never appended,
+    // is appended and counted; use append_if_verified to prove the parent is in the tree.
+    pub fn append_reply(
+        ctx: Context<ReplyAccounts>,
+        parent_leaf: [u8; 32],
+        note: String,
+    ) -> Result<()> {
+        // Create a new "note log" from the parent leaf and note, hashed into the leaf node stored in the merkle tree
+        let mut note_log = NoteLog::new_reply(parent_leaf, note)?;
 
-    // The spl account compression program
-    pub compression_program: Program<'info, SplAccountCompression>,
-}
+        // Log the "note log" and append the leaf node to the merkle tree
+        log_and_append(
+            &mut ctx.accounts.note_accounts,
+            *ctx.bumps.get("tree_authority").unwrap(),
+            &mut note_log,
+            LogCompression::None,
+        )?;
 
-// Define a schema for data that will be logged using noop program
-#[derive(AnchorSerialize)]
-pub struct NoteLog {
-    leaf_node: [u8; 32], // The leaf node hash
-    note: String,        // The note message
-}
+        // Count the reply towards the parent
+        let reply_count = &mut ctx.accounts.reply_count;
+        reply_count.merkle_tree = ctx.accounts.note_accounts.merkle_tree.key();
+        reply_count.parent_leaf = parent_leaf;
+        reply_count.count = reply_count
+            .count
+            .checked_add(1)
+            .ok_or(NoteError::ArithmeticOverflow)?;
+        Ok(())
+    }
 
-impl NoteLog {
-    // Constructs a new note from given leaf node and message
-    pub fn new(leaf_node: [u8; 32], note: String) -> Self {
-        Self { leaf_node, note }
+    // Read-only instruction returning the reply count of a parent leaf as a little-endian u64 return data,
+    // 0 if the parent has no replies yet. Intended for simulation.
+    pub fn get_reply_count(ctx: Context<GetReplyCount>, _parent_leaf: [u8; 32]) -> Result<()> {
+        // The reply count account is only created on the first reply
+        let count = if ctx.accounts.reply_count.data_is_empty() {
+            0
+        } else {
+            Account::<ReplyCount>::try_from(&ctx.accounts.reply_count)?.count
+        };
+        set_return_data_checked(&count.to_le_bytes())
+    }
+
+    // Instruction for appending a note that depends on a note already in the tree. The referenced
+    // note is verified against the given root at the given leaf index, using the proof in the
+    // remaining accounts, and the whole instruction fails if it doesn't verify. The new note is
+    // then hashed with the referenced leaf node into its leaf node, committing to the dependency.
+    pub fn append_if_verified<'info>(
+        ctx: Context<'_, '_, '_, 'info, NoteAccounts<'info>>,
+        ref_index: u32,
+        ref_root: [u8; 32],
+        ref_note: String,
+        new_note: String,
+    ) -> Result<()> {
+        // Hash the referenced note to get the leaf node to verify
+        let ref_leaf = ctx.accounts.config.note_leaf(&ref_note, ref_index)?;
+        // CPI to verify the referenced leaf node against the root using the proof in the remaining accounts
+        verify_note_leaf(
+            &ctx.accounts.compression_program,
+            &ctx.accounts.merkle_tree,
+            ctx.remaining_accounts,
+            ref_root,
+            ref_leaf,
+            ref_index,
+        )?;
+
+        // Create a new "note log" from the new note, hashed with the referenced leaf node into the leaf node
+        let mut note_log = NoteLog::new_dependent(ref_leaf, new_note)?;
+
+        // Log the "note log" and append the leaf node to the merkle tree
+        log_and_append(
+            ctx.accounts,
+            *ctx.bumps.get("tree_authority").unwrap(),
+            &mut note_log,
+            LogCompression::None,
+        )
+    }
+
+    // Instruction for appending a leaf node given as a 64 character hex string, for content addressing
+    // pipelines that already hashed the note. The decoded bytes are hashed once under their own tag,
+    // see LEAF_TAG_RAW, so a raw leaf can't be the leaf node of any other variant.
+    // Explicit rather than detected in append_note, since any note could look like hex.
+    pub fn append_raw_hex_leaf(ctx: Context<NoteAccounts>, hex: String) -> Result<()> {
+        // Decode the hex string, failing on invalid hex
+        let raw_leaf = decode_hex_leaf(&hex)?;
+
+        // Create a new "note log" from the hex string, with the decoded bytes hashed into the leaf node
+        let mut note_log = NoteLog::new_raw_leaf(hex, raw_leaf);
+
+        // Log the "note log" and append the leaf node to the merkle tree
+        log_and_append(
+            ctx.accounts,
+            *ctx.bumps.get("tree_authority").unwrap(),
+            &mut note_log,
+            LogCompression::None,
+        )
+    }
+
+    // Instruction for appending a raw bytes note to a tree, for payloads that aren't UTF-8.
+    pub fn append_bytes(ctx: Context<NoteAccounts>, data: Vec<u8>) -> Result<()> {
+        // Create a new "note log" from the raw bytes, hashed into the leaf node stored in the merkle tree.
+        // The data is checked the same as a text note, see check_note_bytes
+        let mut note_log = NoteLog::new_bytes(data, ctx.accounts.config.hash_iterations)?;
+
+        // Log the "note log" and append the leaf node to the merkle tree
+        log_and_append(
+            ctx.accounts,
+            *ctx.bumps.get("tree_authority").unwrap(),
+            &mut note_log,
+            LogCompression::None,
+        )
+    }
+
+    // Instruction for appending a note to a tree, only if the payer holds enough of the tree's gate token.
+    pub fn append_note_gated(
+        ctx: Context<GatedNoteAccounts>,
+        note: String,
+        min_balance: u64,
+    ) -> Result<()> {
+        // Check the token account is for the gate mint configured for the tree
+        let gate_mint = ctx
+            .accounts
+            .note_accounts
+            .config
+            .gate_mint
+            .ok_or(NoteError::GateNotConfigured)?;
+        require_keys_eq!(
+            ctx.accounts.gate_mint.key(),
+            gate_mint,
+            NoteError::GateMintMismatch
+        );
+        // Check the payer holds at least the minimum balance of the gate token
+        require_gte!(
+            ctx.accounts.gate_token_account.amount,
+            min_balance,
+            NoteError::InsufficientGateBalance
+        );
+
+        let accounts = &mut ctx.accounts.note_accounts;
+        // Create a new "note log" from the note, hashed into the leaf node stored in the merkle tree
+        let mut note_log = NoteLog::new(note, accounts.config.hash_iterations)?;
+
+        // Log the "note log" and append the leaf node to the merkle tree
+        log_and_append(
+            accounts,
+            *ctx.bumps.get("tree_authority").unwrap(),
+            &mut note_log,
+            LogCompression::None,
+        )
+    }
+
+    // Staged notes allow appending a note larger than fits in a single transaction, in two phases:
+    // 1. init_staging creates a staging account for the payer sized for the full note length,
+    //    then write_staging is called in as many transactions as needed, each appending the next
+    //    chunk of the note bytes in order.
+    // 2. finalize_staged_note checks all bytes were written, appends the full note to the tree
+    //    and closes the staging account, refunding its rent to the payer.
+    // Each payer has one staging account per tree, so one staged note at a time.
+
+    // Instruction for creating the staging account for a note of the given total length in bytes.
+    pub fn init_staging(ctx: Context<InitStaging>, total_len: u32) -> Result<()> {
+        // Check the note fits within the max staged note length
+        require!(
+            (1..=MAX_STAGED_NOTE_LEN).contains(&(total_len as usize)),
+            NoteError::StagedNoteTooLong
+        );
+
+        let staging = &mut ctx.accounts.staging;
+        staging.payer = ctx.accounts.payer.key(); // The payer who stages the note
+        staging.merkle_tree = ctx.accounts.merkle_tree.key(); // The tree the note will be appended to
+        staging.total_len = total_len; // The full length of the note
+        Ok(())
+    }
+
+    // Instruction for writing the next chunk of a staged note.
+    pub fn write_staging(ctx: Context<WriteStaging>, chunk: Vec<u8>) -> Result<()> {
+        let staging = &mut ctx.accounts.staging;
+        // Check the chunk doesn't write past the full note length
+        require!(
+            staging.data.len() + chunk.len() <= staging.total_len as usize,
+            NoteError::StagingOverflow
+        );
+
+        staging.data.extend_from_slice(&chunk);
+        Ok(())
+    }
+
+    // Instruction for appending a fully written staged note to the tree and closing the staging account.
+    pub fn finalize_staged_note(ctx: Context<FinalizeStagedNote>) -> Result<()> {
+        let staging = &ctx.accounts.staging;
+        // Check every chunk of the note was written
+        require_eq!(
+            staging.data.len(),
+            staging.total_len as usize,
+            NoteError::StagingIncomplete
+        );
+        // Reassemble the note message from the staged bytes
+        let note = String::from_utf8(staging.data.clone()).map_err(|_| NoteError::InvalidUtf8)?;
+
+        let accounts = &mut ctx.accounts.note_accounts;
+        // Create a new "note log" from the note, hashed into the leaf node stored in the merkle tree
+        let mut note_log = NoteLog::new_staged(note, accounts.config.hash_iterations);
+
+        // Log the "note log" and append the leaf node to the merkle tree
+        log_and_append(
+            accounts,
+            *ctx.bumps.get("tree_authority").unwrap(),
+            &mut note_log,
+            LogCompression::None,
+        )?;
+
+        // Return the serialized "note log" like append_note, staged notes are usually too long to return in full
+        set_note_log_return_data(&note_log)?;
+
+        // Close the staging account, refunding the rent to the payer
+        ctx.accounts
+            .staging
+            .close(ctx.accounts.note_accounts.payer.to_account_info())
+    }
+
+    // Instruction for pinning a note to a directly readable account, seeded by its leaf index.
+    // The pinned note is a convenience mirror, the compressed leaf in the tree is untouched.
+    // The note isn't verified against the tree, so verified pins are seeded apart, see verify_and_pin.
+    pub fn pin_note(ctx: Context<PinNote>, index: u32, note: String) -> Result<()> {
+        // Hash the note the same way as append_note, so the mirror carries the matching leaf node
+        let leaf_node = ctx.accounts.config.note_leaf(&note, index)?;
+
+        // Save the note data to the pinned note account, the payer is recorded as the author
+        ctx.accounts.pinned_note.set_inner(Note {
+            author: ctx.accounts.payer.key(),
+            merkle_tree: ctx.accounts.merkle_tree.key(),
+            index,
+            leaf_node,
+            note,
+        });
+
+        Ok(())
+    }
+
+    // Instruction for closing a pinned note and refunding its rent, only callable by the author of the pin.
+    // Only the pinned mirror is removed, the compressed leaf in the tree is untouched.
+    pub fn unpin_own_note(_ctx: Context<UnpinOwnNote>, _index: u32) -> Result<()> {
+        // The author check and closing the account are handled by the account constraints
+        Ok(())
+    }
+
+    // Instruction for verifying a note is stored in a tree at the given index.
+    // The proof for the leaf is passed as remaining accounts, one account per proof node.
+    pub fn verify_note<'info>(
+        ctx: Context<'_, '_, '_, 'info, VerifyNote<'info>>,
+        index: u32,
+        root: [u8; 32],
+        note: String,
+        canopy_proof_only: bool, // Verify using only the tree's canopy, without proof accounts
+    ) -> Result<()> {
+        // Hash the note to get the leaf node to verify
+        let leaf_node = ctx.accounts.config.note_leaf(&note, index)?;
+
+        // With a canopy covering every level of the tree, the compression program fills in the
+        // whole proof from the canopy, so no proof accounts are needed
+        let proof = if canopy_proof_only {
+            require_gte!(
+                tree_canopy_depth(&ctx.accounts.merkle_tree, &ctx.accounts.config)?,
+                ctx.accounts.config.max_depth,
+                NoteError::CanopyTooShallow
+            );
+            &[]
+        } else {
+            ctx.remaining_accounts
+        };
+
+        // CPI to verify the leaf node against the root using the proof
+        verify_note_leaf(
+            &ctx.accounts.compression_program,
+            &ctx.accounts.merkle_tree,
+            proof,
+            root,
+            leaf_node,
+            index,
+        )
+    }
+
+    // Instruction for verifying a note is stored in a tree at the given index, with the proof passed
+    // inline as an argument instead of as remaining accounts. The compression program only takes
+    // proofs as accounts, which a CPI can't synthesize since they must be in the transaction, so the
+    // root is recomputed from the proof here and must be the tree's current root, failing with
+    // NoteError::RootNotCurrent otherwise. Unlike verify_leaf, a proof against an older root isn't
+    // fast-forwarded over later changes, so a note replaced since then doesn't verify here.
+    // The proof takes the same transaction space either way, but clients holding the proof as
+    // bytes skip building accounts. The canopy isn't used, so the proof must be full,
+    // expected_proof_len nodes, and fails with NoteError::InvalidProofLength otherwise.
+    pub fn verify_note_inline(
+        ctx: Context<VerifyNote>,
+        index: u32,
+        root: [u8; 32],
+        note: String,
+        proof: Vec<[u8; 32]>,
+    ) -> Result<()> {
+        require_eq!(
+            proof.len(),
+            expected_proof_len(&ctx.accounts.config),
+            NoteError::InvalidProofLength
+        );
+        // The tree isn't validated by a CPI, so check it is owned by the compression program of the tree
+        require_keys_eq!(
+            *ctx.accounts.merkle_tree.owner,
+            ctx.accounts.config.compression_program_id(),
+            ErrorCode::ConstraintOwner
+        );
+
+        // Hash the note, then recompute the root from the leaf node and the proof
+        let leaf_node = ctx.accounts.config.note_leaf(&note, index)?;
+        require!(
+            compute_root(leaf_node, &proof, index) == root,
+            NoteError::LeafMismatch
+        );
+        require!(
+            current_root(&ctx.accounts.merkle_tree)?.1 == root,
+            NoteError::RootNotCurrent
+        );
+        Ok(())
+    }
+
+    // Instruction for verifying a leaf index is empty, holding the zero leaf node, against the root,
+    // such as to confirm a slot was cleared. The inverse of verify_note, taking the proof the same way.
+    // With the full proof, max_depth proof node accounts, against a recent root, an index fails with
+    // NoteError::NotEmpty only if the proof verifies a known nonzero leaf at it: a leaf set at the
+    // index by a change log in the tree's buffer, or cached in the config's recent leaves.
+    // Any other mismatch, such as a stale root, a wrong proof, or an occupied index whose leaf
+    // isn't known, is reported by the compression program's check.
+    pub fn verify_empty<'info>(
+        ctx: Context<'_, '_, '_, 'info, VerifyNote<'info>>,
+        index: u32,
+        root: [u8; 32],
+    ) -> Result<()> {
+        let empty_leaf = [0; 32];
+
+        // With the full proof against a recent root, report an occupied index if the proof verifies
+        // a known nonzero leaf at it. Other mismatches fall through to the compression program
+        if ctx.remaining_accounts.len() == ctx.accounts.config.max_depth as usize
+            && is_recent_root(&ctx.accounts.merkle_tree, root)?
+        {
+            let proof: Vec<[u8; 32]> = ctx
+                .remaining_accounts
+                .iter()
+                .map(|node| node.key().to_bytes())
+                .collect();
+            let recent_leaves = ctx
+                .accounts
+                .config
+                .recent_leaves_newest_first()
+                .into_iter()
+                .filter(|recent_leaf| recent_leaf.index == index)
+                .map(|recent_leaf| recent_leaf.leaf_node);
+            let mut known_leaves = change_log_leaves(&ctx.accounts.merkle_tree, index)?
+                .into_iter()
+                .chain(recent_leaves);
+            require!(
+                !known_leaves
+                    .any(|leaf| leaf != empty_leaf && compute_root(leaf, &proof, index) == root),
+                NoteError::NotEmpty
+            );
+        }
+
+        // CPI to verify the zero leaf node against the root using the proof in the remaining accounts
+        verify_note_leaf(
+            &ctx.accounts.compression_program,
+            &ctx.accounts.merkle_tree,
+            ctx.remaining_accounts,
+            root,
+            empty_leaf,
+            index,
+        )
+    }
+
+    // Instruction for verifying a note and pinning it in one step, so only notes
+    // actually stored in the tree can be pinned. If verification fails, the whole
+    // instruction fails and no pinned note account is created. Verified pins are seeded
+    // with a "verified" prefix, so an unverified pin_note can't take a verified pin's address.
+    pub fn verify_and_pin<'info>(
+        ctx: Context<'_, '_, '_, 'info, VerifyAndPin<'info>>,
+        index: u32,
+        root: [u8; 32],
+        note: String,
+    ) -> Result<()> {
+        // Hash the note to get the leaf node to verify
+        let leaf_node = ctx.accounts.config.note_leaf(&note, index)?;
+        // CPI to verify the leaf node against the root using the proof in the remaining accounts
+        verify_note_leaf(
+            &ctx.accounts.compression_program,
+            &ctx.accounts.merkle_tree,
+            ctx.remaining_accounts,
+            root,
+            leaf_node,
+            index,
+        )?;
+
+        // Save the verified note data to the pinned note account
+        ctx.accounts.pinned_note.set_inner(Note {
+            author: ctx.accounts.payer.key(),
+            merkle_tree: ctx.accounts.merkle_tree.key(),
+            index,
+            leaf_node,
+            note,
+        });
+
+        Ok(())
+    }
+
+    // Instruction for setting the lamports rewarded per verified note by claim_for_notes, only callable by the config authority.
+    pub fn set_reward_per_note(ctx: Context<UpdateConfig>, reward_per_note: u64) -> Result<()> {
+        ctx.accounts.config.reward_per_note = reward_per_note;
+        Ok(())
+    }
+
+    // Instruction for setting the max total bytes of note logs logged for a tree, 0 for no limit.
+    // Only callable by the config authority.
+    pub fn set_max_log_bytes(ctx: Context<UpdateConfig>, max_log_bytes: u64) -> Result<()> {
+        ctx.accounts.config.max_log_bytes = max_log_bytes;
+        Ok(())
+    }
+
+    // Instruction for rotating the domain tag mixed into the leaf nodes of new appends, zero for untagged.
+    // Only callable by the config authority. Existing leaves keep the tag they were appended with,
+    // so a tree that rotated its tag holds mixed-tag leaves. Each tag is recorded in leaf_domains
+    // with the index of the first leaf appended with it, so the verification and update instructions
+    // hash a note with the tag of its index, see stored_leaf. A tag set before any leaf was appended
+    // with the current one replaces it, otherwise a tree can record up to MAX_LEAF_DOMAINS tags and
+    // further rotations fail with NoteError::TooManyLeafDomains.
+    pub fn set_leaf_domain(ctx: Context<UpdateConfig>, new_tag: [u8; 4]) -> Result<()> {
+        ctx.accounts.config.rotate_leaf_domain(new_tag)
+    }
+
+    // Instruction for setting every how many notes append_note also pins the note, 0 to disable.
+    // Only callable by the config authority.
+    pub fn set_auto_pin_interval(ctx: Context<UpdateConfig>, auto_pin_interval: u32) -> Result<()> {
+        ctx.accounts.config.auto_pin_interval = auto_pin_interval;
+        Ok(())
+    }
+
+    // Instruction for setting whether appends reject notes containing C0 control characters other than
+    // tab and newline, such as null bytes and escape sequences, for trees rendered in terminals or logs.
+    // Off by default. Only callable by the config authority.
+    pub fn set_strict_text(ctx: Context<UpdateConfig>, strict_text: bool) -> Result<()> {
+        ctx.accounts.config.strict_text = strict_text;
+        Ok(())
+    }
+
+    // Instruction for setting how appends handle a note whose leaf equals the current rightmost leaf, only callable by the config authority.
+    pub fn set_duplicate_leaf_check(
+        ctx: Context<UpdateConfig>,
+        duplicate_leaf_check: DuplicateLeafCheck,
+    ) -> Result<()> {
+        ctx.accounts.config.duplicate_leaf_check = duplicate_leaf_check;
+        Ok(())
+    }
+
+    // Instruction for an author claiming a reward for their notes stored in the tree, an example of
+    // composing verification with an incentive. Only notes whose leaf node commits to the author can
+    // be claimed, so each note is verified as a delegated note of the signing author, see
+    // NoteLog::new_delegated; plain text leaves don't commit to an author and can't be claimed.
+    // Every note is verified before anything is paid, so if any verification fails the whole
+    // instruction fails and no partial reward is paid.
+    // The proofs are passed as remaining accounts, max_depth - canopy_depth accounts per note in
+    // the order of the claims, followed by the claim record pda of each note in the same order.
+    // Each claim is recorded in its claim record, created with the author paying the rent, so a
+    // note can only be claimed once and claiming it again fails with NoteError::AlreadyClaimed.
+    // Within one claim the indices must be strictly increasing. The reward is paid in lamports
+    // from the tree's reward vault pda to the author.
+    pub fn claim_for_notes<'info>(
+        ctx: Context<'_, '_, '_, 'info, ClaimForNotes<'info>>,
+        items: Vec<NoteClaim>,
+    ) -> Result<()> {
+        let config = &ctx.accounts.config;
+        let author = ctx.accounts.author.key();
+        require!(config.reward_per_note > 0, NoteError::RewardNotConfigured);
+        require!(
+            items.windows(2).all(|pair| pair[0].index < pair[1].index),
+            NoteError::DuplicateClaim
+        );
+
+        // Split the remaining accounts into one proof per claim
+        let canopy_depth = tree_canopy_depth(&ctx.accounts.merkle_tree, &ctx.accounts.config)?;
+        let proof_len = config.max_depth.saturating_sub(canopy_depth) as usize;
+        require_eq!(
+            ctx.remaining_accounts.len(),
+            (proof_len + 1) * items.len(),
+            NoteError::InvalidProofAccounts
+        );
+        let (proofs, claim_records) = ctx.remaining_accounts.split_at(proof_len * items.len());
+
+        // CPI to verify each note, failing the instruction if any proof is invalid
+        for (i, item) in items.iter().enumerate() {
+            let note_log =
+                NoteLog::new_delegated(author, item.note.clone(), config.hash_iterations)?;
+            verify_note_leaf(
+                &ctx.accounts.compression_program,
+                &ctx.accounts.merkle_tree,
+                &proofs[i * proof_len..(i + 1) * proof_len],
+                item.root,
+                config.stored_leaf(item.index, note_log.leaf_node),
+                item.index,
+            )?;
+        }
+
+        // Record each claim, failing the instruction if any note was already claimed
+        let merkle_tree = ctx.accounts.merkle_tree.key();
+        for (item, claim_record) in items.iter().zip(claim_records) {
+            let index_seed = index_bytes(item.index);
+            let (claim_record_key, bump) = Pubkey::find_program_address(
+                &[b"claimed", merkle_tree.as_ref(), &index_seed],
+                ctx.program_id,
+            );
+            require_keys_eq!(
+                claim_record.key(),
+                claim_record_key,
+                NoteError::InvalidClaimAccount
+            );
+            require!(
+                claim_record.owner != ctx.program_id,
+                NoteError::AlreadyClaimed
+            );
+            create_pda_account(
+                &ctx.accounts.author.to_account_info(),
+                claim_record,
+                &ctx.accounts.system_program.to_account_info(),
+                8 + ClaimRecord::LEN,
+                ctx.program_id,
+                &[&[b"claimed", merkle_tree.as_ref(), &index_seed, &[bump]]],
+            )?;
+            ClaimRecord {
+                merkle_tree,
+                index: item.index,
+                author,
+            }
+            .try_serialize(&mut &mut claim_record.try_borrow_mut_data()?[..])?;
+        }
+
+        // Pay the reward for all the verified notes from the reward vault
+        let reward = config
+            .reward_per_note
+            .checked_mul(items.len() as u64)
+            .ok_or(NoteError::ArithmeticOverflow)?;
+        let signer_seeds: &[&[&[u8]]] = &[&[
+            b"reward_vault",
+            merkle_tree.as_ref(),
+            &[*ctx.bumps.get("reward_vault").unwrap()],
+        ]];
+        let cpi_ctx = CpiContext::new_with_signer(
+            ctx.accounts.system_program.to_account_info(),
+            Transfer {
+                from: ctx.accounts.reward_vault.to_account_info(),
+                to: ctx.accounts.author.to_account_info(),
+            },
+            signer_seeds,
+        );
+        transfer(cpi_ctx, reward)
+    }
+
+    // Instruction for saving the current root of a tree to a checkpoint account, seeded by the
+    // checkpoint index, so notes can later be proven against the tree as of now. Only callable by the config authority.
+    pub fn create_checkpoint(
+        ctx: Context<CreateCheckpoint>,
+        checkpoint_index: u32,
+        label: String,
+    ) -> Result<()> {
+        // Check the label fits within the max label length
+        require!(
+            label.len() <= MAX_CHECKPOINT_LABEL_LEN,
+            NoteError::CheckpointLabelTooLong
+        );
+
+        let (seq, root) = current_root(&ctx.accounts.merkle_tree)?;
+        ctx.accounts.checkpoint.set_inner(Checkpoint {
+            merkle_tree: ctx.accounts.merkle_tree.key(),
+            checkpoint_index,
+            seq,
+            root,
+            slot: now()?.1,
+            label,
+        });
+        Ok(())
+    }
+
+    // Read-only instruction returning the serialized data of a checkpoint as return data.
+    // Intended for simulation, so clients don't need to know the checkpoint account layout.
+    pub fn get_checkpoint(ctx: Context<GetCheckpoint>, _checkpoint_index: u32) -> Result<()> {
+        let checkpoint = &ctx.accounts.checkpoint;
+        let checkpoint_data = CheckpointData {
+            root: checkpoint.root,
+            slot: checkpoint.slot,
+            sequence: checkpoint.seq,
+            label: checkpoint.label.clone(),
+        };
+        set_return_data_checked(&checkpoint_data.try_to_vec()?)
+    }
+
+    // Instruction for verifying a note was stored in a tree at the given leaf index as of a checkpoint,
+    // even if the tree changed since. The compression program only verifies against recent roots,
+    // so the root is recomputed here from the proof instead. Clients must supply the full proof,
+    // max_depth proof node accounts, valid for the tree as of the checkpoint rather than the
+    // current tree, since the canopy only holds current nodes.
+    pub fn verify_note_at_checkpoint(
+        ctx: Context<VerifyNoteAtCheckpoint>,
+        _checkpoint_index: u32,
+        note: String,
+        leaf_index: u32,
+    ) -> Result<()> {
+        require_eq!(
+            ctx.remaining_accounts.len(),
+            ctx.accounts.config.max_depth as usize,
+            NoteError::InvalidProofAccounts
+        );
+
+        // Hash the note to get the leaf node to verify
+        let leaf_node = ctx.accounts.config.note_leaf(&note, leaf_index)?;
+        // Recompute the root from the leaf node and the proof, and compare it to the checkpoint root
+        let proof: Vec<[u8; 32]> = ctx
+            .remaining_accounts
+            .iter()
+            .map(|node| node.key().to_bytes())
+            .collect();
+        require!(
+            compute_root(leaf_node, &proof, leaf_index) == ctx.accounts.checkpoint.root,
+            NoteError::LeafMismatch
+        );
+        Ok(())
+    }
+
+    // Instruction for adding an author to the allowlist of a tree, only callable by the config authority.
+    // The allowlist account is created on the first author added.
+    pub fn add_author(ctx: Context<ManageAllowlist>, author: Pubkey) -> Result<()> {
+        let allowlist = &mut ctx.accounts.allowlist;
+        allowlist.merkle_tree = ctx.accounts.merkle_tree.key();
+
+        // Check the author isn't already allowlisted and the list isn't full
+        require!(
+            !allowlist.authors.contains(&author),
+            NoteError::AlreadyAllowlisted
+        );
+        require!(
+            allowlist.authors.len() < MAX_ALLOWLIST_LEN,
+            NoteError::AllowlistFull
+        );
+
+        allowlist.authors.push(author);
+        Ok(())
+    }
+
+    // Instruction for removing an author from the allowlist of a tree, only callable by the config authority.
+    pub fn remove_author(ctx: Context<ManageAllowlist>, author: Pubkey) -> Result<()> {
+        let allowlist = &mut ctx.accounts.allowlist;
+
+        // Find the author in the allowlist
+        let position = allowlist
+            .authors
+            .iter()
+            .position(|allowlisted| *allowlisted == author)
+            .ok_or(NoteError::NotAllowlisted)?;
+
+        allowlist.authors.swap_remove(position);
+        Ok(())
+    }
+
+    // Read-only instruction returning the serialized config of a tree as return data.
+    // Intended for simulation, so clients don't need to know the config account layout.
+    pub fn get_config(ctx: Context<GetConfig>) -> Result<()> {
+        set_return_data_checked(&ctx.accounts.config.try_to_vec()?)
+    }
+
+    // Read-only instruction returning the serialized recent leaves of a tree as return data, newest first.
+    // Intended for simulation, so a client that just connected can bootstrap the latest leaves in one call.
+    // Only the last RECENT_LEAVES_LEN appended leaves are cached, older leaves must come from an indexer.
+    pub fn list_recent(ctx: Context<GetConfig>) -> Result<()> {
+        set_return_data_checked(
+            &ctx.accounts
+                .config
+                .recent_leaves_newest_first()
+                .try_to_vec()?,
+        )
+    }
+
+    // Read-only instruction returning the seed and bump the program derives the tree authority pda from,
+    // as return data. Intended for simulation, so integrators debugging failed pda signatures can
+    // cross-check their own derivation. The seed is the merkle tree, or the global config for shared trees.
+    pub fn debug_tree_authority(ctx: Context<DebugTreeAuthority>) -> Result<()> {
+        let seed = ctx.accounts.config.tree_authority_seed();
+        let (tree_authority, bump) = Pubkey::find_program_address(&[seed.as_ref()], ctx.program_id);
+        let tree_authority_data = TreeAuthorityData {
+            seed,
+            bump,
+            tree_authority,
+        };
+        set_return_data_checked(&tree_authority_data.try_to_vec()?)
+    }
+
+    // Read-only instruction returning the serialized list of supported tree sizes as return data,
+    // each with the account space the tree requires before its canopy. Intended for simulation,
+    // so clients don't need to hardcode the sizes supported by the compression program.
+    pub fn supported_tree_sizes(_ctx: Context<SupportedTreeSizes>) -> Result<()> {
+        let tree_sizes = SUPPORTED_TREE_SIZES
+            .iter()
+            .map(|&(max_depth, max_buffer_size)| TreeSize::new(max_depth, max_buffer_size))
+            .collect::<Result<Vec<_>>>()?;
+        set_return_data_checked(&tree_sizes.try_to_vec()?)
+    }
+
+    // Instruction for creating the config of an existing tree that has none, such as trees created
+    // before configs existed. Only trees whose authority is the tree authority pda of this program
+    // can be adopted, and only by the upgrade authority of the program, who becomes the config
+    // authority. The max depth and buffer size are read from the tree, and the note count from the
+    // number of leaves already appended.
+    pub fn adopt_tree(
+        ctx: Context<AdoptTree>,
+        hash_iterations: u8, // Number of times each note was hashed into its leaf node
+        gate_mint: Option<Pubkey>, // Mint of the token required by append_note_gated, if any
+        access_mode: AccessMode, // Whether any payer can append notes, or only allowlisted authors
+    ) -> Result<()> {
+        // Check the tree is initialized and governed by this program's tree authority
+        let header = tree_header(&ctx.accounts.merkle_tree.try_borrow_data()?)?;
+        header.assert_valid()?;
+        header.assert_valid_authority(&ctx.accounts.tree_authority.key())?;
+
+        // Save the tree settings to the config account
+        let config = &mut ctx.accounts.config;
+        config.init(
+            ctx.accounts.authority.key(), // The upgrade authority who adopted the tree
+            ctx.accounts.merkle_tree.key(),
+            *ctx.bumps.get("config").unwrap(),
+            None, // The tree authority is derived from the merkle tree
+            &TreeConfigInitArgs {
+                max_depth: header.get_max_depth(),
+                max_buffer_size: header.get_max_buffer_size(),
+                hash_iterations,
+                gate_mint,
+                access_mode,
+                canopy_depth: 0, // Only checked when creating a tree, not stored in the config
+            },
+        )?;
+        config.note_count = leaf_count(&ctx.accounts.merkle_tree)?.into();
+        // Use the compression program that owns the tree, recorded only when it is a fork
+        let compression_program = ctx.accounts.compression_program.key();
+        config.compression_program =
+            (compression_program != SplAccountCompression::id()).then_some(compression_program);
+        Ok(())
+    }
+
+    // Read-only instruction checking the accounts of a tree are set up correctly, returning a bitmask
+    // of the checks that passed as a 1 byte return data, see the HEALTH_ constants for each bit.
+    // No account is validated up front, so every check runs and reports instead of failing.
+    pub fn healthcheck(ctx: Context<Healthcheck>) -> Result<()> {
+        let accounts = &ctx.accounts;
+        let merkle_tree = accounts.merkle_tree.key();
+        let mut passed = 0;
+
+        // The config is the config pda of the tree and deserializes, with any layout version
+        let (config_address, _) =
+            Pubkey::find_program_address(&[b"config", merkle_tree.as_ref()], ctx.program_id);
+        let config =
+            if accounts.config.key() == config_address && *accounts.config.owner == crate::ID {
+                NoteTreeConfig::try_from_versioned(&accounts.config.try_borrow_data()?).ok()
+            } else {
+                None
+            };
+        if config.is_some() {
+            passed |= HEALTH_CONFIG;
+        }
+
+        // The config authority is the expected authority
+        if config.as_ref().map(|config| config.authority) == Some(accounts.authority.key()) {
+            passed |= HEALTH_AUTHORITY;
+        }
+
+        // The tree authority derives from the merkle tree, or the global config for shared trees
+        let tree_authority_seed = config
+            .as_ref()
+            .map_or(merkle_tree, NoteTreeConfig::tree_authority_seed);
+        let (tree_authority, _) =
+            Pubkey::find_program_address(&[tree_authority_seed.as_ref()], ctx.program_id);
+        if accounts.tree_authority.key() == tree_authority
+            && is_signer_only_pda(&accounts.tree_authority)
+        {
+            passed |= HEALTH_TREE_AUTHORITY;
+        }
+
+        // The compression program is the canonical one, or the fork set in the config
+        let compression_program = config.as_ref().map_or(
+            SplAccountCompression::id(),
+            NoteTreeConfig::compression_program_id,
+        );
+        if accounts.compression_program.key() == compression_program
+            && accounts.compression_program.executable
+        {
+            passed |= HEALTH_COMPRESSION_PROGRAM;
+        }
+
+        // The noop program is the canonical one and deployed
+        if accounts.log_wrapper.key() == Noop::id() && accounts.log_wrapper.executable {
+            passed |= HEALTH_NOOP_PROGRAM;
+        }
+
+        // The merkle tree is an initialized tree of the compression program, governed by the tree authority
+        if *accounts.merkle_tree.owner == compression_program {
+            let data = accounts.merkle_tree.try_borrow_data()?;
+            if let Ok(header) = tree_header(&data) {
+                if header.assert_valid().is_ok()
+                    && header.assert_valid_authority(&tree_authority).is_ok()
+                {
+                    passed |= HEALTH_MERKLE_TREE;
+                }
+            }
+        }
+
+        set_return_data_checked(&[passed])
+    }
+
+    // Instruction for closing an empty tree, refunding its rent to the config authority.
+    // The compression program only closes trees without leaves. Only callable by the config authority.
+    pub fn close_note_tree(ctx: Context<CloseNoteTree>) -> Result<()> {
+        // Define the seeds for pda signing
+        let tree_authority_seed = ctx.accounts.config.tree_authority_seed();
+        let signer_seeds: &[&[&[u8]]] = &[&[
+            tree_authority_seed.as_ref(), // The tree authority seed of the config
+            &[*ctx.bumps.get("tree_authority").unwrap()], // The bump seed for the pda
+        ]];
+        let cpi_ctx = CpiContext::new_with_signer(
+            ctx.accounts.compression_program.to_account_info(), // The spl account compression program
+            CloseTree {
+                merkle_tree: ctx.accounts.merkle_tree.to_account_info(), // The merkle tree account to close
+                authority: ctx.accounts.tree_authority.to_account_info(), // The authority for the merkle tree, using a PDA
+                recipient: ctx.accounts.authority.to_account_info(), // The recipient of the rent
+            },
+            signer_seeds, // The seeds for pda signing
+        );
+        // CPI to close the empty merkle tree
+        close_empty_tree(cpi_ctx)
+    }
+
+    // Instruction for closing the config of a closed tree, refunding its rent to the config authority.
+    // Fails with NoteError::TreeNotClosed while the tree account still holds lamports, so it can run
+    // in the same transaction after close_note_tree. Only callable by the config authority.
+    pub fn close_config(_ctx: Context<CloseConfig>) -> Result<()> {
+        Ok(())
+    }
+
+    // Instruction for upgrading a config account created by an older program version to the current layout.
+    pub fn migrate_config(ctx: Context<MigrateConfig>) -> Result<()> {
+        let config_info = ctx.accounts.config.to_account_info();
+        // Read the config from whichever layout the account currently holds, keeping its counters
+        let (mut config, stored_version) =
+            NoteTreeConfig::try_from_versioned_with_version(&config_info.try_borrow_data()?)?;
+        // Count the notes from the tree, only for layouts before version 10 that didn't count them
+        if stored_version < 10 {
+            config.note_count = leaf_count(&ctx.accounts.merkle_tree)?.into();
+        }
+
+        // Only the authority stored in the config can migrate it
+        require_keys_eq!(
+            config.authority,
+            ctx.accounts.authority.key(),
+            NoteError::Unauthorized
+        );
+
+        // Top up the rent for the resized account, paid by the authority
+        let new_len = 8 + NoteTreeConfig::LEN;
+        let rent_due = Rent::get()?
+            .minimum_balance(new_len)
+            .saturating_sub(config_info.lamports());
+        if rent_due > 0 {
+            let cpi_ctx = CpiContext::new(
+                ctx.accounts.system_program.to_account_info(),
+                Transfer {
+                    from: ctx.accounts.authority.to_account_info(),
+                    to: config_info.clone(),
+                },
+            );
+            transfer(cpi_ctx, rent_due)?;
+        }
+
+        // Resize the account and write the config using the current layout
+        config_info.realloc(new_len, false)?;
+        config.try_serialize(&mut &mut config_info.try_borrow_mut_data()?[..])?;
+
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct CreateNoteTree<'info> {
+    // The payer for the transaction
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    // The config account for the tree, a pda derived from the merkle tree
+    #[account(
+        init,
+        payer = payer,
+        space = 8 + NoteTreeConfig::LEN,
+        seeds = [b"config", merkle_tree.key().as_ref()],
+        bump,
+    )]
+    pub config: Account<'info, NoteTreeConfig>,
+
+    // The pda authority for the merkle tree, only used for signing
+    #[account(
+        seeds = [merkle_tree.key().as_ref()],
+        bump,
+        constraint = is_signer_only_pda(&tree_authority) @ NoteError::InvalidTreeAuthority,
+    )]
+    pub tree_authority: SystemAccount<'info>,
+
+    // The merkle tree account
+    /// CHECK: This account is validated by the spl account compression program
+    #[account(mut)]
+    pub merkle_tree: UncheckedAccount<'info>,
+
+    // The noop program to log data
+    pub log_wrapper: Program<'info, Noop>,
+
+    // The spl account compression program
+    pub compression_program: Program<'info, SplAccountCompression>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct CreateNoteTreeWithNote<'info> {
+    // The accounts used to create a note tree
+    pub create: CreateNoteTree<'info>,
+
+    // The author stats of the creator for the tree, a pda derived from the merkle tree and the payer
+    #[account(
+        init,
+        payer = create.payer,
+        space = 8 + AuthorStats::LEN,
+        seeds = [b"author", create.merkle_tree.key().as_ref(), create.payer.key().as_ref()],
+        bump,
+    )]
+    pub author_stats: Account<'info, AuthorStats>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct InitGlobalConfig<'info> {
+    // The authority of the global config, also pays for the account
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    // The global config account, a pda derived from the authority
+    #[account(
+        init,
+        payer = authority,
+        space = 8 + GlobalConfig::LEN,
+        seeds = [b"global_config", authority.key().as_ref()],
+        bump,
+    )]
+    pub global_config: Account<'info, GlobalConfig>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateGlobalConfig<'info> {
+    // The authority stored in the global config
+    pub authority: Signer<'info>,
+
+    // The global config account
+    #[account(
+        mut,
+        seeds = [b"global_config", authority.key().as_ref()],
+        bump = global_config.bump,
+        has_one = authority @ NoteError::Unauthorized,
+    )]
+    pub global_config: Account<'info, GlobalConfig>,
+}
+
+#[derive(Accounts)]
+pub struct CreateSharedTree<'info> {
+    // The authority stored in the global config, also pays for the config account
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    // The global config the tree authority is shared with
+    #[account(
+        seeds = [b"global_config", authority.key().as_ref()],
+        bump = global_config.bump,
+        has_one = authority @ NoteError::Unauthorized,
+    )]
+    pub global_config: Account<'info, GlobalConfig>,
+
+    // The config account for the tree, a pda derived from the merkle tree
+    #[account(
+        init,
+        payer = authority,
+        space = 8 + NoteTreeConfig::LEN,
+        seeds = [b"config", merkle_tree.key().as_ref()],
+        bump,
+    )]
+    pub config: Account<'info, NoteTreeConfig>,
+
+    // The shared pda authority for the merkle tree, derived from the global config
+    #[account(
+        seeds = [global_config.key().as_ref()],
+        bump,
+        constraint = is_signer_only_pda(&tree_authority) @ NoteError::InvalidTreeAuthority,
+    )]
+    pub tree_authority: SystemAccount<'info>,
+
+    // The merkle tree account
+    /// CHECK: This account is validated by the spl account compression program
+    #[account(mut)]
+    pub merkle_tree: UncheckedAccount<'info>,
+
+    // The noop program to log data
+    pub log_wrapper: Program<'info, Noop>,
+
+    // The spl account compression program, or the fork set in the global config
+    /// CHECK: Checked against the compression program id of the global config
+    #[account(address = global_config.compression_program_id())]
+    pub compression_program: UncheckedAccount<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct NoteAccounts<'info> {
+    // The payer for the transaction
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    // The config account of the tree, mutable for the running rating aggregates
+    #[account(
+        mut,
+        seeds = [b"config", merkle_tree.key().as_ref()],
+        bump = config.bump,
+    )]
+    pub config: Account<'info, NoteTreeConfig>,
+
+    // The pda authority for the merkle tree, only used for signing.
+    // Derived from the merkle tree, or from the global config for shared trees
+    #[account(
+        seeds = [config.tree_authority_seed().as_ref()],
+        bump,
+        constraint = is_signer_only_pda(&tree_authority) @ NoteError::InvalidTreeAuthority,
+    )]
+    pub tree_authority: SystemAccount<'info>,
+
+    // The merkle tree account
+    /// CHECK: This account is validated by the spl account compression program
+    #[account(mut)]
+    pub merkle_tree: UncheckedAccount<'info>,
+
+    // The noop program to log data, only checked by address so a missing deployment can be reported
+    /// CHECK: Checked to be executable before logging, see wrap_note_log
+    #[account(address = Noop::id())]
+    pub log_wrapper: UncheckedAccount<'info>,
+
+    // The spl account compression program, or the fork set in the tree config
+    /// CHECK: Checked against the compression program id of the tree config
+    #[account(address = config.compression_program_id())]
+    pub compression_program: UncheckedAccount<'info>,
+
+    // The allowlist of the tree, only read when the tree's access mode is restricted
+    /// CHECK: Seeds are checked here, the data is checked by check_allowlisted, since it may not exist for open trees
+    #[account(
+        seeds = [b"allowlist", merkle_tree.key().as_ref()],
+        bump,
+    )]
+    pub allowlist: UncheckedAccount<'info>,
+
+    // The note count of the payer in this tree, created on the payer's first note
+    #[account(
+        init_if_needed,
+        payer = payer,
+        space = 8 + AuthorStats::LEN,
+        seeds = [b"author", merkle_tree.key().as_ref(), payer.key().as_ref()],
+        bump,
+    )]
+    pub author_stats: Account<'info, AuthorStats>,
+
+    pub system_program: Program<'info, System>,
+}
+
+// The accounts of instructions replacing leaves of a tree, update_title and swap_notes
+#[derive(Accounts)]
+pub struct ReplaceLeaf<'info> {
+    // The authority stored in the config
+    pub authority: Signer<'info>,
+
+    // The config account of the tree
+    #[account(
+        seeds = [b"config", merkle_tree.key().as_ref()],
+        bump = config.bump,
+        has_one = authority @ NoteError::Unauthorized,
+    )]
+    pub config: Account<'info, NoteTreeConfig>,
+
+    // The pda authority for the merkle tree, only used for signing.
+    // Derived from the merkle tree, or from the global config for shared trees
+    #[account(
+        seeds = [config.tree_authority_seed().as_ref()],
+        bump,
+        constraint = is_signer_only_pda(&tree_authority) @ NoteError::InvalidTreeAuthority,
+    )]
+    pub tree_authority: SystemAccount<'info>,
+
+    // The merkle tree account
+    /// CHECK: This account is validated by the spl account compression program
+    #[account(mut)]
+    pub merkle_tree: UncheckedAccount<'info>,
+
+    // The noop program to log data
+    /// CHECK: Checked by address, only passed to the compression program
+    #[account(address = Noop::id())]
+    pub log_wrapper: UncheckedAccount<'info>,
+
+    // The spl account compression program, or the fork set in the tree config
+    /// CHECK: Checked against the compression program id of the tree config
+    #[account(address = config.compression_program_id())]
+    pub compression_program: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct GatedNoteAccounts<'info> {
+    // The accounts used to append a note
+    pub note_accounts: NoteAccounts<'info>,
+
+    // The payer's token account for the gate mint
+    #[account(
+        constraint = gate_token_account.owner == note_accounts.payer.key() @ NoteError::Unauthorized,
+        constraint = gate_token_account.mint == gate_mint.key() @ NoteError::GateMintMismatch,
+    )]
+    pub gate_token_account: Account<'info, TokenAccount>,
+
+    // The gate mint, checked against the mint stored in the config
+    pub gate_mint: Account<'info, Mint>,
+}
+
+#[derive(Accounts)]
+pub struct MirroredNoteAccounts<'info> {
+    // The accounts used to append a note
+    pub note_accounts: NoteAccounts<'info>,
+
+    // The root mirror of the tree, a pda derived from the merkle tree
+    #[account(
+        init_if_needed,
+        payer = note_accounts.payer,
+        space = 8 + RootMirror::LEN,
+        seeds = [b"root_mirror", note_accounts.merkle_tree.key().as_ref()],
+        bump,
+    )]
+    pub root_mirror: Account<'info, RootMirror>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(parent_leaf: [u8; 32])]
+pub struct ReplyAccounts<'info> {
+    // The accounts used to append a note
+    pub note_accounts: NoteAccounts<'info>,
+
+    // The reply count of the parent, a pda derived from the merkle tree and the parent leaf
+    #[account(
+        init_if_needed,
+        payer = note_accounts.payer,
+        space = 8 + ReplyCount::LEN,
+        seeds = [b"replies", note_accounts.merkle_tree.key().as_ref(), parent_leaf.as_ref()],
+        bump,
+    )]
+    pub reply_count: Account<'info, ReplyCount>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(parent_leaf: [u8; 32])]
+pub struct GetReplyCount<'info> {
+    // The reply count of the parent, which may not exist yet
+    /// CHECK: Seeds are checked here, the data is checked by get_reply_count, since it may not exist
+    #[account(
+        seeds = [b"replies", merkle_tree.key().as_ref(), parent_leaf.as_ref()],
+        bump,
+    )]
+    pub reply_count: UncheckedAccount<'info>,
+
+    // The merkle tree account, only used as a seed
+    /// CHECK: This account is only used as a seed
+    pub merkle_tree: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(note: String, owner: Pubkey)]
+pub struct DelegatedNoteAccounts<'info> {
+    // The delegate, paying for the transaction
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    // The config account of the tree, mutable for the running aggregates
+    #[account(
+        mut,
+        seeds = [b"config", merkle_tree.key().as_ref()],
+        bump = config.bump,
+    )]
+    pub config: Account<'info, NoteTreeConfig>,
+
+    // The pda authority for the merkle tree, only used for signing.
+    // Derived from the merkle tree, or from the global config for shared trees
+    #[account(
+        seeds = [config.tree_authority_seed().as_ref()],
+        bump,
+        constraint = is_signer_only_pda(&tree_authority) @ NoteError::InvalidTreeAuthority,
+    )]
+    pub tree_authority: SystemAccount<'info>,
+
+    // The merkle tree account
+    /// CHECK: This account is validated by the spl account compression program
+    #[account(mut)]
+    pub merkle_tree: UncheckedAccount<'info>,
+
+    // The noop program to log data, only checked by address so a missing deployment can be reported
+    /// CHECK: Checked to be executable before logging, see wrap_note_log
+    #[account(address = Noop::id())]
+    pub log_wrapper: UncheckedAccount<'info>,
+
+    // The spl account compression program, or the fork set in the tree config
+    /// CHECK: Checked against the compression program id of the tree config
+    #[account(address = config.compression_program_id())]
+    pub compression_program: UncheckedAccount<'info>,
+
+    // The allowlist of the tree, only read when the tree's access mode is restricted
+    /// CHECK: Seeds are checked here, the data is checked by check_allowlisted, since it may not exist for open trees
+    #[account(
+        seeds = [b"allowlist", merkle_tree.key().as_ref()],
+        bump,
+    )]
+    pub allowlist: UncheckedAccount<'info>,
+
+    // The note count of the owner in this tree, created on the owner's first note, paid by the delegate
+    #[account(
+        init_if_needed,
+        payer = payer,
+        space = 8 + AuthorStats::LEN,
+        seeds = [b"author", merkle_tree.key().as_ref(), owner.as_ref()],
+        bump,
+    )]
+    pub author_stats: Account<'info, AuthorStats>,
+
+    // The delegate nonce of the owner, a pda derived from the owner
+    #[account(
+        init_if_needed,
+        payer = payer,
+        space = 8 + DelegateNonce::LEN,
+        seeds = [b"delegate_nonce", owner.as_ref()],
+        bump,
+    )]
+    pub delegate_nonce: Account<'info, DelegateNonce>,
+
+    // The instructions sysvar, to read the ed25519 program instruction before this one
+    /// CHECK: Checked by address, read by ed25519_instruction_parts
+    #[account(address = anchor_lang::solana_program::sysvar::instructions::ID)]
+    pub instructions: UncheckedAccount<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(index: u32, note: String)]
+pub struct PinNote<'info> {
+    // The payer for the transaction and the rent of the pinned note account
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    // The config account of the tree
+    #[account(
+        seeds = [b"config", merkle_tree.key().as_ref()],
+        bump = config.bump,
+    )]
+    pub config: Account<'info, NoteTreeConfig>,
+
+    // The pinned note account, a pda derived from the merkle tree and the leaf index
+    // The index instruction argument is bound to the seeds, so a mismatched index fails account validation
+    #[account(
+        init,
+        payer = payer,
+        space = Note::space(&note),
+        seeds = [merkle_tree.key().as_ref(), &index_bytes(index)],
+        bump,
+    )]
+    pub pinned_note: Account<'info, Note>,
+
+    // The merkle tree account, only used as a seed for the pinned note
+    /// CHECK: This account is only checked to be owned by the compression program of the tree config
+    #[account(owner = config.compression_program_id())]
+    pub merkle_tree: UncheckedAccount<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(index: u32)]
+pub struct UnpinOwnNote<'info> {
+    // The author of the pinned note, receives the refunded rent
+    #[account(mut)]
+    pub author: Signer<'info>,
+
+    // The pinned note account to close, verified or not, checked by the tree and index it was pinned for
+    #[account(
+        mut,
+        close = author,
+        constraint = pinned_note.merkle_tree == merkle_tree.key()
+            && pinned_note.index == index @ NoteError::InvalidPinAccount,
+        has_one = author @ NoteError::Unauthorized,
+    )]
+    pub pinned_note: Account<'info, Note>,
+
+    // The merkle tree account the note was pinned for
+    /// CHECK: This account is only compared to the tree of the pinned note
+    pub merkle_tree: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(total_len: u32)]
+pub struct InitStaging<'info> {
+    // The payer for the transaction and the rent of the staging account
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    // The config account of the tree
+    #[account(
+        seeds = [b"config", merkle_tree.key().as_ref()],
+        bump = config.bump,
+    )]
+    pub config: Account<'info, NoteTreeConfig>,
+
+    // The staging account, a pda derived from the merkle tree and the payer
+    #[account(
+        init,
+        payer = payer,
+        space = Staging::space(total_len),
+        seeds = [b"staging", merkle_tree.key().as_ref(), payer.key().as_ref()],
+        bump,
+    )]
+    pub staging: Account<'info, Staging>,
+
+    // The merkle tree account, only used as a seed for the staging account
+    /// CHECK: This account is only checked to be owned by the compression program of the tree config
+    #[account(owner = config.compression_program_id())]
+    pub merkle_tree: UncheckedAccount<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct WriteStaging<'info> {
+    // The payer who initialized the staging account
+    pub payer: Signer<'info>,
+
+    // The staging account of the payer
+    #[account(
+        mut,
+        seeds = [b"staging", merkle_tree.key().as_ref(), payer.key().as_ref()],
+        bump,
+    )]
+    pub staging: Account<'info, Staging>,
+
+    // The merkle tree account, only used as a seed for the staging account
+    /// CHECK: This account is only used as a seed
+    pub merkle_tree: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct FinalizeStagedNote<'info> {
+    // The accounts used to append a note
+    pub note_accounts: NoteAccounts<'info>,
+
+    // The staging account of the payer, closed once the note is appended
+    #[account(
+        mut,
+        seeds = [
+            b"staging",
+            note_accounts.merkle_tree.key().as_ref(),
+            note_accounts.payer.key().as_ref(),
+        ],
+        bump,
+    )]
+    pub staging: Account<'info, Staging>,
+}
+
+#[derive(Accounts)]
+pub struct VerifyNote<'info> {
+    // The config account of the tree
+    #[account(
+        seeds = [b"config", merkle_tree.key().as_ref()],
+        bump = config.bump,
+    )]
+    pub config: Account<'info, NoteTreeConfig>,
+
+    // The merkle tree account
+    /// CHECK: This account is validated by the spl account compression program
+    pub merkle_tree: UncheckedAccount<'info>,
+
+    // The spl account compression program, or the fork set in the tree config
+    /// CHECK: Checked against the compression program id of the tree config
+    #[account(address = config.compression_program_id())]
+    pub compression_program: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(index: u32, root: [u8; 32], note: String)]
+pub struct VerifyAndPin<'info> {
+    // The payer for the transaction and the rent of the pinned note account
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    // The config account of the tree
+    #[account(
+        seeds = [b"config", merkle_tree.key().as_ref()],
+        bump = config.bump,
+    )]
+    pub config: Account<'info, NoteTreeConfig>,
+
+    // The verified pinned note account, a pda derived from the merkle tree and the leaf index
+    #[account(
+        init,
+        payer = payer,
+        space = Note::space(&note),
+        seeds = [b"verified", merkle_tree.key().as_ref(), &index_bytes(index)],
+        bump,
+    )]
+    pub pinned_note: Account<'info, Note>,
+
+    // The merkle tree account
+    /// CHECK: This account is validated by the spl account compression program
+    pub merkle_tree: UncheckedAccount<'info>,
+
+    // The spl account compression program, or the fork set in the tree config
+    /// CHECK: Checked against the compression program id of the tree config
+    #[account(address = config.compression_program_id())]
+    pub compression_program: UncheckedAccount<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateConfig<'info> {
+    // The authority stored in the config
+    pub authority: Signer<'info>,
+
+    // The config account of the tree
+    #[account(
+        mut,
+        seeds = [b"config", merkle_tree.key().as_ref()],
+        bump = config.bump,
+        has_one = authority @ NoteError::Unauthorized,
+    )]
+    pub config: Account<'info, NoteTreeConfig>,
+
+    // The merkle tree account, only used as a seed
+    /// CHECK: This account is only used as a seed
+    pub merkle_tree: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CloseNoteTree<'info> {
+    // The authority stored in the config, receives the rent of the tree
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    // The config account of the tree
+    #[account(
+        seeds = [b"config", merkle_tree.key().as_ref()],
+        bump = config.bump,
+        has_one = authority @ NoteError::Unauthorized,
+    )]
+    pub config: Account<'info, NoteTreeConfig>,
+
+    // The pda authority for the merkle tree, only used for signing.
+    // Derived from the merkle tree, or from the global config for shared trees
+    #[account(
+        seeds = [config.tree_authority_seed().as_ref()],
+        bump,
+        constraint = is_signer_only_pda(&tree_authority) @ NoteError::InvalidTreeAuthority,
+    )]
+    pub tree_authority: SystemAccount<'info>,
+
+    // The merkle tree account to close
+    /// CHECK: This account is validated by the spl account compression program
+    #[account(mut)]
+    pub merkle_tree: UncheckedAccount<'info>,
+
+    // The spl account compression program, or the fork set in the tree config
+    /// CHECK: Checked against the compression program id of the tree config
+    #[account(address = config.compression_program_id())]
+    pub compression_program: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CloseConfig<'info> {
+    // The authority stored in the config, receives the rent of the config
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    // The config account to close
+    #[account(
+        mut,
+        close = authority,
+        seeds = [b"config", merkle_tree.key().as_ref()],
+        bump = config.bump,
+        has_one = authority @ NoteError::Unauthorized,
+    )]
+    pub config: Account<'info, NoteTreeConfig>,
+
+    // The merkle tree account, checked to be closed
+    /// CHECK: Only checked to hold no lamports
+    #[account(constraint = merkle_tree.lamports() == 0 @ NoteError::TreeNotClosed)]
+    pub merkle_tree: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimForNotes<'info> {
+    // The author the claimed notes commit to, pays for the claim records and receives the reward
+    #[account(mut)]
+    pub author: Signer<'info>,
+
+    // The config account of the tree
+    #[account(
+        seeds = [b"config", merkle_tree.key().as_ref()],
+        bump = config.bump,
+    )]
+    pub config: Account<'info, NoteTreeConfig>,
+
+    // The reward vault of the tree, a pda derived from the merkle tree funded with plain lamport transfers
+    #[account(
+        mut,
+        seeds = [b"reward_vault", merkle_tree.key().as_ref()],
+        bump,
+    )]
+    pub reward_vault: SystemAccount<'info>,
+
+    // The merkle tree account
+    /// CHECK: This account is validated by the spl account compression program
+    pub merkle_tree: UncheckedAccount<'info>,
+
+    // The spl account compression program, or the fork set in the tree config
+    /// CHECK: Checked against the compression program id of the tree config
+    #[account(address = config.compression_program_id())]
+    pub compression_program: UncheckedAccount<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(checkpoint_index: u32)]
+pub struct CreateCheckpoint<'info> {
+    // The authority stored in the config, also pays for the checkpoint account
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    // The config account of the tree
+    #[account(
+        seeds = [b"config", merkle_tree.key().as_ref()],
+        bump = config.bump,
+        has_one = authority @ NoteError::Unauthorized,
+    )]
+    pub config: Account<'info, NoteTreeConfig>,
+
+    // The checkpoint account, a pda derived from the merkle tree and the checkpoint index
+    #[account(
+        init,
+        payer = authority,
+        space = 8 + Checkpoint::LEN,
+        seeds = [b"checkpoint", merkle_tree.key().as_ref(), &index_bytes(checkpoint_index)],
+        bump,
+    )]
+    pub checkpoint: Account<'info, Checkpoint>,
+
+    // The merkle tree account to read the current root from
+    /// CHECK: This account is checked to be owned by the compression program of the tree config
+    #[account(owner = config.compression_program_id())]
+    pub merkle_tree: UncheckedAccount<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(checkpoint_index: u32)]
+pub struct VerifyNoteAtCheckpoint<'info> {
+    // The config account of the tree
+    #[account(
+        seeds = [b"config", merkle_tree.key().as_ref()],
+        bump = config.bump,
+    )]
+    pub config: Account<'info, NoteTreeConfig>,
+
+    // The checkpoint account to verify against
+    #[account(
+        seeds = [b"checkpoint", merkle_tree.key().as_ref(), &index_bytes(checkpoint_index)],
+        bump,
+    )]
+    pub checkpoint: Account<'info, Checkpoint>,
+
+    // The merkle tree account, only used as a seed
+    /// CHECK: This account is only used as a seed
+    pub merkle_tree: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(checkpoint_index: u32)]
+pub struct GetCheckpoint<'info> {
+    // The checkpoint account to read
+    #[account(
+        seeds = [b"checkpoint", merkle_tree.key().as_ref(), &index_bytes(checkpoint_index)],
+        bump,
+    )]
+    pub checkpoint: Account<'info, Checkpoint>,
+
+    // The merkle tree account, only used as a seed
+    /// CHECK: This account is only used as a seed
+    pub merkle_tree: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct DebugTreeAuthority<'info> {
+    // The config account of the tree
+    #[account(
+        seeds = [b"config", merkle_tree.key().as_ref()],
+        bump = config.bump,
+    )]
+    pub config: Account<'info, NoteTreeConfig>,
+
+    // The merkle tree account, only used as a seed
+    /// CHECK: This account is only used as a seed
+    pub merkle_tree: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ManageAllowlist<'info> {
+    // The authority stored in the config, also pays for the allowlist account
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    // The config account of the tree
+    #[account(
+        seeds = [b"config", merkle_tree.key().as_ref()],
+        bump = config.bump,
+        has_one = authority @ NoteError::Unauthorized,
+    )]
+    pub config: Account<'info, NoteTreeConfig>,
+
+    // The allowlist of the tree, a pda derived from the merkle tree
+    #[account(
+        init_if_needed,
+        payer = authority,
+        space = 8 + Allowlist::LEN,
+        seeds = [b"allowlist", merkle_tree.key().as_ref()],
+        bump,
+    )]
+    pub allowlist: Account<'info, Allowlist>,
+
+    // The merkle tree account, only used as a seed
+    /// CHECK: This account is only used as a seed
+    pub merkle_tree: UncheckedAccount<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct GetConfig<'info> {
+    // The config account to read
+    pub config: Account<'info, NoteTreeConfig>,
+}
+
+#[derive(Accounts)]
+pub struct SupportedTreeSizes {}
+
+#[derive(Accounts)]
+pub struct AdoptTree<'info> {
+    // The upgrade authority of the program, also pays for the config account
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    // This program, to find its program data account
+    #[account(constraint = program.programdata_address()? == Some(program_data.key()))]
+    pub program: Program<'info, crate::program::AnchorCompressedNotes>,
+
+    // The program data account of this program, holding its upgrade authority
+    #[account(constraint = program_data.upgrade_authority_address == Some(authority.key()) @ NoteError::Unauthorized)]
+    pub program_data: Account<'info, ProgramData>,
+
+    // The config account for the tree, a pda derived from the merkle tree
+    #[account(
+        init,
+        payer = authority,
+        space = 8 + NoteTreeConfig::LEN,
+        seeds = [b"config", merkle_tree.key().as_ref()],
+        bump,
+    )]
+    pub config: Account<'info, NoteTreeConfig>,
+
+    // The pda authority for the merkle tree, checked against the authority in the tree header
+    #[account(
+        seeds = [merkle_tree.key().as_ref()],
+        bump,
+        constraint = is_signer_only_pda(&tree_authority) @ NoteError::InvalidTreeAuthority,
+    )]
+    pub tree_authority: SystemAccount<'info>,
+
+    // The merkle tree account to adopt
+    /// CHECK: This account is checked to be an initialized tree owned by the compression program
+    #[account(owner = compression_program.key())]
+    pub merkle_tree: UncheckedAccount<'info>,
+
+    // The spl account compression program, or a fork, saved in the config as the tree's compression program
+    /// CHECK: Only used as the owner of the merkle tree, and checked to be a program
+    #[account(executable)]
+    pub compression_program: UncheckedAccount<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct Healthcheck<'info> {
+    // The config account of the tree
+    /// CHECK: Checked by the healthcheck instruction, which reports instead of failing
+    pub config: UncheckedAccount<'info>,
+
+    // The authority expected to be stored in the config
+    /// CHECK: Only compared against the config authority
+    pub authority: UncheckedAccount<'info>,
+
+    // The pda authority for the merkle tree
+    /// CHECK: Checked by the healthcheck instruction, which reports instead of failing
+    pub tree_authority: UncheckedAccount<'info>,
+
+    // The merkle tree account
+    /// CHECK: Checked by the healthcheck instruction, which reports instead of failing
+    pub merkle_tree: UncheckedAccount<'info>,
+
+    // The noop program to log data
+    /// CHECK: Checked by the healthcheck instruction, which reports instead of failing
+    pub log_wrapper: UncheckedAccount<'info>,
+
+    // The spl account compression program
+    /// CHECK: Checked by the healthcheck instruction, which reports instead of failing
+    pub compression_program: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct MigrateConfig<'info> {
+    // The authority stored in the config, also pays for the additional rent
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    // The config account to migrate, unchecked since an older layout fails to deserialize
+    /// CHECK: Seeds and owner are checked here, the data is checked by NoteTreeConfig::try_from_versioned
+    #[account(
+        mut,
+        seeds = [b"config", merkle_tree.key().as_ref()],
+        bump,
+        owner = crate::ID,
+    )]
+    pub config: UncheckedAccount<'info>,
+
+    // The merkle tree account, only used as a seed for the config
+    /// CHECK: This account is only used as a seed
+    pub merkle_tree: UncheckedAccount<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+// The current layout version of the config account.
+// New fields are only ever appended to the end of NoteTreeConfig, so each layout is a prefix of the next,
+// except for the fields removed in version 20, see LEGACY_LEAF_DOMAIN_FIELDS_LEN.
+pub const CONFIG_VERSION: u8 = 20;
+
+// The bytes of prev_leaf_domain and leaf_domain_slot, which versions 14 to 19 stored right after
+// leaf_domain. They were superseded by leaf_domains and are dropped when reading those layouts.
+pub const LEGACY_LEAF_DOMAIN_FIELDS_LEN: usize = 4 + 8;
+
+// The settings of a note tree, stored in a pda derived from the merkle tree
+#[account]
+pub struct NoteTreeConfig {
+    pub authority: Pubkey,                        // The payer who created the tree
+    pub merkle_tree: Pubkey,                      // The merkle tree the config belongs to
+    pub max_depth: u32,                           // Max depth of the merkle tree
+    pub max_buffer_size: u32,                     // Max buffer size of the merkle tree
+    pub bump: u8,                                 // The bump seed for the config pda
+    pub config_version: u8,                       // The layout version of the config account
+    pub hash_iterations: u8, // Number of times each note is hashed into its leaf node
+    pub gate_mint: Option<Pubkey>, // Mint of the token required by append_note_gated, if any
+    pub access_mode: AccessMode, // Whether appends are restricted to allowlisted authors
+    pub reward_per_note: u64, // Lamports rewarded per verified note by claim_for_notes
+    pub duplicate_leaf_check: DuplicateLeafCheck, // How appends handle a leaf equal to the rightmost leaf
+    pub rating_sum: i64, // Sum of the values of notes appended with append_rated_note
+    pub rating_count: u64, // Number of notes appended with append_rated_note
+    pub global_config: Option<Pubkey>, // The global config the tree authority is shared with, if any
+    pub compression_program: Option<Pubkey>, // The forked compression program of the tree, if any
+    pub note_count: u64,               // Number of notes appended to the tree
+    pub chain_tip: [u8; 32], // The leaf node of the last note appended with append_chained_note
+    pub recent_leaves: [RecentLeaf; RECENT_LEAVES_LEN], // The last appended leaf nodes, see RecentLeaf
+    pub total_log_bytes: u64, // Total bytes of note logs logged for the tree
+    pub max_log_bytes: u64,   // Max total bytes of note logs logged for the tree, 0 for no limit
+    pub leaf_domain: [u8; 4], // The domain tag of leaves appended from now on, zero for untagged
+    pub strict_text: bool,    // Whether appends reject notes containing control characters
+    pub last_timestamp: i64, // The created_at of the last note appended with append_timestamped_note
+    pub auto_pin_interval: u32, // Every how many notes append_note pins the note, 0 to disable
+    pub leaf_domains: [LeafDomain; MAX_LEAF_DOMAINS], // The domain tags set so far in order, see LeafDomain
+    pub leaf_domain_count: u8,                        // Number of entries of leaf_domains in use
+    pub recent_leaf_count: u64, // Number of leaf nodes recorded in recent_leaves so far
+}
+
+impl NoteTreeConfig {
+    // Space required for the config account data, excluding the discriminator
+    pub const LEN: usize = 32 + // authority
+        32 + // merkle_tree
+        4 + // max_depth
+        4 + // max_buffer_size
+        1 + // bump
+        1 + // config_version
+        1 + // hash_iterations
+        1 + 32 + // gate_mint
+        1 + // access_mode
+        8 + // reward_per_note
+        1 + // duplicate_leaf_check
+        8 + // rating_sum
+        8 + // rating_count
+        1 + 32 + // global_config
+        1 + 32 + // compression_program
+        8 + // note_count
+        32 + // chain_tip
+        RECENT_LEAVES_LEN * RecentLeaf::LEN + // recent_leaves
+        8 + // total_log_bytes
+        8 + // max_log_bytes
+        4 + // leaf_domain
+        1 + // strict_text
+        8 + // last_timestamp
+        4 + // auto_pin_interval
+        MAX_LEAF_DOMAINS * LeafDomain::LEN + // leaf_domains
+        1 + // leaf_domain_count
+        8; // recent_leaf_count
+
+    // Initialize the config of a new tree, checking the settings are valid
+    pub fn init(
+        &mut self,
+        authority: Pubkey,
+        merkle_tree: Pubkey,
+        bump: u8,
+        global_config: Option<Pubkey>,
+        args: &TreeConfigInitArgs,
+    ) -> Result<()> {
+        // Check the hash iteration count is within the allowed range
+        require!(
+            (1..=MAX_HASH_ITERATIONS).contains(&args.hash_iterations),
+            NoteError::InvalidHashIterations
+        );
+
+        self.authority = authority; // The payer who created the tree
+        self.merkle_tree = merkle_tree; // The merkle tree the config belongs to
+        self.max_depth = args.max_depth; // Max depth of the merkle tree
+        self.max_buffer_size = args.max_buffer_size; // Max buffer size of the merkle tree
+        self.bump = bump; // The bump seed for the config pda
+        self.config_version = CONFIG_VERSION; // The layout version of the config account
+        self.hash_iterations = args.hash_iterations; // Number of times each note is hashed into its leaf node
+        self.gate_mint = args.gate_mint; // Mint of the token required by append_note_gated
+        self.access_mode = args.access_mode; // Whether appends are restricted to allowlisted authors
+        self.reward_per_note = 0; // No reward until the authority sets one
+        self.duplicate_leaf_check = DuplicateLeafCheck::Off; // Duplicate leaves are appended as usual
+        self.rating_sum = 0; // No rated notes yet
+        self.rating_count = 0; // No rated notes yet
+        self.global_config = global_config; // The global config the tree authority is shared with
+        self.compression_program = None; // The canonical compression program
+        self.note_count = 0; // No notes yet
+        self.chain_tip = [0; 32]; // The chain starts from a zeroed tip
+        self.recent_leaves = Default::default(); // No leaves yet
+        self.total_log_bytes = 0; // No note logs yet
+        self.max_log_bytes = 0; // No limit until the authority sets one
+        self.leaf_domain = [0; 4]; // Untagged until the authority sets a tag
+        self.strict_text = false; // Any text until the authority opts in
+        self.last_timestamp = 0; // No timestamped notes yet
+        self.auto_pin_interval = 0; // No automatic pinning until the authority sets an interval
+        self.leaf_domains = Default::default(); // Untagged until the authority sets a tag
+        self.leaf_domain_count = 0;
+        self.recent_leaf_count = 0; // No leaves yet
+        Ok(())
+    }
+
+    // Whether logging the given note log bytes keeps the total within the max log bytes
+    pub fn fits_log_bytes(&self, len: usize) -> bool {
+        self.total_log_bytes
+            .checked_add(len as u64)
+            .is_some_and(|total| self.max_log_bytes == 0 || total <= self.max_log_bytes)
+    }
+
+    // Count logged note log bytes towards the total, failing if they would exceed the max log bytes
+    pub fn add_log_bytes(&mut self, len: usize) -> Result<()> {
+        let total_log_bytes = self
+            .total_log_bytes
+            .checked_add(len as u64)
+            .ok_or(NoteError::ArithmeticOverflow)?;
+        require!(
+            self.max_log_bytes == 0 || total_log_bytes <= self.max_log_bytes,
+            NoteError::LogBudgetExceeded
+        );
+        self.total_log_bytes = total_log_bytes;
+        Ok(())
+    }
+
+    // Record an appended leaf node in the recent leaves ring, overwriting the oldest entry
+    pub fn record_recent_leaf(&mut self, index: u32, leaf_node: [u8; 32]) {
+        self.recent_leaves[index as usize % RECENT_LEAVES_LEN] = RecentLeaf { index, leaf_node };
+        self.recent_leaf_count = self.recent_leaf_count.saturating_add(1);
+    }
+
+    // The filled entries of the recent leaves ring, ordered from the newest leaf index to the oldest.
+    // Leaves are recorded at consecutive indices, so the filled entries are the recent_leaf_count
+    // newest entries held at the position of their index, whatever their leaf node, zero included.
+    pub fn recent_leaves_newest_first(&self) -> Vec<RecentLeaf> {
+        let mut recent_leaves = self
+            .recent_leaves
+            .iter()
+            .enumerate()
+            .filter(|(position, recent_leaf)| {
+                recent_leaf.index as usize % RECENT_LEAVES_LEN == *position
+            })
+            .map(|(_, recent_leaf)| *recent_leaf)
+            .collect::<Vec<_>>();
+        recent_leaves.sort_by_key(|recent_leaf| std::cmp::Reverse(recent_leaf.index));
+        recent_leaves.truncate((self.recent_leaf_count as usize).min(RECENT_LEAVES_LEN));
+        recent_leaves
+    }
+
+    // Record a new domain tag for the leaves appended from now on, see set_leaf_domain
+    pub fn rotate_leaf_domain(&mut self, new_tag: [u8; 4]) -> Result<()> {
+        let start_index =
+            u32::try_from(self.note_count).map_err(|_| NoteError::ArithmeticOverflow)?;
+        let count = self.leaf_domain_count as usize;
+        let entry = match count.checked_sub(1) {
+            Some(last) if self.leaf_domains[last].start_index == start_index => last,
+            _ => {
+                require!(count < MAX_LEAF_DOMAINS, NoteError::TooManyLeafDomains);
+                self.leaf_domain_count += 1;
+                count
+            }
+        };
+        self.leaf_domains[entry] = LeafDomain {
+            tag: new_tag,
+            start_index,
+        };
+        self.leaf_domain = new_tag;
+        Ok(())
+    }
+
+    // The domain tag the leaf at the given index was appended with, the tag of the last
+    // set_leaf_domain at or before the index, zero for leaves appended before the first one
+    pub fn leaf_domain_at(&self, index: u32) -> [u8; 4] {
+        self.leaf_domains
+            .iter()
+            .take(self.leaf_domain_count as usize)
+            .rev()
+            .find(|leaf_domain| leaf_domain.start_index <= index)
+            .map_or([0; 4], |leaf_domain| leaf_domain.tag)
+    }
+
+    // Mix the domain tag of the given index into an untagged leaf node, giving the leaf node stored
+    // at the index, see leaf_domain_at. Every instruction recomputing a stored leaf node to verify
+    // or replace it goes through here, so notes still verify after the tree rotates its tag.
+    pub fn stored_leaf(&self, index: u32, leaf_node: [u8; 32]) -> [u8; 32] {
+        domain_leaf(self.leaf_domain_at(index), leaf_node)
+    }
+
+    // The leaf node stored at the given index for a text note, see compute_leaf and stored_leaf
+    pub fn note_leaf(&self, note: &str, index: u32) -> Result<[u8; 32]> {
+        Ok(self.stored_leaf(index, compute_leaf(note, self.hash_iterations)?))
+    }
+
+    // The id of the compression program of the tree, the canonical id unless overridden
+    pub fn compression_program_id(&self) -> Pubkey {
+        self.compression_program
+            .unwrap_or_else(SplAccountCompression::id)
+    }
+
+    // The seed the tree authority pda is derived from, the global config for shared trees
+    // and the merkle tree otherwise
+    pub fn tree_authority_seed(&self) -> Pubkey {
+        self.global_config.unwrap_or(self.merkle_tree)
+    }
+
+    // Deserialize config account data stored with any layout version up to the current one.
+    // Older data is zero padded to the current size, then fields the stored version didn't
+    // have yet are set to their defaults. Accounts created before versioning read as version 0.
+    // Every field the stored version had, the note count and other counters included, is kept.
+    pub fn try_from_versioned(data: &[u8]) -> Result<Self> {
+        Ok(Self::try_from_versioned_with_version(data)?.0)
+    }
+
+    // Deserialize config account data as try_from_versioned does, also returning the layout
+    // version the data was stored with
+    pub fn try_from_versioned_with_version(data: &[u8]) -> Result<(Self, u8)> {
+        require!(
+            data.len() >= 8 && data[..8] == Self::DISCRIMINATOR,
+            ErrorCode::AccountDiscriminatorMismatch
+        );
+
+        let mut bytes = data[8..].to_vec();
+        bytes.resize(bytes.len().max(Self::LEN), 0);
+        // The fields up to leaf_domain read the same in every layout, giving the stored version
+        // and, for versions 14 to 19, where the fields removed in version 20 start
+        let mut rest = bytes.as_slice();
+        let prefix = ConfigPrefix::deserialize(&mut rest)?;
+        let stored_version = prefix.config_version;
+        require!(
+            stored_version <= CONFIG_VERSION,
+            NoteError::UnsupportedConfigVersion
+        );
+        if (14..20).contains(&stored_version) {
+            let start = bytes.len() - rest.len();
+            bytes.drain(start..start + LEGACY_LEAF_DOMAIN_FIELDS_LEN);
+        }
+        require!(
+            bytes.len() <= Self::LEN,
+            NoteError::UnsupportedConfigVersion
+        );
+        bytes.resize(Self::LEN, 0);
+        let mut config = Self::deserialize(&mut bytes.as_slice())?;
+
+        // Set defaults for fields added after the stored version
+        if config.config_version < 2 {
+            config.hash_iterations = 1;
+        }
+        // Tagged trees before version 18 didn't record the index their tag was set at, so all
+        // their leaves are taken to be appended with the current tag
+        if config.config_version < 18 && config.leaf_domain != [0; 4] {
+            config.leaf_domains[0] = LeafDomain {
+                tag: config.leaf_domain,
+                start_index: 0,
+            };
+            config.leaf_domain_count = 1;
+        }
+        // Trees before version 19 didn't count their recorded leaves, so the entries with a
+        // nonzero leaf node are taken to be the recorded ones
+        if config.config_version < 19 {
+            config.recent_leaf_count = config
+                .recent_leaves
+                .iter()
+                .filter(|recent_leaf| recent_leaf.leaf_node != [0; 32])
+                .count() as u64;
+        }
+        config.config_version = CONFIG_VERSION;
+        Ok((config, stored_version))
+    }
+}
+
+// The fields of NoteTreeConfig up to leaf_domain, which every layout stores the same way, read by
+// try_from_versioned_with_version before it knows the stored layout
+#[derive(AnchorDeserialize)]
+struct ConfigPrefix {
+    _authority: Pubkey,
+    _merkle_tree: Pubkey,
+    _max_depth: u32,
+    _max_buffer_size: u32,
+    _bump: u8,
+    config_version: u8,
+    _hash_iterations: u8,
+    _gate_mint: Option<Pubkey>,
+    _access_mode: AccessMode,
+    _reward_per_note: u64,
+    _duplicate_leaf_check: DuplicateLeafCheck,
+    _rating_sum: i64,
+    _rating_count: u64,
+    _global_config: Option<Pubkey>,
+    _compression_program: Option<Pubkey>,
+    _note_count: u64,
+    _chain_tip: [u8; 32],
+    _recent_leaves: [RecentLeaf; RECENT_LEAVES_LEN],
+    _total_log_bytes: u64,
+    _max_log_bytes: u64,
+    _leaf_domain: [u8; 4],
+}
+
+// The number of recently appended leaf nodes cached in the config
+pub const RECENT_LEAVES_LEN: usize = 8;
+
+// A recently appended leaf node and its leaf index, cached in a ring in the config so clients
+// that just appended can build a proof without waiting for an indexer. Entry i holds the latest
+// leaf whose index is i modulo RECENT_LEAVES_LEN. It is a convenience cache, not a complete
+// record of the tree: entries are overwritten, leaves replaced later aren't updated, and unused
+// entries are zeroed, told apart from zero leaf nodes by the config's recent_leaf_count. Proofs still need the other leaves, and verifying them the tree's changelog.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct RecentLeaf {
+    pub index: u32,          // The leaf index of the leaf node
+    pub leaf_node: [u8; 32], // The leaf node appended at the index
+}
+
+impl RecentLeaf {
+    // Space required for a recent leaf
+    pub const LEN: usize = 4 + // index
+        32; // leaf_node
+}
+
+// The max number of domain tags recorded by a tree, see set_leaf_domain
+pub const MAX_LEAF_DOMAINS: usize = 8;
+
+// A domain tag set by set_leaf_domain and the index of the first leaf appended with it. Recorded
+// in order in the config, so the tag any leaf was appended with can be found from its index.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct LeafDomain {
+    pub tag: [u8; 4],     // The domain tag, zero for untagged
+    pub start_index: u32, // The index of the first leaf appended with the tag
+}
+
+impl LeafDomain {
+    // Space required for a leaf domain
+    pub const LEN: usize = 4 + // tag
+        4; // start_index
+}
+
+// How appends handle a note whose leaf equals the rightmost leaf of the tree, a cheap guard
+// against accidental double submits. Only consecutive duplicates are caught, a leaf equal to
+// any earlier leaf is appended as usual.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum DuplicateLeafCheck {
+    Off,    // Duplicate leaves are appended without a check
+    Warn,   // Duplicate leaves are appended with a warning log
+    Strict, // Duplicate leaves are rejected
+}
+
+// A note to claim a reward for, verified against the given root at the given leaf index
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct NoteClaim {
+    pub index: u32,
+    pub root: [u8; 32],
+    pub note: String,
+}
+
+// A key-value metadata entry of a note, logged by append_with_metadata
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct MetadataEntry {
+    pub key: String,
+    pub value: String,
+}
+
+// The settings of a new tree, given to create_note_tree and create_shared_tree
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct TreeConfigInitArgs {
+    pub max_depth: u32,            // Max depth of the merkle tree
+    pub max_buffer_size: u32,      // Max buffer size of the merkle tree
+    pub hash_iterations: u8,       // Number of times each note is hashed into its leaf node
+    pub gate_mint: Option<Pubkey>, // Mint of the token required by append_note_gated, if any
+    pub access_mode: AccessMode, // Whether any payer can append notes, or only allowlisted authors
+    pub canopy_depth: u32, // Canopy depth the merkle tree account was allocated with, checked against its size
+}
+
+// The defaults mirrored by DEFAULT_TREE_CONFIG_INIT_ARGS in utils/utils.ts, so clients only set
+// the settings they need to change
+impl Default for TreeConfigInitArgs {
+    fn default() -> Self {
+        Self {
+            max_depth: 14,
+            max_buffer_size: 64,
+            hash_iterations: 1,
+            gate_mint: None,
+            access_mode: AccessMode::Open,
+            canopy_depth: 0,
+        }
+    }
+}
+
+// An authority that can create shared trees, all signed for by one tree authority pda
+#[account]
+pub struct GlobalConfig {
+    pub authority: Pubkey, // The authority allowed to create shared trees
+    pub bump: u8,          // The bump seed for the global config pda
+    pub compression_program: Option<Pubkey>, // The forked compression program of new shared trees, if any
+    pub min_depth: u32, // The min max depth of trees created with the global config
+}
+
+impl GlobalConfig {
+    // Space required for the global config account data, excluding the discriminator
+    pub const LEN: usize = 32 + // authority
+        1 + // bump
+        1 + 32 + // compression_program
+        4; // min_depth
+
+    // The id of the compression program new shared trees use, the canonical id unless overridden
+    pub fn compression_program_id(&self) -> Pubkey {
+        self.compression_program
+            .unwrap_or_else(SplAccountCompression::id)
+    }
+}
+
+// The root of a tree saved at a point in time, for verify_note_at_checkpoint
+#[account]
+pub struct Checkpoint {
+    pub merkle_tree: Pubkey,   // The merkle tree the root belongs to
+    pub checkpoint_index: u32, // The index of the checkpoint, used in its pda seeds
+    pub seq: u64,              // The sequence number of the tree when the checkpoint was created
+    pub root: [u8; 32],        // The root of the tree when the checkpoint was created
+    pub slot: u64,             // The slot the checkpoint was created in
+    pub label: String,         // A label describing the checkpoint, may be empty
+}
+
+impl Checkpoint {
+    // Space required for the checkpoint account data, excluding the discriminator
+    pub const LEN: usize = 32 + // merkle_tree
+        4 + // checkpoint_index
+        8 + // seq
+        32 + // root
+        8 + // slot
+        4 + MAX_CHECKPOINT_LABEL_LEN; // label
+}
+
+// The data of a checkpoint, returned by get_checkpoint
+#[derive(AnchorSerialize)]
+pub struct CheckpointData {
+    pub root: [u8; 32], // The root of the tree when the checkpoint was created
+    pub slot: u64,      // The slot the checkpoint was created in
+    pub sequence: u64,  // The sequence number of the tree when the checkpoint was created
+    pub label: String,  // The label of the checkpoint
+}
+
+// The derivation of the tree authority pda of a tree, returned by debug_tree_authority
+#[derive(AnchorSerialize)]
+pub struct TreeAuthorityData {
+    pub seed: Pubkey, // The address the tree authority is derived from as a seed
+    pub bump: u8,     // The bump seed for the pda
+    pub tree_authority: Pubkey, // The derived tree authority pda
+}
+
+// A copy of the current root of a tree, updated by append_note_mirrored
+#[account]
+pub struct RootMirror {
+    pub merkle_tree: Pubkey, // The merkle tree the root belongs to
+    pub seq: u64,            // The sequence number of the tree when the root was mirrored
+    pub root: [u8; 32],      // The root of the tree after the latest mirrored append
+}
+
+impl RootMirror {
+    // Space required for the root mirror account data, excluding the discriminator
+    pub const LEN: usize = 32 + // merkle_tree
+        8 + // seq
+        32; // root
+}
+
+// The number of replies to a parent leaf, incremented by append_reply
+#[account]
+pub struct ReplyCount {
+    pub merkle_tree: Pubkey,   // The merkle tree the parent leaf belongs to
+    pub parent_leaf: [u8; 32], // The leaf node replied to
+    pub count: u64,            // The number of replies appended to the parent
+}
+
+impl ReplyCount {
+    // Space required for the reply count account data, excluding the discriminator
+    pub const LEN: usize = 32 + // merkle_tree
+        32 + // parent_leaf
+        8; // count
+}
+
+// The nonce of an owner's delegated appends, bumped by append_delegated
+#[account]
+pub struct DelegateNonce {
+    pub owner: Pubkey, // The owner authorizing delegated appends
+    pub nonce: u64,    // The nonce the next authorization of the owner must sign over
+}
+
+impl DelegateNonce {
+    // Space required for the delegate nonce account data, excluding the discriminator
+    pub const LEN: usize = 32 + // owner
+        8; // nonce
+}
+
+// The record of a claimed note, a pda derived from the merkle tree and the leaf index of the note,
+// created by claim_for_notes so each note can only be claimed once
+#[account]
+pub struct ClaimRecord {
+    pub merkle_tree: Pubkey, // The merkle tree the claimed note belongs to
+    pub index: u32,          // The leaf index of the claimed note
+    pub author: Pubkey,      // The author the reward was paid to
+}
+
+impl ClaimRecord {
+    // Space required for the claim record account data, excluding the discriminator
+    pub const LEN: usize = 32 + // merkle_tree
+        4 + // index
+        32; // author
+}
+
+// Who can append notes to a tree
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum AccessMode {
+    Open,       // Any payer can append notes
+    Restricted, // Only authors in the tree's allowlist can append notes
+}
+
+// The content type of a note, for renderers to branch on. Serialized as a single byte.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum ContentType {
+    Text,     // Plain text, the content type of notes appended without one
+    Markdown, // Markdown text
+    Json,     // A JSON object or array
+    Link,     // An http or https URL
+}
+
+impl ContentType {
+    // Cheaply check a note has the basic shape of the content type. Only the outline is checked,
+    // such as the brackets of JSON, not full validity. Notes are strings, so always valid UTF-8.
+    pub fn matches_shape(self, note: &str) -> bool {
+        match self {
+            ContentType::Text | ContentType::Markdown => true,
+            ContentType::Json => {
+                let note = note.trim();
+                (note.starts_with('{') && note.ends_with('}'))
+                    || (note.starts_with('[') && note.ends_with(']'))
+            }
+            ContentType::Link => {
+                (note.starts_with("https://") || note.starts_with("http://"))
+                    && !note.contains(char::is_whitespace)
+            }
+        }
+    }
+}
+
+// The max number of authors in an allowlist, keeping the account at about 1KB
+pub const MAX_ALLOWLIST_LEN: usize = 32;
+
+// The authors allowed to append notes to a restricted tree, stored in a pda derived from the merkle tree
+#[account]
+pub struct Allowlist {
+    pub merkle_tree: Pubkey,  // The merkle tree the allowlist belongs to
+    pub authors: Vec<Pubkey>, // The allowlisted authors, at most MAX_ALLOWLIST_LEN
+}
+
+impl Allowlist {
+    // Space required for the allowlist account data at max length, excluding the discriminator
+    pub const LEN: usize = 32 + // merkle_tree
+        4 + 32 * MAX_ALLOWLIST_LEN; // authors, borsh vec length prefix and keys
+}
+
+// The number of notes an author has appended to a tree, stored in a pda derived from the merkle tree and author
+#[account]
+pub struct AuthorStats {
+    pub author: Pubkey, // The author, the payer or delegating owner of the appends
+    pub merkle_tree: Pubkey, // The merkle tree the notes were appended to
+    pub count: u64,     // The number of notes appended by the author
+}
+
+impl AuthorStats {
+    // Space required for the author stats account data, excluding the discriminator
+    pub const LEN: usize = 32 + // author
+        32 + // merkle_tree
+        8; // count
+}
+
+// The max length in bytes of a staged note, keeping the staging account and note log within account and cpi limits
+pub const MAX_STAGED_NOTE_LEN: usize = 8192;
+
+// A note being written in chunks across transactions, before it is appended to the tree
+#[account]
+pub struct Staging {
+    pub payer: Pubkey,       // The payer who stages the note
+    pub merkle_tree: Pubkey, // The merkle tree the note will be appended to
+    pub total_len: u32,      // The full length of the note in bytes
+    pub data: Vec<u8>,       // The note bytes written so far
+}
+
+impl Staging {
+    // Space required for a staging account holding a note of the given total length
+    pub fn space(total_len: u32) -> usize {
+        8 + // Anchor account discriminator
+        32 + // payer
+        32 + // merkle_tree
+        4 + // total_len
+        4 + total_len as usize // data, borsh vec length prefix and bytes
+    }
+}
+
+// A note pinned to a regular account, so it can be read without an indexer
+#[account]
+pub struct Note {
+    pub author: Pubkey,      // The payer who pinned the note
+    pub merkle_tree: Pubkey, // The merkle tree the note belongs to
+    pub index: u32,          // The leaf index of the note in the merkle tree
+    pub leaf_node: [u8; 32], // The leaf node hash
+    pub note: String,        // The note message
+}
+
+impl Note {
+    // Space required for a pinned note account holding the given note message
+    pub fn space(note: &str) -> usize {
+        8 + // Anchor account discriminator
+        32 + // author
+        32 + // merkle_tree
+        4 + // index
+        32 + // leaf_node
+        4 + note.len() // note, borsh string length prefix and bytes
+    }
+}
+
+// Create a pda account owned by the given program with the given space, signed for by its seeds,
+// with the payer funding rent exemption. Like anchor's init, an address already holding lamports
+// is topped up, allocated and assigned instead, since create_account fails for a funded address.
+pub fn create_pda_account<'info>(
+    payer: &AccountInfo<'info>,
+    account: &AccountInfo<'info>,
+    system_program: &AccountInfo<'info>,
+    space: usize,
+    owner: &Pubkey,
+    signer_seeds: &[&[&[u8]]],
+) -> Result<()> {
+    let rent = Rent::get()?.minimum_balance(space);
+    if account.lamports() == 0 {
+        let cpi_ctx = CpiContext::new_with_signer(
+            system_program.clone(),
+            CreateAccount {
+                from: payer.clone(),
+                to: account.clone(),
+            },
+            signer_seeds,
+        );
+        return create_account(cpi_ctx, rent, space as u64, owner);
+    }
+
+    let top_up = rent.saturating_sub(account.lamports());
+    if top_up > 0 {
+        let cpi_ctx = CpiContext::new(
+            system_program.clone(),
+            Transfer {
+                from: payer.clone(),
+                to: account.clone(),
+            },
+        );
+        transfer(cpi_ctx, top_up)?;
+    }
+    let cpi_ctx = CpiContext::new_with_signer(
+        system_program.clone(),
+        Allocate {
+            account_to_allocate: account.clone(),
+        },
+        signer_seeds,
+    );
+    allocate(cpi_ctx, space as u64)?;
+    let cpi_ctx = CpiContext::new_with_signer(
+        system_program.clone(),
+        Assign {
+            account_to_assign: account.clone(),
+        },
+        signer_seeds,
+    );
+    assign(cpi_ctx, owner)
+}
+
+// Pin a just appended note to its verified pinned note pda, seeded like verify_and_pin, with the
+// payer as the author and paying the rent. A pda already pinned is left as is, so the append isn't
+// blocked by an existing pin, and a pda pre-funded with lamports is still created, see
+// create_pda_account. Fails with NoteError::InvalidPinAccount if the account isn't the pinned note
+// pda of the note.
+pub fn auto_pin_note<'info>(
+    accounts: &NoteAccounts<'info>,
+    pinned_note: &AccountInfo<'info>,
+    program_id: &Pubkey,
+    note_log: &NoteLog,
+) -> Result<()> {
+    let merkle_tree = accounts.merkle_tree.key();
+    let index = leaf_count(&accounts.merkle_tree)? - 1;
+    let (pinned_note_key, bump) = Pubkey::find_program_address(
+        &[b"verified", merkle_tree.as_ref(), &index_bytes(index)],
+        program_id,
+    );
+    require_keys_eq!(
+        pinned_note.key(),
+        pinned_note_key,
+        NoteError::InvalidPinAccount
+    );
+    if pinned_note.owner == program_id {
+        msg!("The note at index {} is already pinned", index);
+        return Ok(());
+    }
+
+    // Create the pinned note account, signed for by its pda seeds
+    let index_seed = index_bytes(index);
+    create_pda_account(
+        &accounts.payer.to_account_info(),
+        pinned_note,
+        &accounts.system_program.to_account_info(),
+        Note::space(&note_log.note),
+        program_id,
+        &[&[b"verified", merkle_tree.as_ref(), &index_seed, &[bump]]],
+    )?;
+
+    // Save the note data to the pinned note account, the same as verify_and_pin
+    Note {
+        author: accounts.payer.key(),
+        merkle_tree,
+        index,
+        leaf_node: note_log.leaf_node,
+        note: note_log.note.clone(),
+    }
+    .try_serialize(&mut &mut pinned_note.try_borrow_mut_data()?[..])
+}
+
+// Log a "note log" using the noop program, then append its leaf node to the merkle tree
+pub fn log_and_append(
+    accounts: &mut NoteAccounts,
+    tree_authority_bump: u8,
+    note_log: &mut NoteLog,
+    compression: LogCompression,
+) -> Result<()> {
+    // Check the tree authority pda wasn't passed as the payer
+    require_keys_neq!(
+        accounts.payer.key(),
+        accounts.tree_authority.key(),
+        NoteError::InvalidPayer
+    );
+    // Check the payer can append to the tree
+    check_allowlisted(&accounts.config, &accounts.allowlist, &accounts.payer.key())?;
+    append_note_log(
+        AppendAccounts {
+            author: accounts.payer.key(),
+            config: &mut accounts.config,
+            tree_authority: accounts.tree_authority.as_ref(),
+            merkle_tree: accounts.merkle_tree.as_ref(),
+            log_wrapper: accounts.log_wrapper.as_ref(),
+            compression_program: accounts.compression_program.as_ref(),
+            author_stats: &mut accounts.author_stats,
+        },
+        tree_authority_bump,
+        note_log,
+        compression,
+    )
+}
+
+// The accounts an append writes to, borrowed from the accounts of the appending instruction,
+// and the author the note is counted towards, the payer unless a delegate appends for an owner
+pub struct AppendAccounts<'a, 'info> {
+    pub author: Pubkey,
+    pub config: &'a mut Account<'info, NoteTreeConfig>,
+    pub tree_authority: &'a AccountInfo<'info>,
+    pub merkle_tree: &'a AccountInfo<'info>,
+    pub log_wrapper: &'a AccountInfo<'info>,
+    pub compression_program: &'a AccountInfo<'info>,
+    pub author_stats: &'a mut Account<'info, AuthorStats>,
+}
+
+// Log a "note log" using the noop program, then append its leaf node to the merkle tree and count
+// it towards the tree and the author's stats. Shared by log_and_append, append_delegated and
+// create_note_tree_with_note, the payer and allowlist checks are up to the caller.
+pub fn append_note_log(
+    accounts: AppendAccounts,
+    tree_authority_bump: u8,
+    note_log: &mut NoteLog,
+    compression: LogCompression,
+) -> Result<()> {
+    // Check every logged text field has no control characters, if the tree is strict about text
+    require!(
+        !accounts.config.strict_text || note_log.is_clean_text(),
+        NoteError::InvalidCharacters
+    );
+    // Mix the tree's current domain tag into the leaf node, if it has one
+    note_log.apply_leaf_domain(accounts.config.leaf_domain);
+    // Log the "note log" data using noop program, prefixed with the compression header,
+    // counting the logged bytes towards the tree's log budget only if the note log was logged
+    let data = compression.encode(&note_log.try_to_vec()?);
+    let len = data.len();
+    if wrap_note_log(data, accounts.log_wrapper)? {
+        accounts.config.add_log_bytes(len)?;
+    }
+    // Check the leaf node against the rightmost leaf, if the tree asks for it
+    check_duplicate_leaf(accounts.config, accounts.merkle_tree, note_log.leaf_node)?;
+    // Get the seed of the tree authority, the merkle tree or the global config for shared trees
+    let tree_authority_seed = accounts.config.tree_authority_seed();
+    // Define the seeds for pda signing
+    let signer_seeds: &[&[&[u8]]] = &[&[
+        tree_authority_seed.as_ref(), // The address the tree authority is derived from as a seed
+        &[tree_authority_bump],       // The bump seed for the pda
+    ]];
+    // Append the leaf node to the merkle tree
+    append_leaf(
+        accounts.compression_program,
+        accounts.tree_authority,
+        accounts.merkle_tree,
+        accounts.log_wrapper,
+        signer_seeds,
+        note_log.leaf_node,
+    )?;
+
+    // Cache the leaf node with its index in the config, for clients building a proof right away
+    let index = leaf_count(accounts.merkle_tree)? - 1;
+    accounts
+        .config
+        .record_recent_leaf(index, note_log.leaf_node);
+
+    // Count the note towards the tree and the author's stats
+    accounts.config.note_count = accounts
+        .config
+        .note_count
+        .checked_add(1)
+        .ok_or(NoteError::ArithmeticOverflow)?;
+    let author_stats = accounts.author_stats;
+    author_stats.author = accounts.author;
+    author_stats.merkle_tree = accounts.merkle_tree.key();
+    author_stats.count = author_stats
+        .count
+        .checked_add(1)
+        .ok_or(NoteError::ArithmeticOverflow)?;
+    Ok(())
+}
+
+// Check the leaf node against the rightmost leaf of the tree, per the tree's duplicate leaf check
+pub fn check_duplicate_leaf(
+    config: &NoteTreeConfig,
+    merkle_tree: &AccountInfo,
+    leaf_node: [u8; 32],
+) -> Result<()> {
+    let check = config.duplicate_leaf_check;
+    if check == DuplicateLeafCheck::Off || rightmost_leaf(merkle_tree)? != Some(leaf_node) {
+        return Ok(());
+    }
+    require!(
+        check != DuplicateLeafCheck::Strict,
+        NoteError::DuplicateLeaf
+    );
+    msg!("Warning: the note's leaf node equals the rightmost leaf of the tree");
+    Ok(())
+}
+
+// Read the header of merkle tree account data, checking the data holds the whole tree
+pub fn tree_header(data: &[u8]) -> Result<ConcurrentMerkleTreeHeader> {
+    require_gte!(
+        data.len(),
+        CONCURRENT_MERKLE_TREE_HEADER_SIZE_V1,
+        NoteError::InvalidMerkleTree
+    );
+    let header =
+        ConcurrentMerkleTreeHeader::try_from_slice(&data[..CONCURRENT_MERKLE_TREE_HEADER_SIZE_V1])?;
+    require_gte!(
+        data.len(),
+        CONCURRENT_MERKLE_TREE_HEADER_SIZE_V1 + merkle_tree_get_size(&header)?,
+        NoteError::InvalidMerkleTree
+    );
+    Ok(header)
+}
+
+// Get the rightmost leaf of a merkle tree account, or None if the tree is empty
+pub fn rightmost_leaf(merkle_tree: &AccountInfo) -> Result<Option<[u8; 32]>> {
+    let (leaf_count, leaf) = rightmost_path(merkle_tree)?;
+    Ok((leaf_count > 0).then_some(leaf))
+}
+
+// Get the number of leaves appended to a merkle tree account
+pub fn leaf_count(merkle_tree: &AccountInfo) -> Result<u32> {
+    Ok(rightmost_path(merkle_tree)?.0)
+}
+
+// Get the index and leaf of the rightmost proof of a merkle tree account, the index being the
+// number of leaves appended so far. The rightmost proof is stored after the change log buffer,
+// each change log holding a root, a path of max_depth nodes and a u32 index padded to 8 bytes.
+fn rightmost_path(merkle_tree: &AccountInfo) -> Result<(u32, [u8; 32])> {
+    let data = merkle_tree.try_borrow_data()?;
+    let header = tree_header(&data)?;
+    let max_depth = header.get_max_depth() as usize;
+    let max_buffer_size = header.get_max_buffer_size() as usize;
+
+    // Skip the sequence number, active index and buffer size, then the change logs
+    let change_log_len = 32 + 32 * max_depth + 8;
+    let rightmost_proof =
+        CONCURRENT_MERKLE_TREE_HEADER_SIZE_V1 + 24 + change_log_len * max_buffer_size;
+    let leaf = rightmost_proof + 32 * max_depth;
+    let index = u32::from_le_bytes(data[leaf + 32..leaf + 36].try_into().unwrap());
+    Ok((index, data[leaf..leaf + 32].try_into().unwrap()))
+}
+
+// Get the sequence number and current root of a merkle tree account.
+// The current root is the root of the change log at the active index.
+pub fn current_root(merkle_tree: &AccountInfo) -> Result<(u64, [u8; 32])> {
+    let data = merkle_tree.try_borrow_data()?;
+    let header = tree_header(&data)?;
+    let max_depth = header.get_max_depth() as usize;
+
+    // The sequence number and active index are the first fields after the header
+    let tree = CONCURRENT_MERKLE_TREE_HEADER_SIZE_V1;
+    let seq = u64::from_le_bytes(data[tree..tree + 8].try_into().unwrap());
+    let active_index = u64::from_le_bytes(data[tree + 8..tree + 16].try_into().unwrap()) as usize;
+
+    // Skip the sequence number, active index and buffer size, then the change logs before the active one
+    let change_log_len = 32 + 32 * max_depth + 8;
+    let root = tree + 24 + change_log_len * active_index;
+    Ok((seq, data[root..root + 32].try_into().unwrap()))
+}
+
+// Check a root is one of the recent roots of a merkle tree account, the roots of the change logs
+// in its buffer, which the compression program accepts proofs against. Fails with
+// NoteError::ConcurrentWriteConflict otherwise, since the root was overwritten by later changes.
+// A CPI error can't be caught by the caller, so this checks ahead of the CPI instead.
+pub fn require_recent_root(merkle_tree: &AccountInfo, root: [u8; 32]) -> Result<()> {
+    require!(
+        is_recent_root(merkle_tree, root)?,
+        NoteError::ConcurrentWriteConflict
+    );
+    Ok(())
+}
+
+// Whether a root is one of the recent roots of a merkle tree account, see require_recent_root
+pub fn is_recent_root(merkle_tree: &AccountInfo, root: [u8; 32]) -> Result<bool> {
+    let data = merkle_tree.try_borrow_data()?;
+    let header = tree_header(&data)?;
+    let max_depth = header.get_max_depth() as usize;
+    let max_buffer_size = header.get_max_buffer_size() as usize;
+
+    // The active index and buffer size follow the sequence number
+    let tree = CONCURRENT_MERKLE_TREE_HEADER_SIZE_V1;
+    let active_index = u64::from_le_bytes(data[tree + 8..tree + 16].try_into().unwrap()) as usize;
+    let buffer_size = u64::from_le_bytes(data[tree + 16..tree + 24].try_into().unwrap()) as usize;
+
+    // Walk the change logs in the buffer back from the active one
+    let change_log_len = 32 + 32 * max_depth + 8;
+    Ok((0..buffer_size).any(|age| {
+        let index = (active_index + max_buffer_size - age) % max_buffer_size;
+        let change_log_root = tree + 24 + change_log_len * index;
+        data[change_log_root..change_log_root + 32] == root
+    }))
+}
+
+// The leaf nodes the change logs in the buffer of a merkle tree account set at a leaf index, newest
+// first. A change log's path starts from the leaf it set, which may have been replaced since.
+pub fn change_log_leaves(merkle_tree: &AccountInfo, leaf_index: u32) -> Result<Vec<[u8; 32]>> {
+    let data = merkle_tree.try_borrow_data()?;
+    let header = tree_header(&data)?;
+    let max_depth = header.get_max_depth() as usize;
+    let max_buffer_size = header.get_max_buffer_size() as usize;
+
+    // The active index and buffer size follow the sequence number
+    let tree = CONCURRENT_MERKLE_TREE_HEADER_SIZE_V1;
+    let active_index = u64::from_le_bytes(data[tree + 8..tree + 16].try_into().unwrap()) as usize;
+    let buffer_size = u64::from_le_bytes(data[tree + 16..tree + 24].try_into().unwrap()) as usize;
+
+    // Walk the change logs in the buffer back from the active one, each the root, the path from
+    // the leaf and the leaf index
+    let change_log_len = 32 + 32 * max_depth + 8;
+    Ok((0..buffer_size)
+        .map(|age| {
+            let index = (active_index + max_buffer_size - age) % max_buffer_size;
+            tree + 24 + change_log_len * index
+        })
+        .filter(|change_log| {
+            let index_at = change_log + 32 + 32 * max_depth;
+            data[index_at..index_at + 4] == leaf_index.to_le_bytes()
+        })
+        .map(|change_log| data[change_log + 32..change_log + 64].try_into().unwrap())
+        .collect())
+}
+
+// Check the author, the payer or the owner of a delegated note, is allowlisted when the tree's access mode is restricted
+pub fn check_allowlisted(
+    config: &NoteTreeConfig,
+    allowlist: &AccountInfo,
+    author: &Pubkey,
+) -> Result<()> {
+    if config.access_mode == AccessMode::Open {
+        return Ok(());
+    }
+    // A restricted tree without an allowlist account has no allowlisted authors
+    let allowlist =
+        Account::<Allowlist>::try_from(allowlist).map_err(|_| error!(NoteError::NotAllowlisted))?;
+    require!(
+        allowlist.authors.contains(author),
+        NoteError::NotAllowlisted
+    );
+    Ok(())
+}
+
+// Initialize an empty merkle tree, signed for by the tree authority pda
+pub fn init_tree<'info>(
+    compression_program: &AccountInfo<'info>,
+    tree_authority: &SystemAccount<'info>,
+    merkle_tree: &UncheckedAccount<'info>,
+    log_wrapper: &Program<'info, Noop>,
+    signer_seeds: &[&[&[u8]]],
+    max_depth: u32,
+    max_buffer_size: u32,
+) -> Result<()> {
+    // Create cpi context for init_empty_merkle_tree instruction.
+    let cpi_ctx = CpiContext::new_with_signer(
+        compression_program.clone(), // The spl account compression program
+        Initialize {
+            authority: tree_authority.to_account_info(), // The authority for the merkle tree, using a PDA
+            merkle_tree: merkle_tree.to_account_info(), // The merkle tree account to be initialized
+            noop: log_wrapper.to_account_info(),        // The noop program to log data
+        },
+        signer_seeds, // The seeds for pda signing
+    );
+
+    // CPI to initialize an empty merkle tree with given max depth and buffer size
+    init_empty_merkle_tree(cpi_ctx, max_depth, max_buffer_size)
+}
+
+// Append a leaf node to the merkle tree, signed for by the tree authority pda
+pub fn append_leaf<'info>(
+    compression_program: &AccountInfo<'info>,
+    tree_authority: &AccountInfo<'info>,
+    merkle_tree: &AccountInfo<'info>,
+    log_wrapper: &AccountInfo<'info>,
+    signer_seeds: &[&[&[u8]]],
+    leaf_node: [u8; 32],
+) -> Result<()> {
+    // Create a new cpi context and append the leaf node to the merkle tree.
+    let cpi_ctx = CpiContext::new_with_signer(
+        compression_program.clone(), // The spl account compression program
+        Modify {
+            authority: tree_authority.clone(), // The authority for the merkle tree, using a PDA
+            merkle_tree: merkle_tree.clone(),  // The merkle tree account to be modified
+            noop: log_wrapper.clone(),         // The noop program to log data
+        },
+        signer_seeds, // The seeds for pda signing
+    );
+    // CPI to append the leaf node to the merkle tree
+    append(cpi_ctx, leaf_node)
+}
+
+// Check a tree authority pda is still only a signer: owned by the system program with no data.
+// Nothing should ever initialize an account at the address, this catches it if something did.
+pub fn is_signer_only_pda(tree_authority: &AccountInfo) -> bool {
+    tree_authority.owner == &System::id() && tree_authority.data_is_empty()
+}
+
+// Replace the leaf node stored in the merkle tree at the given index, using the proof nodes passed as accounts
+#[allow(clippy::too_many_arguments)]
+pub fn replace_note_leaf<'info>(
+    compression_program: &AccountInfo<'info>,
+    tree_authority: &AccountInfo<'info>,
+    merkle_tree: &AccountInfo<'info>,
+    log_wrapper: &AccountInfo<'info>,
+    signer_seeds: &[&[&[u8]]],
+    proof: &[AccountInfo<'info>],
+    root: [u8; 32],
+    previous_leaf: [u8; 32],
+    new_leaf: [u8; 32],
+    index: u32,
+) -> Result<()> {
+    // Create a new cpi context with the proof as remaining accounts
+    let cpi_ctx = CpiContext::new_with_signer(
+        compression_program.clone(), // The spl account compression program
+        Modify {
+            authority: tree_authority.clone(), // The authority for the merkle tree, using a PDA
+            merkle_tree: merkle_tree.clone(),  // The merkle tree account to be modified
+            noop: log_wrapper.clone(),         // The noop program to log data
+        },
+        signer_seeds, // The seeds for pda signing
+    )
+    .with_remaining_accounts(proof.to_vec());
+    // CPI to replace the previous leaf node with the new one
+    replace_leaf(cpi_ctx, root, previous_leaf, new_leaf, index)
+}
+
+// Verify a leaf node is stored in the merkle tree at the given index, using the proof nodes passed as accounts
+pub fn verify_note_leaf<'info>(
+    compression_program: &AccountInfo<'info>,
+    merkle_tree: &AccountInfo<'info>,
+    proof: &[AccountInfo<'info>],
+    root: [u8; 32],
+    leaf_node: [u8; 32],
+    index: u32,
+) -> Result<()> {
+    // Create cpi context for the verify_leaf instruction, with the proof as remaining accounts
+    let cpi_ctx = CpiContext::new(
+        compression_program.clone(), // The spl account compression program
+        VerifyLeaf {
+            merkle_tree: merkle_tree.clone(), // The merkle tree account to verify against
+        },
+    )
+    .with_remaining_accounts(proof.to_vec());
+    // CPI to verify the leaf node, fails if the proof is invalid
+    verify_leaf(cpi_ctx, root, leaf_node, index)
+}
+
+// Get the canopy depth of a merkle tree account, from the space left after the header and the tree
+pub fn tree_canopy_depth(merkle_tree: &AccountInfo, config: &NoteTreeConfig) -> Result<u32> {
+    require_keys_eq!(
+        *merkle_tree.owner,
+        config.compression_program_id(),
+        NoteError::InvalidMerkleTree
+    );
+    let data = merkle_tree.try_borrow_data()?;
+    let header = tree_header(&data)?;
+    // The canopy is the rest of the account after the header and the tree
+    let canopy_len =
+        data.len() - CONCURRENT_MERKLE_TREE_HEADER_SIZE_V1 - merkle_tree_get_size(&header)?;
+
+    // The canopy stores the 2^(depth + 1) - 2 nodes of every cached level below the root
+    let canopy_nodes = canopy_len / 32 + 2;
+    Ok(canopy_nodes.trailing_zeros() - 1)
+}
+
+// Log data using the noop program, first checking the noop program is actually deployed.
+// Some local or custom validators don't have the noop program, in which case this fails with
+// NoteError::NoopUnavailable, or with the "skip-missing-noop" feature only logs a warning and skips.
+// Note the spl account compression program also logs its changelog through the noop program,
+// so the CPI that follows still fails on such a validator. Skipping only applies to the note log.
+// The data is prefixed with NOTE_LOG_DISCRIMINATOR before logging. Returns whether the data was logged.
+pub fn wrap_note_log<'info>(data: Vec<u8>, log_wrapper: &AccountInfo<'info>) -> Result<bool> {
+    if !log_wrapper.executable {
+        #[cfg(feature = "skip-missing-noop")]
+        {
+            msg!("Warning: noop program is not deployed, skipping note log");
+            return Ok(false);
+        }
+        #[cfg(not(feature = "skip-missing-noop"))]
+        return err!(NoteError::NoopUnavailable);
+    }
+    let data = [NOTE_LOG_DISCRIMINATOR.as_slice(), &data].concat();
+    wrap_application_data_v1(data, &Program::<Noop>::try_from(log_wrapper)?)?;
+    Ok(true)
+}
+
+// Prefix of every note log logged by this program, before the compression header, so indexers
+// sharing the noop feed with other programs can filter this program's logs without deserializing.
+// The first 4 bytes of sha256("anchor-compressed-notes:note_log").
+pub const NOTE_LOG_DISCRIMINATOR: [u8; 4] = [63, 224, 6, 164];
+
+// Verbosity of the debug logs, 1 with the "debug-logs" feature. At 0 the level check is constant,
+// so the logs and their formatting are compiled out.
+#[cfg(feature = "debug-logs")]
+pub const LOG_LEVEL: u8 = 1;
+#[cfg(not(feature = "debug-logs"))]
+pub const LOG_LEVEL: u8 = 0;
+
+// Log a structured debug line for an instruction, if the log level is at least the given level
+pub fn debug_log(level: u8, instruction: &str, tree: &Pubkey, leaf_index: Option<u64>) {
+    if LOG_LEVEL < level {
+        return;
+    }
+    match leaf_index {
+        Some(leaf_index) => msg!(
+            "debug ix={} tree={} leaf_index={}",
+            instruction,
+            tree,
+            leaf_index
+        ),
+        None => msg!("debug ix={} tree={}", instruction, tree),
+    }
+}
+
+// The current unix timestamp and slot, from the Clock sysvar.
+// Unit tests have no Clock sysvar, so under cfg(test) this returns (0, 0) instead. Those zeros are a
+// placeholder and not real time: a timestamp or slot of 0 in a test means the clock wasn't read.
+// Program builds never set cfg(test), so always read the real clock.
+#[cfg(not(test))]
+pub fn now() -> Result<(i64, u64)> {
+    let clock = Clock::get()?;
+    Ok((clock.unix_timestamp, clock.slot))
+}
+
+// The placeholder (0, 0) timestamp and slot of unit tests, see the cfg(not(test)) now
+#[cfg(test)]
+pub fn now() -> Result<(i64, u64)> {
+    Ok((0, 0))
+}
+
+// Set the return data of the instruction, failing instead of exceeding the 1024 byte return data limit
+pub fn set_return_data_checked(data: &[u8]) -> Result<()> {
+    require!(data.len() <= MAX_RETURN_DATA, NoteError::ReturnDataTooLarge);
+    set_return_data(data);
+    Ok(())
+}
+
+// Set the serialized note log as the return data of an append, truncating the note if the note log
+// exceeds the 1024 byte return data limit rather than failing the append. Notes can't be empty, so
+// an empty note in the returned note log flags the truncation, the full note is still in the log.
+pub fn set_note_log_return_data(note_log: &NoteLog) -> Result<()> {
+    let data = note_log.try_to_vec()?;
+    if data.len() <= MAX_RETURN_DATA {
+        return set_return_data_checked(&data);
+    }
+    let mut truncated = note_log.clone();
+    truncated.note.clear();
+    set_return_data_checked(&truncated.try_to_vec()?)
+}
+
+// The default min max depth of new trees, so trees can't be created too shallow to hold many notes.
+// Overridable with the min depth of a global config.
+pub const MIN_DEPTH: u32 = 14;
+
+// Get the min depth for a new tree created by the payer, the min depth of the payer's global config
+// if one is passed as the first remaining account, and MIN_DEPTH otherwise
+pub fn min_depth(remaining_accounts: &[AccountInfo], payer: &Pubkey) -> Result<u32> {
+    match remaining_accounts.first() {
+        Some(account) => {
+            let global_config = Account::<GlobalConfig>::try_from(account)?;
+            require_keys_eq!(global_config.authority, *payer, NoteError::Unauthorized);
+            Ok(global_config.min_depth)
+        }
+        None => Ok(MIN_DEPTH),
+    }
+}
+
+// The max length in bytes of a note, for both text and raw bytes notes
+pub const MAX_NOTE_LEN: usize = 1024;
+
+// Byte order: every integer folded into a pda seed or a leaf hash is encoded as little-endian,
+// the same as borsh. Conversions go through the helpers below so the encoding is defined in one
+// place, and clients must match it exactly or derived addresses and proofs won't verify.
+// The known answer tests in tests/anchor-compressed-notes.ts are the reference for clients.
+
+// Encode a leaf index as little-endian bytes, as used in the pinned note pda seeds
+pub fn index_bytes(index: u32) -> [u8; 4] {
+    index.to_le_bytes()
+}
+
+// The max number of hash iterations, bounding the compute spent on each leaf node
+pub const MAX_HASH_ITERATIONS: u8 = 64;
+
+// The max length in bytes of a note namespace
+pub const MAX_NAMESPACE_LEN: usize = 32;
+
+// The max number of notes appended by one append_batch_summarized
+pub const MAX_BATCH_SIZE: usize = 8;
+
+// The max number of metadata entries of a note
+pub const MAX_METADATA_ENTRIES: usize = 8;
+
+// The max serialized length in bytes of the metadata entries of a note, including length prefixes
+pub const MAX_METADATA_LEN: usize = 256;
+
+// The max length in bytes of a note title
+pub const MAX_TITLE_LEN: usize = 64;
+
+// The max length in bytes of a checkpoint label
+pub const MAX_CHECKPOINT_LABEL_LEN: usize = 32;
+
+// Compute unit estimates for clients setting ComputeBudgetInstruction::set_compute_unit_limit,
+// since long proofs can exceed the default limit. They are upper bounds including the noop and
+// compression program CPIs, and the "Compute Estimates" test fails if an instruction exceeds them.
+// The estimate of an append covers a single uncompressed note, up to the longest that fits in a
+// transaction and up to MAX_HASH_ITERATIONS, measured with append_note.
+pub const APPEND_CU_ESTIMATE: u32 = 60_000;
+
+// The estimate of append_note_compressed, deflating the longest note that fits in a transaction
+pub const COMPRESSED_APPEND_CU_ESTIMATE: u32 = 400_000;
+
+// The estimate of finalize_staged_note, hashing and logging a note of up to MAX_STAGED_NOTE_LEN bytes
+pub const STAGED_APPEND_CU_ESTIMATE: u32 = 120_000;
+
+// The compute unit estimate of append_batch_summarized with the given number of notes, one append each
+pub fn batch_append_cu_estimate(note_count: usize) -> u32 {
+    APPEND_CU_ESTIMATE.saturating_mul(note_count as u32)
+}
+
+// The base estimate of an instruction replacing a leaf, such as update_title, before its proof
+pub const UPDATE_CU_ESTIMATE_BASE: u32 = 40_000;
+
+// The estimate per proof node passed to an instruction replacing a leaf, hashed on-chain twice,
+// once to check the old leaf and once by the compression program
+pub const UPDATE_CU_ESTIMATE_PER_PROOF_NODE: u32 = 1_500;
+
+// The compute unit estimate of an instruction replacing a leaf with the given number of proof nodes
+pub fn update_cu_estimate(proof_len: usize) -> u32 {
+    UPDATE_CU_ESTIMATE_BASE
+        .saturating_add(UPDATE_CU_ESTIMATE_PER_PROOF_NODE.saturating_mul(proof_len as u32))
+}
+
+// The compute unit estimate of swap_notes with the given number of proof nodes per note, two leaf replaces
+pub fn swap_cu_estimate(proof_len: usize) -> u32 {
+    update_cu_estimate(proof_len).saturating_mul(2)
+}
+
+// Frame a namespace and a note into the bytes hashed into the leaf node of a namespaced note:
+// the namespace length as a little-endian u32, the namespace, then the note. The length prefix
+// keeps the boundary unambiguous, so ("ab", "c") and ("a", "bc") hash to different leaves.
+// Titled notes are framed the same way, with the title in place of the namespace, and hashed with
+// their own tag, see LEAF_TAG_TITLED, so a title and body never give the leaf of a namespace and note.
+pub fn frame_namespaced(namespace: &str, note: &str) -> Vec<u8> {
+    let mut framed = Vec::with_capacity(4 + namespace.len() + note.len());
+    framed.extend_from_slice(&(namespace.len() as u32).to_le_bytes());
+    framed.extend_from_slice(namespace.as_bytes());
+    framed.extend_from_slice(note.as_bytes());
+    framed
+}
+
+// The message an owner signs to authorize a delegate's append, the hash of the program id, the merkle tree,
+// the note, the delegate and the nonce. The program id and tree bind the authorization to one tree of one
+// deployment, so it can't be replayed on another tree sharing the owner's nonce, or another deployment.
+pub fn delegated_message(
+    program_id: &Pubkey,
+    merkle_tree: &Pubkey,
+    note: &str,
+    delegate: &Pubkey,
+    nonce: u64,
+) -> [u8; 32] {
+    keccak::hashv(&[
+        program_id.as_ref(),
+        merkle_tree.as_ref(),
+        note.as_bytes(),
+        delegate.as_ref(),
+        &nonce.to_le_bytes(),
+    ])
+    .to_bytes()
+}
+
+// Read the public key, signature and message of the ed25519 program instruction right before the current one.
+// The runtime already verified the signature, so this only extracts what was verified. Only a single
+// signature with its data inside the ed25519 instruction itself is accepted, see Ed25519SignatureOffsets.
+pub fn ed25519_instruction_parts(
+    instructions: &AccountInfo,
+) -> Result<([u8; 32], [u8; 64], Vec<u8>)> {
+    let instruction = get_instruction_relative(-1, instructions)
+        .map_err(|_| error!(NoteError::InvalidSignature))?;
+    require_keys_eq!(
+        instruction.program_id,
+        ed25519_program::ID,
+        NoteError::InvalidSignature
+    );
+
+    // One signature, a padding byte, then seven u16 offsets
+    let data = &instruction.data;
+    require!(
+        data.len() >= 16 && data[0] == 1,
+        NoteError::InvalidSignature
+    );
+    let offset = |i: usize| u16::from_le_bytes([data[2 + 2 * i], data[3 + 2 * i]]) as usize;
+    // The instruction index offsets must all point at the ed25519 instruction itself
+    require!(
+        [1, 3, 6].iter().all(|&i| offset(i) == u16::MAX as usize),
+        NoteError::InvalidSignature
+    );
+    let slice = |start: usize, len: usize| {
+        data.get(start..start + len)
+            .ok_or_else(|| error!(NoteError::InvalidSignature))
+    };
+    let signature = slice(offset(0), 64)?.try_into().unwrap();
+    let pubkey = slice(offset(2), 32)?.try_into().unwrap();
+    let message = slice(offset(4), offset(5))?.to_vec();
+    Ok((pubkey, signature, message))
+}
+
+// The status of the checks try_append_note soft fails on that only depend on the note:
+// the checks of compute_leaf, and is_clean_text for strict text trees
+pub fn note_status(config: &NoteTreeConfig, note: &str) -> AppendStatus {
+    if note.is_empty() {
+        AppendStatus::NoteEmpty
+    } else if note.len() > MAX_NOTE_LEN {
+        AppendStatus::NoteTooLong
+    } else if config.strict_text && !is_clean_text(note) {
+        AppendStatus::InvalidCharacters
+    } else {
+        AppendStatus::Appended
+    }
+}
+
+// Whether a note has no C0 control characters other than tab and newline, see set_strict_text
+pub fn is_clean_text(note: &str) -> bool {
+    note.chars()
+        .all(|c| !matches!(c, '\0'..='\x1f') || c == '\t' || c == '\n')
+}
+
+// Decode a 64 character hex string, either case, into a leaf node
+pub fn decode_hex_leaf(hex: &str) -> Result<[u8; 32]> {
+    require_eq!(hex.len(), 64, NoteError::InvalidHex);
+    let mut leaf_node = [0; 32];
+    let digits = hex
+        .chars()
+        .map(|c| c.to_digit(16).ok_or(NoteError::InvalidHex))
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    for (byte, pair) in leaf_node.iter_mut().zip(digits.chunks(2)) {
+        *byte = (pair[0] * 16 + pair[1]) as u8;
+    }
+    Ok(leaf_node)
+}
+
+// Check the bytes of a note, the UTF-8 bytes of a text note or the data of a raw bytes note:
+// fails with NoteError::NoteEmpty for an empty note and NoteError::NoteTooLong past MAX_NOTE_LEN.
+pub fn check_note_bytes(data: &[u8]) -> Result<()> {
+    require!(!data.is_empty(), NoteError::NoteEmpty);
+    require!(data.len() <= MAX_NOTE_LEN, NoteError::NoteTooLong);
+    Ok(())
+}
+
+// Hash a note message into the leaf node stored in the merkle tree.
+// Every note appended or verified goes through here or NoteLog::new_bytes, so the note is checked
+// centrally, see check_note_bytes.
+// Text notes are hashed without a tag byte, so the leaves of existing and adopted trees stay valid.
+pub fn compute_leaf(note: &str, hash_iterations: u8) -> Result<[u8; 32]> {
+    check_note_bytes(note.as_bytes())?;
+    Ok(iterate_leaf(
+        keccak::hashv(&[note.as_bytes()]).to_bytes(),
+        hash_iterations,
+    ))
+}
+
+// Mix a domain tag into a leaf node, hashing the tag followed by the leaf node.
+// The zero tag leaves the leaf node untagged, so trees that never set a tag keep their leaves.
+pub fn domain_leaf(leaf_domain: [u8; 4], leaf_node: [u8; 32]) -> [u8; 32] {
+    if leaf_domain == [0; 4] {
+        leaf_node
+    } else {
+        keccak::hashv(&[&leaf_domain, &leaf_node]).to_bytes()
+    }
+}
+
+// The tag byte hashed before the payload of a leaf node, one per note variant other than text.
+// Without it the payload of one variant could be crafted to be the payload of another, such as raw
+// bytes holding the framing of a namespaced note, and both would have the same leaf node.
+// Text notes stay untagged, see compute_leaf, so the tags are bytes that never start valid UTF-8,
+// and no text note can hash the payload of a tagged variant either.
+pub const LEAF_TAG_BYTES: u8 = 0xf8;
+pub const LEAF_TAG_NAMESPACED: u8 = 0xf9;
+pub const LEAF_TAG_CHAINED: u8 = 0xfa;
+pub const LEAF_TAG_DELEGATED: u8 = 0xfb;
+pub const LEAF_TAG_TITLED: u8 = 0xfc;
+pub const LEAF_TAG_REPLY: u8 = 0xfd;
+pub const LEAF_TAG_DEPENDENT: u8 = 0xfe;
+pub const LEAF_TAG_RAW: u8 = 0xff;
+
+// Hash the payload of a note variant into the leaf node stored in the merkle tree.
+// The first hash is over the variant's tag byte then the payload, each further iteration hashes
+// the previous hash, so clients must use the hash_iterations stored in the tree config to reconstruct leaves.
+pub fn compute_bytes_leaf(leaf_tag: u8, data: &[u8], hash_iterations: u8) -> [u8; 32] {
+    iterate_leaf(
+        keccak::hashv(&[&[leaf_tag], data]).to_bytes(),
+        hash_iterations,
+    )
+}
+
+// Rehash a first hash once for each further hash iteration.
+fn iterate_leaf(mut leaf_node: [u8; 32], hash_iterations: u8) -> [u8; 32] {
+    for _ in 1..hash_iterations {
+        leaf_node = keccak::hashv(&[&leaf_node]).to_bytes();
+    }
+    leaf_node
+}
+
+// The number of proof nodes of a full proof for a tree, one per level, as verify_note_inline expects
+pub fn expected_proof_len(config: &NoteTreeConfig) -> usize {
+    config.max_depth as usize
+}
+
+// Recompute the root of a merkle tree from a leaf node, its proof and its leaf index, the same
+// way the compression program does. At each level the bit of the index picks the hashing order:
+// 0 hashes the node then the proof node, 1 the proof node then the node.
+pub fn compute_root(leaf_node: [u8; 32], proof: &[[u8; 32]], leaf_index: u32) -> [u8; 32] {
+    let mut node = leaf_node;
+    for (level, sibling) in proof.iter().enumerate() {
+        node = if leaf_index >> level & 1 == 0 {
+            keccak::hashv(&[&node, sibling]).to_bytes()
+        } else {
+            keccak::hashv(&[sibling, &node]).to_bytes()
+        };
+    }
+    node
+}
+
+// Compute the root of a batch of leaf nodes, for the BatchAppended event. The leaf nodes are hashed
+// in pairs in order, keccak(left || right), level by level until one node is left. A level with
+// an odd number of nodes carries its last node up unhashed, and a batch of one leaf node is its root.
+pub fn batch_root(leaf_nodes: &[[u8; 32]]) -> [u8; 32] {
+    let mut level = leaf_nodes.to_vec();
+    while level.len() > 1 {
+        level = level
+            .chunks(2)
+            .map(|pair| match pair {
+                [left, right] => keccak::hashv(&[left, right]).to_bytes(),
+                [node] => *node,
+                _ => unreachable!(),
+            })
+            .collect();
+    }
+    level.first().copied().unwrap_or_default()
+}
+
+// The compression of a logged note log. Every logged note log is prefixed with this as a 1 byte
+// header after NOTE_LOG_DISCRIMINATOR, so indexers know whether to inflate the rest of the data
+// before deserializing it.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum LogCompression {
+    None,    // The note log bytes as is
+    Deflate, // The note log bytes raw deflate compressed
+}
+
+// Compression level for deflate, favouring low compute over ratio since compressing runs on-chain
+pub const LOG_COMPRESSION_LEVEL: u8 = 1;
+
+impl LogCompression {
+    // Prefix the note log bytes with the compression header, compressing them if needed
+    pub fn encode(self, note_log: &[u8]) -> Vec<u8> {
+        let mut data = vec![self as u8];
+        match self {
+            LogCompression::None => data.extend_from_slice(note_log),
+            LogCompression::Deflate => {
+                data.extend(compress_to_vec(note_log, LOG_COMPRESSION_LEVEL))
+            }
+        }
+        data
+    }
+}
+
+// The outcome of try_append_note, returned as a single byte, the variant index
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
+pub enum AppendStatus {
+    Appended,          // The note was appended
+    NoteEmpty,         // The note is empty
+    NoteTooLong,       // The note exceeds MAX_NOTE_LEN
+    InvalidCharacters, // The note has control characters and the tree has strict text mode on
+    LogBudgetExceeded, // The note log would exceed the tree's max log bytes
+}
+
+// Event emitted when a batch of notes is appended, see batch_root for the batch root
+#[event]
+pub struct BatchAppended {
+    pub tree: Pubkey,
+    pub batch_root: [u8; 32],
+    pub count: u32,
+    pub root: [u8; 32],
+}
+
+// Event emitted when the title of a note is updated, with the new leaf node of the note
+#[event]
+pub struct NoteUpdated {
+    pub tree: Pubkey,
+    pub index: u32,
+    pub old_title: String,
+    pub new_title: String,
+    pub leaf_node: [u8; 32],
+}
+
+// Event emitted when two notes are swapped, with the new leaf node at each index
+#[event]
+pub struct NotesSwapped {
+    pub tree: Pubkey,
+    pub index_a: u32,
+    pub index_b: u32,
+    pub leaf_a: [u8; 32],
+    pub leaf_b: [u8; 32],
+}
+
+// Event emitted when a new tree is created, the authority being the payer who created it
+#[event]
+pub struct TreeCreated {
+    pub tree: Pubkey,
+    pub authority: Pubkey,
+    pub max_depth: u32,
+    pub max_buffer_size: u32,
+    pub slot: u64,
+}
+
+// The bits of the healthcheck return data, each set if its check passed
+pub const HEALTH_CONFIG: u8 = 1 << 0; // The config is the config pda of the tree and deserializes, with any layout version
+pub const HEALTH_AUTHORITY: u8 = 1 << 1; // The config authority is the given authority
+pub const HEALTH_TREE_AUTHORITY: u8 = 1 << 2; // The tree authority derives from the tree, or the global config, and holds no data
+pub const HEALTH_COMPRESSION_PROGRAM: u8 = 1 << 3; // The compression program is the canonical one, or the config's fork
+pub const HEALTH_NOOP_PROGRAM: u8 = 1 << 4; // The noop program is the canonical one and deployed
+pub const HEALTH_MERKLE_TREE: u8 = 1 << 5; // The merkle tree is initialized, with the tree authority as its authority
+
+// The (max_depth, max_buffer_size) pairs supported by the spl account compression program
+pub const SUPPORTED_TREE_SIZES: [(u32, u32); 26] = [
+    (3, 8),
+    (5, 8),
+    (14, 64),
+    (14, 256),
+    (14, 1024),
+    (14, 2048),
+    (15, 64),
+    (16, 64),
+    (17, 64),
+    (18, 64),
+    (19, 64),
+    (20, 64),
+    (20, 256),
+    (20, 1024),
+    (20, 2048),
+    (24, 64),
+    (24, 256),
+    (24, 512),
+    (24, 1024),
+    (24, 2048),
+    (26, 512),
+    (26, 1024),
+    (26, 2048),
+    (30, 512),
+    (30, 1024),
+    (30, 2048),
+];
+
+// A supported tree size, returned by supported_tree_sizes
+#[derive(AnchorSerialize)]
+pub struct TreeSize {
+    pub max_depth: u32,
+    pub max_buffer_size: u32,
+    pub space: u64, // Space of the merkle tree account without a canopy, add 32 * (2^(canopy_depth + 1) - 2) for one
+}
+
+impl TreeSize {
+    // Get the space of a tree from the compression program, using a header initialized the same
+    // way the compression program initializes one from zeroed account data
+    pub fn new(max_depth: u32, max_buffer_size: u32) -> Result<Self> {
+        let mut header = ConcurrentMerkleTreeHeader::try_from_slice(
+            &[0; CONCURRENT_MERKLE_TREE_HEADER_SIZE_V1],
+        )?;
+        header.initialize(max_depth, max_buffer_size, &Pubkey::default(), 0);
+        let space = CONCURRENT_MERKLE_TREE_HEADER_SIZE_V1 + merkle_tree_get_size(&header)?;
+        Ok(Self {
+            max_depth,
+            max_buffer_size,
+            space: space as u64,
+        })
+    }
+}
+
+// The space of a merkle tree account with the given max depth, max buffer size and canopy depth,
+// the size to allocate the account with before creating the tree. The canopy stores the
+// 2^(canopy_depth + 1) - 2 nodes of every cached level below the root. Fails with
+// NoteError::ArithmeticOverflow for a canopy depth whose space doesn't fit in a usize.
+pub fn required_tree_space(
+    max_depth: u32,
+    max_buffer_size: u32,
+    canopy_depth: u32,
+) -> Result<usize> {
+    let canopy_space = canopy_depth
+        .checked_add(1)
+        .and_then(|levels| 1usize.checked_shl(levels))
+        .and_then(|nodes| (nodes - 2).checked_mul(32))
+        .ok_or(NoteError::ArithmeticOverflow)?;
+    (TreeSize::new(max_depth, max_buffer_size)?.space as usize)
+        .checked_add(canopy_space)
+        .ok_or_else(|| NoteError::ArithmeticOverflow.into())
+}
+
+// Check a merkle tree account has the space required for the tree settings, failing with
+// NoteError::WrongTreeAccountSize, which logs the expected size as left and the actual size as right
+pub fn check_tree_account_size(merkle_tree: &AccountInfo, args: &TreeConfigInitArgs) -> Result<()> {
+    require_eq!(
+        required_tree_space(args.max_depth, args.max_buffer_size, args.canopy_depth)?,
+        merkle_tree.data_len(),
+        NoteError::WrongTreeAccountSize
+    );
+    Ok(())
+}
+
+// The layout version of the note log, the first byte of every logged note log.
+// Version 2 appended the value field, version 3 the genesis field, version 4 the namespace field,
+// version 5 the prev_leaf field, version 6 the title field, version 7 the raw_leaf field,
+// version 8 the content_type field, version 9 the metadata field, version 10 the owner field,
+// version 11 the leaf_domain field, version 12 the created_at field,
+// version 13 the parent_leaf and ref_leaf fields.
+pub const NOTE_LOG_VERSION: u8 = 13;
+
+// Define a schema for data that will be logged using noop program
+#[derive(AnchorSerialize, Clone)]
+pub struct NoteLog {
+    version: u8,                   // The layout version of the note log, see NOTE_LOG_VERSION
+    leaf_node: [u8; 32],           // The leaf node hash
+    note: String,                  // The note message, empty for raw bytes notes
+    data: Vec<u8>,                 // The raw bytes of the note, empty for text notes
+    priority: u8,                  // The priority of the note for client sorting, 0 by default
+    value: i64,                    // The numeric value of the note for aggregation, 0 by default
+    genesis: bool, // Whether the note is the genesis note, the first leaf of its tree
+    namespace: String, // The namespace of the note, empty for notes without one
+    prev_leaf: Option<[u8; 32]>, // The chain tip a chained note was hashed with, if any
+    title: String, // The title of the note, empty for notes without one
+    raw_leaf: bool, // Whether the note is hex, decoded before hashing it into the leaf node
+    content_type: ContentType, // The content type of the note, Text by default
+    metadata: Vec<MetadataEntry>, // The key-value metadata of the note, empty by default
+    owner: Option<Pubkey>, // The owner a delegated note was authorized by, if any
+    leaf_domain: [u8; 4], // The domain tag of the tree mixed into the leaf node, zero for untagged
+    created_at: i64, // The unix timestamp of a timestamped note, 0 for notes without one
+    parent_leaf: Option<[u8; 32]>, // The parent leaf a reply was hashed with, if any
+    ref_leaf: Option<[u8; 32]>, // The referenced leaf a dependent note was hashed with, if any
+}
+
+impl NoteLog {
+    // A note log of the given note or data with every other field at its default, and no leaf node yet
+    fn base(note: String, data: Vec<u8>) -> Self {
+        Self {
+            version: NOTE_LOG_VERSION,
+            leaf_node: [0; 32],
+            note,
+            data,
+            priority: 0,
+            value: 0,
+            genesis: false,
+            namespace: String::new(),
+            prev_leaf: None,
+            title: String::new(),
+            raw_leaf: false,
+            content_type: ContentType::Text,
+            metadata: Vec::new(),
+            owner: None,
+            leaf_domain: [0; 4],
+            created_at: 0,
+            parent_leaf: None,
+            ref_leaf: None,
+        }
+    }
+
+    // Constructs a new note from given message, hashing it into the leaf node.
+    // Fails if the note is empty or too long, see compute_leaf.
+    pub fn new(note: String, hash_iterations: u8) -> Result<Self> {
+        let leaf_node = compute_leaf(&note, hash_iterations)?;
+        let mut note_log = Self::base(note, Vec::new());
+        note_log.leaf_node = leaf_node;
+        Ok(note_log)
+    }
+
+    // Constructs a new note stamped with the current unix timestamp, see now. Fails with
+    // NoteError::NonMonotonicTime if the time is before the last timestamp, and as new does.
+    pub fn new_timestamped(note: String, hash_iterations: u8, last_timestamp: i64) -> Result<Self> {
+        let (created_at, _) = now()?;
+        require_gte!(created_at, last_timestamp, NoteError::NonMonotonicTime);
+        let mut note_log = Self::new(note, hash_iterations)?;
+        note_log.created_at = created_at;
+        Ok(note_log)
+    }
+
+    // Constructs a new note from a reassembled staged message, hashing it into the leaf node.
+    // Staged notes are checked against MAX_STAGED_NOTE_LEN when staging starts, so may be longer than MAX_NOTE_LEN.
+    pub fn new_staged(note: String, hash_iterations: u8) -> Self {
+        let mut note_log = Self::base(note, Vec::new());
+        note_log.leaf_node = note_log.leaf(hash_iterations);
+        note_log
+    }
+
+    // Constructs a new raw bytes note from given data, hashing it into the leaf node.
+    // Fails if the data is empty or too long, the same checks as compute_leaf.
+    pub fn new_bytes(data: Vec<u8>, hash_iterations: u8) -> Result<Self> {
+        check_note_bytes(&data)?;
+        let mut note_log = Self::base(String::new(), data);
+        note_log.leaf_node = note_log.leaf(hash_iterations);
+        Ok(note_log)
+    }
+
+    // Constructs a new note under a namespace from given namespace and message, hashing both into the leaf node
+    pub fn new_namespaced(namespace: String, note: String, hash_iterations: u8) -> Result<Self> {
+        let mut note_log = Self::new(note, hash_iterations)?;
+        note_log.namespace = namespace;
+        note_log.leaf_node = note_log.leaf(hash_iterations);
+        Ok(note_log)
+    }
+
+    // Constructs a new raw leaf note from the hex string and its decoded bytes, hashing them into the leaf node
+    pub fn new_raw_leaf(hex: String, raw_leaf: [u8; 32]) -> Self {
+        let mut note_log = Self::base(hex, Vec::new());
+        note_log.raw_leaf = true;
+        note_log.leaf_node = compute_bytes_leaf(LEAF_TAG_RAW, &raw_leaf, 1);
+        note_log
+    }
+
+    // Constructs a new titled note from given title and body, hashing both into the leaf node
+    pub fn new_titled(title: String, body: String, hash_iterations: u8) -> Result<Self> {
+        let mut note_log = Self::new(body, hash_iterations)?;
+        note_log.title = title;
+        note_log.leaf_node = note_log.leaf(hash_iterations);
+        Ok(note_log)
+    }
+
+    // Constructs a new note appended by a delegate from the owner and given message, hashing both into the leaf node
+    pub fn new_delegated(owner: Pubkey, note: String, hash_iterations: u8) -> Result<Self> {
+        let mut note_log = Self::new(note, hash_iterations)?;
+        note_log.owner = Some(owner);
+        note_log.leaf_node = note_log.leaf(hash_iterations);
+        Ok(note_log)
+    }
+
+    // Constructs a new note from the chain tip and given message, hashing both into the leaf node
+    pub fn new_chained(prev_leaf: [u8; 32], note: String) -> Result<Self> {
+        let mut note_log = Self::new(note, 1)?;
+        note_log.prev_leaf = Some(prev_leaf);
+        note_log.leaf_node = note_log.leaf(1);
+        Ok(note_log)
+    }
+
+    // Constructs a new reply from the parent leaf node and given message, hashing both into the leaf node
+    pub fn new_reply(parent_leaf: [u8; 32], note: String) -> Result<Self> {
+        let mut note_log = Self::new(note, 1)?;
+        note_log.parent_leaf = Some(parent_leaf);
+        note_log.leaf_node = note_log.leaf(1);
+        Ok(note_log)
+    }
+
+    // Constructs a new dependent note from the referenced leaf node and given message, hashing both into the leaf node
+    pub fn new_dependent(ref_leaf: [u8; 32], note: String) -> Result<Self> {
+        let mut note_log = Self::new(note, 1)?;
+        note_log.ref_leaf = Some(ref_leaf);
+        note_log.leaf_node = note_log.leaf(1);
+        Ok(note_log)
+    }
+
+    // Reconstruct the leaf node of the note log, hashing the same bytes every append instruction does.
+    // Only the note, or the data of a raw bytes note, is hashed, framed with the namespace or title
+    // if it has one, or prefixed with the chain tip, parent leaf, referenced leaf or owner if it has one,
+    // the other fields are metadata.
+    // The payload is prefixed with the tag byte of its variant, text notes excepted, see LEAF_TAG_BYTES.
+    // The note of a raw leaf note is hex, hashed into the leaf node once when constructed, see
+    // new_raw_leaf, so it is returned as is.
+    // The domain tag, if any, is mixed in last, see domain_leaf.
+    // Clients must match this, noteLogLeaf in utils/utils.ts mirrors it.
+    pub fn leaf(&self, hash_iterations: u8) -> [u8; 32] {
+        if self.raw_leaf {
+            return self.leaf_node;
+        }
+        let leaf_node = if let Some(prev_leaf) = self.prev_leaf {
+            keccak::hashv(&[&[LEAF_TAG_CHAINED], &prev_leaf, self.note.as_bytes()]).to_bytes()
+        } else if let Some(parent_leaf) = self.parent_leaf {
+            keccak::hashv(&[&[LEAF_TAG_REPLY], &parent_leaf, self.note.as_bytes()]).to_bytes()
+        } else if let Some(ref_leaf) = self.ref_leaf {
+            keccak::hashv(&[&[LEAF_TAG_DEPENDENT], &ref_leaf, self.note.as_bytes()]).to_bytes()
+        } else if let Some(owner) = self.owner {
+            compute_bytes_leaf(
+                LEAF_TAG_DELEGATED,
+                &[owner.as_ref(), self.note.as_bytes()].concat(),
+                hash_iterations,
+            )
+        } else if !self.namespace.is_empty() {
+            compute_bytes_leaf(
+                LEAF_TAG_NAMESPACED,
+                &frame_namespaced(&self.namespace, &self.note),
+                hash_iterations,
+            )
+        } else if !self.title.is_empty() {
+            compute_bytes_leaf(
+                LEAF_TAG_TITLED,
+                &frame_namespaced(&self.title, &self.note),
+                hash_iterations,
+            )
+        } else if self.data.is_empty() {
+            iterate_leaf(
+                keccak::hashv(&[self.note.as_bytes()]).to_bytes(),
+                hash_iterations,
+            )
+        } else {
+            compute_bytes_leaf(LEAF_TAG_BYTES, &self.data, hash_iterations)
+        };
+        domain_leaf(self.leaf_domain, leaf_node)
+    }
+
+    // Whether every text field logged with the note is clean text, see is_clean_text: the note,
+    // the title, the namespace and the metadata keys and values.
+    pub fn is_clean_text(&self) -> bool {
+        [&self.note, &self.title, &self.namespace]
+            .into_iter()
+            .chain(
+                self.metadata
+                    .iter()
+                    .flat_map(|entry| [&entry.key, &entry.value]),
+            )
+            .all(|text| is_clean_text(text))
+    }
+
+    // Mix the domain tag of the tree into the untagged leaf node, when appending.
+    // Raw leaf notes are never domain tagged, so their leaf node only depends on the hex.
+    pub fn apply_leaf_domain(&mut self, leaf_domain: [u8; 4]) {
+        if !self.raw_leaf {
+            self.leaf_domain = leaf_domain;
+            self.leaf_node = domain_leaf(leaf_domain, self.leaf_node);
+        }
+    }
+}
+
+#[error_code]
+pub enum NoteError {
+    #[msg("The noop program is not deployed on this cluster")]
+    NoopUnavailable,
+    #[msg("The signer is not authorized for this action")]
+    Unauthorized,
+    #[msg("The config account layout version is not supported by this program")]
+    UnsupportedConfigVersion,
+    #[msg("The note exceeds the max note length")]
+    NoteTooLong,
+    #[msg("The hash iteration count is outside the allowed range")]
+    InvalidHashIterations,
+    #[msg("The tree has no gate mint configured")]
+    GateNotConfigured,
+    #[msg("The token account is not for the tree's gate mint")]
+    GateMintMismatch,
+    #[msg("The payer's gate token balance is below the required minimum")]
+    InsufficientGateBalance,
+    #[msg("The payer is not an allowlisted author of this tree")]
+    NotAllowlisted,
+    #[msg("The author is already allowlisted")]
+    AlreadyAllowlisted,
+    #[msg("The allowlist is full")]
+    AllowlistFull,
+    #[msg("The return data exceeds the 1024 byte limit")]
+    ReturnDataTooLarge,
+    #[msg("The staged note exceeds the max staged note length")]
+    StagedNoteTooLong,
+    #[msg("The chunk writes past the end of the staged note")]
+    StagingOverflow,
+    #[msg("The staged note has not been fully written")]
+    StagingIncomplete,
+    #[msg("The note is not valid UTF-8")]
+    InvalidUtf8,
+    #[msg("Arithmetic overflow")]
+    ArithmeticOverflow,
+    #[msg("The tree's canopy doesn't cover enough levels to verify without a proof")]
+    CanopyTooShallow,
+    #[msg("The merkle tree account is not a valid compression tree")]
+    InvalidMerkleTree,
+    #[msg("No reward is configured for the tree")]
+    RewardNotConfigured,
+    #[msg("Claimed note indices must be strictly increasing")]
+    DuplicateClaim,
+    #[msg("The number of proof and claim record accounts doesn't match the claimed notes")]
+    InvalidProofAccounts,
+    #[msg("The note's leaf node equals the rightmost leaf of the tree")]
+    DuplicateLeaf,
+    #[msg("The tree authority pda can't be the payer")]
+    InvalidPayer,
+    #[msg("The tree already has a leaf")]
+    NotEmpty,
+    #[msg("The note doesn't verify against the checkpoint root")]
+    LeafMismatch,
+    #[msg("The namespace must be between 1 and the max namespace length")]
+    InvalidNamespace,
+    #[msg("The checkpoint label exceeds the max checkpoint label length")]
+    CheckpointLabelTooLong,
+    #[msg("The max depth is below the min depth")]
+    DepthTooShallow,
+    #[msg("The title must be between 1 and the max title length")]
+    InvalidTitle,
+    #[msg("The tree authority must be a system account with no data")]
+    InvalidTreeAuthority,
+    #[msg("The batch must have between 1 and the max batch size notes")]
+    InvalidBatchSize,
+    #[msg("The tree account must be closed first")]
+    TreeNotClosed,
+    #[msg("The leaf node must be 64 hex characters")]
+    InvalidHex,
+    #[msg("The note doesn't have the shape of its content type")]
+    InvalidContent,
+    #[msg("The root is no longer a recent root of the tree, refetch the root and retry")]
+    ConcurrentWriteConflict,
+    #[msg("The note log would exceed the tree's max log bytes")]
+    LogBudgetExceeded,
+    #[msg("The metadata exceeds the max metadata entries or length")]
+    MetadataTooLarge,
+    #[msg("The note is empty")]
+    NoteEmpty,
+    #[msg("The delegated append isn't authorized by a matching ed25519 signature of the owner")]
+    InvalidSignature,
+    #[msg("The authorization was already used, sign over the owner's current delegate nonce")]
+    NonceReused,
+    #[msg("The note contains control characters, which the tree's strict text mode rejects")]
+    InvalidCharacters,
+    #[msg("The clock is before the time of the last timestamped note")]
+    NonMonotonicTime,
+    #[msg("The proof doesn't have the expected number of proof nodes")]
+    InvalidProofLength,
+    #[msg("The pinned note account is missing or isn't the note's pinned note pda")]
+    InvalidPinAccount,
+    #[msg("Both notes to swap are at the same index")]
+    SameIndex,
+    #[msg("The merkle tree account's size doesn't match the tree settings, the logged left value is the expected size and right the actual size")]
+    WrongTreeAccountSize,
+    #[msg("The tree already recorded the max number of leaf domains")]
+    TooManyLeafDomains,
+    #[msg("The claim record account isn't the claim record pda of the claimed note")]
+    InvalidClaimAccount,
+    #[msg("The note was already claimed")]
+    AlreadyClaimed,
+    #[msg("The root isn't the current root of the tree, fetch the proof against the current root")]
+    RootNotCurrent,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compute_leaf_rejects_empty_note() {
+        assert_eq!(compute_leaf("", 1), Err(NoteError::NoteEmpty.into()));
+    }
+
+    #[test]
+    fn compute_leaf_rejects_note_over_max_len() {
+        let note = "a".repeat(MAX_NOTE_LEN + 1);
+        assert_eq!(compute_leaf(&note, 1), Err(NoteError::NoteTooLong.into()));
+    }
+
+    #[test]
+    fn compute_leaf_accepts_note_at_max_len() {
+        let note = "a".repeat(MAX_NOTE_LEN);
+        assert!(compute_leaf(&note, 1).is_ok());
+    }
+
+    #[test]
+    fn new_bytes_checks_data_like_compute_leaf() {
+        assert!(matches!(
+            NoteLog::new_bytes(Vec::new(), 1),
+            Err(error) if error == NoteError::NoteEmpty.into()
+        ));
+        assert!(matches!(
+            NoteLog::new_bytes(vec![0; MAX_NOTE_LEN + 1], 1),
+            Err(error) if error == NoteError::NoteTooLong.into()
+        ));
+    }
+
+    // Pairs of variants whose payloads are the same bytes get different leaf nodes
+    #[test]
+    fn leaf_variants_do_not_collide() {
+        let namespaced = NoteLog::new_namespaced("ns".to_string(), "note".to_string(), 1).unwrap();
+        let framed = String::from_utf8(frame_namespaced("ns", "note")).unwrap();
+        assert_ne!(namespaced.leaf_node, compute_leaf(&framed, 1).unwrap());
+
+        let titled = NoteLog::new_titled("ns".to_string(), "note".to_string(), 1).unwrap();
+        assert_ne!(namespaced.leaf_node, titled.leaf_node);
+
+        let bytes = NoteLog::new_bytes(b"note".to_vec(), 1).unwrap();
+        assert_ne!(bytes.leaf_node, compute_leaf("note", 1).unwrap());
+
+        let prev_leaf = compute_leaf("prev", 1).unwrap();
+        let chained = NoteLog::new_chained(prev_leaf, "note".to_string()).unwrap();
+        let prefixed = NoteLog::new_bytes([&prev_leaf[..], b"note"].concat(), 1).unwrap();
+        assert_ne!(chained.leaf_node, prefixed.leaf_node);
+    }
+
+    // Strict text covers the title, namespace and metadata of a note log, not only the note
+    #[test]
+    fn note_log_clean_text_checks_every_text_field() {
+        let clean = NoteLog::new_titled("title".to_string(), "body".to_string(), 1).unwrap();
+        assert!(clean.is_clean_text());
+        let titled = NoteLog::new_titled("ti\x1btle".to_string(), "body".to_string(), 1).unwrap();
+        assert!(!titled.is_clean_text());
+        let namespaced =
+            NoteLog::new_namespaced("n\0s".to_string(), "note".to_string(), 1).unwrap();
+        assert!(!namespaced.is_clean_text());
+        let mut with_metadata = NoteLog::new("note".to_string(), 1).unwrap();
+        with_metadata.metadata = vec![MetadataEntry {
+            key: "key".to_string(),
+            value: "val\x07ue".to_string(),
+        }];
+        assert!(!with_metadata.is_clean_text());
+    }
+
+    // A raw leaf is hashed under its tag, so its bytes can't be a leaf node of another variant
+    #[test]
+    fn raw_leaf_is_tagged() {
+        let text_leaf = compute_leaf("note", 1).unwrap();
+        let raw = NoteLog::new_raw_leaf(String::new(), text_leaf);
+        assert_ne!(raw.leaf_node, text_leaf);
+        assert_eq!(
+            raw.leaf_node,
+            keccak::hashv(&[&[LEAF_TAG_RAW], &text_leaf]).to_bytes()
+        );
+    }
+
+    // A chained note, a reply and a dependent note of the same leaf and note get different leaf nodes
+    #[test]
+    fn linked_leaf_variants_do_not_collide() {
+        let leaf = compute_leaf("prev", 1).unwrap();
+        let chained = NoteLog::new_chained(leaf, "note".to_string()).unwrap();
+        let reply = NoteLog::new_reply(leaf, "note".to_string()).unwrap();
+        let dependent = NoteLog::new_dependent(leaf, "note".to_string()).unwrap();
+        assert_ne!(chained.leaf_node, reply.leaf_node);
+        assert_ne!(chained.leaf_node, dependent.leaf_node);
+        assert_ne!(reply.leaf_node, dependent.leaf_node);
+    }
+
+    // Text leaves are the bare hash of the note, and no text note starts with a tag byte
+    #[test]
+    fn text_leaves_are_untagged() {
+        assert_eq!(
+            compute_leaf("hello world", 1).unwrap(),
+            keccak::hash(b"hello world").to_bytes()
+        );
+        for leaf_tag in [
+            LEAF_TAG_BYTES,
+            LEAF_TAG_NAMESPACED,
+            LEAF_TAG_CHAINED,
+            LEAF_TAG_DELEGATED,
+            LEAF_TAG_TITLED,
+            LEAF_TAG_REPLY,
+            LEAF_TAG_DEPENDENT,
+            LEAF_TAG_RAW,
+        ] {
+            assert!(std::str::from_utf8(&[leaf_tag, b'a']).is_err());
+        }
+    }
+
+    // A config with every field zeroed, as read from a new account of the current layout
+    fn zeroed_config() -> NoteTreeConfig {
+        let data = [
+            &NoteTreeConfig::DISCRIMINATOR[..],
+            &[0; NoteTreeConfig::LEN],
+        ]
+        .concat();
+        NoteTreeConfig::try_from_versioned(&data).unwrap()
+    }
+
+    // The config data of versions 14 to 19, with the fields removed in version 20 set to the given
+    // bytes after leaf_domain, zero padded or truncated to the given length
+    fn legacy_leaf_domain_data(config: &NoteTreeConfig, removed: [u8; 12], len: usize) -> Vec<u8> {
+        let mut bytes = config.try_to_vec().unwrap();
+        let fields_after = 1 + 8 + 4 + MAX_LEAF_DOMAINS * LeafDomain::LEN + 1 + 8;
+        let at = bytes.len() - fields_after;
+        bytes.splice(at..at, removed);
+        bytes.resize(len, 0);
+        [&NoteTreeConfig::DISCRIMINATOR[..], &bytes].concat()
+    }
+
+    // Migrating an older layout keeps its counters rather than resetting them to their defaults
+    #[test]
+    fn try_from_versioned_keeps_counters_of_older_layouts() {
+        let mut config = zeroed_config();
+        config.config_version = 17;
+        config.note_count = 5;
+        config.rating_count = 3;
+        config.total_log_bytes = 700;
+        config.last_timestamp = 42;
+        // Every option set, so the serialized fields take the space LEN counts for them
+        config.gate_mint = Some(Pubkey::new_unique());
+        config.global_config = Some(Pubkey::new_unique());
+        config.compression_program = Some(Pubkey::new_unique());
+        // The version 17 layout ends before leaf_domains, leaf_domain_count and recent_leaf_count
+        let v17_len = NoteTreeConfig::LEN + LEGACY_LEAF_DOMAIN_FIELDS_LEN
+            - MAX_LEAF_DOMAINS * LeafDomain::LEN
+            - 1
+            - 8;
+        let data = legacy_leaf_domain_data(&config, [0; 12], v17_len);
+
+        let (migrated, stored_version) =
+            NoteTreeConfig::try_from_versioned_with_version(&data).unwrap();
+        assert_eq!(stored_version, 17);
+        assert_eq!(migrated.config_version, CONFIG_VERSION);
+        assert_eq!(migrated.note_count, 5);
+        assert_eq!(migrated.rating_count, 3);
+        assert_eq!(migrated.total_log_bytes, 700);
+        assert_eq!(migrated.last_timestamp, 42);
+    }
+
+    // Reading versions 14 to 19 drops prev_leaf_domain and leaf_domain_slot, wherever the unset
+    // options before them put them, and reads the fields after them from their new offset
+    #[test]
+    fn try_from_versioned_drops_removed_leaf_domain_fields() {
+        let mut config = zeroed_config();
+        config.config_version = 19;
+        config.leaf_domain = [1; 4];
+        config.leaf_domains[0].tag = [1; 4];
+        config.leaf_domain_count = 1;
+        config.strict_text = true;
+        config.last_timestamp = 42;
+        config.auto_pin_interval = 3;
+        config.recent_leaf_count = 2;
+        config.recent_leaves[0].leaf_node = [1; 32];
+        // Not valid as strict_text, so the removed fields can't be read in their place
+        let removed = [0xff; 12];
+        let data = legacy_leaf_domain_data(
+            &config,
+            removed,
+            NoteTreeConfig::LEN + LEGACY_LEAF_DOMAIN_FIELDS_LEN,
+        );
+
+        let (migrated, stored_version) =
+            NoteTreeConfig::try_from_versioned_with_version(&data).unwrap();
+        assert_eq!(stored_version, 19);
+        assert_eq!(migrated.leaf_domain, [1; 4]);
+        assert!(migrated.strict_text);
+        assert_eq!(migrated.last_timestamp, 42);
+        assert_eq!(migrated.auto_pin_interval, 3);
+        assert_eq!(migrated.leaf_domain_count, 1);
+        assert_eq!(migrated.leaf_domain_at(0), [1; 4]);
+        // Version 19 already counted its recorded leaves, so the count is kept as stored
+        assert_eq!(migrated.recent_leaf_count, 2);
+
+        // Current layouts of the size of the older ones are rejected
+        config.config_version = CONFIG_VERSION;
+        let data = legacy_leaf_domain_data(
+            &config,
+            removed,
+            NoteTreeConfig::LEN + LEGACY_LEAF_DOMAIN_FIELDS_LEN,
+        );
+        assert!(NoteTreeConfig::try_from_versioned(&data).is_err());
+    }
+
+    #[test]
+    fn leaf_domain_at_keeps_every_rotation() {
+        let mut config = zeroed_config();
+        config.note_count = 2;
+        config.rotate_leaf_domain([1; 4]).unwrap();
+        config.note_count = 5;
+        config.rotate_leaf_domain([2; 4]).unwrap();
+        config.note_count = 7;
+        config.rotate_leaf_domain([0; 4]).unwrap();
+
+        let tags: Vec<_> = (0..8).map(|index| config.leaf_domain_at(index)).collect();
+        assert_eq!(
+            tags,
+            [[0; 4], [0; 4], [1; 4], [1; 4], [1; 4], [2; 4], [2; 4], [0; 4]]
+        );
+        assert_eq!(config.leaf_domain, [0; 4]);
+    }
+
+    #[test]
+    fn rotate_leaf_domain_replaces_tag_without_leaves() {
+        let mut config = zeroed_config();
+        config.rotate_leaf_domain([1; 4]).unwrap();
+        config.rotate_leaf_domain([2; 4]).unwrap();
+        assert_eq!(config.leaf_domain_count, 1);
+        assert_eq!(config.leaf_domain_at(0), [2; 4]);
+
+        for note_count in 1..MAX_LEAF_DOMAINS as u64 {
+            config.note_count = note_count;
+            config.rotate_leaf_domain([3; 4]).unwrap();
+        }
+        config.note_count += 1;
+        assert_eq!(
+            config.rotate_leaf_domain([4; 4]),
+            Err(NoteError::TooManyLeafDomains.into())
+        );
+    }
+
+    #[test]
+    fn note_leaf_uses_tag_of_index() {
+        let mut config = zeroed_config();
+        config.hash_iterations = 1;
+        config.note_count = 1;
+        config.rotate_leaf_domain([1, 2, 3, 4]).unwrap();
+        let leaf_node = compute_leaf("note", 1).unwrap();
+        assert_eq!(config.note_leaf("note", 0).unwrap(), leaf_node);
+        assert_eq!(
+            config.note_leaf("note", 1).unwrap(),
+            domain_leaf([1, 2, 3, 4], leaf_node)
+        );
+    }
+
+    #[test]
+    fn required_tree_space_adds_canopy_nodes() {
+        let space = TreeSize::new(14, 64).unwrap().space as usize;
+        assert_eq!(required_tree_space(14, 64, 0).unwrap(), space);
+        assert_eq!(required_tree_space(14, 64, 3).unwrap(), space + 32 * 14);
+    }
+
+    #[test]
+    fn required_tree_space_rejects_out_of_range_canopy_depth() {
+        for canopy_depth in [usize::BITS - 1, usize::BITS, u32::MAX] {
+            assert_eq!(
+                required_tree_space(14, 64, canopy_depth),
+                Err(NoteError::ArithmeticOverflow.into())
+            );
+        }
+    }
+
+    // now is the (0, 0) placeholder in unit tests, so the note is stamped 0
+    #[test]
+    fn new_timestamped_stamps_current_time() {
+        let note_log = NoteLog::new_timestamped("audit entry".to_string(), 1, 0).unwrap();
+        assert_eq!(note_log.created_at, now().unwrap().0);
+        assert_eq!(note_log.leaf_node, compute_leaf("audit entry", 1).unwrap());
+    }
+
+    #[test]
+    fn new_timestamped_rejects_time_before_last_timestamp() {
+        let last_timestamp = now().unwrap().0 + 1;
+        assert!(matches!(
+            NoteLog::new_timestamped("audit entry".to_string(), 1, last_timestamp),
+            Err(error) if error == NoteError::NonMonotonicTime.into()
+        ));
+    }
+
+    // Recent leaves are filled by the recorded count, so a zero leaf node is kept and unused entries aren't
+    #[test]
+    fn recent_leaves_keep_zero_leaf_nodes() {
+        let mut config = zeroed_config();
+        assert!(config.recent_leaves_newest_first().is_empty());
+
+        config.record_recent_leaf(0, [0; 32]);
+        config.record_recent_leaf(1, [1; 32]);
+        let indices = |config: &NoteTreeConfig| {
+            config
+                .recent_leaves_newest_first()
+                .iter()
+                .map(|recent_leaf| recent_leaf.index)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(indices(&config), [1, 0]);
+        assert_eq!(config.recent_leaves_newest_first()[1].leaf_node, [0; 32]);
+
+        // Once the ring wraps, only the newest RECENT_LEAVES_LEN leaves are kept
+        for index in 2..RECENT_LEAVES_LEN as u32 + 2 {
+            config.record_recent_leaf(index, [index as u8; 32]);
+        }
+        assert_eq!(
+            indices(&config),
+            (2..RECENT_LEAVES_LEN as u32 + 2).rev().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn wrap_note_log_reports_skipped_logs() {
+        // A noop account that isn't deployed, as on validators without the noop program
+        let (key, owner) = (Pubkey::new_unique(), Pubkey::default());
+        let (mut lamports, mut data) = (0, vec![]);
+        let log_wrapper = AccountInfo::new(
+            &key,
+            false,
+            false,
+            &mut lamports,
+            &mut data,
+            &owner,
+            false,
+            0,
+        );
+        let logged = wrap_note_log(vec![1, 2, 3], &log_wrapper);
+        // The note log isn't logged, so callers don't count it towards the log budget
+        #[cfg(feature = "skip-missing-noop")]
+        assert_eq!(logged, Ok(false));
+        #[cfg(not(feature = "skip-missing-noop"))]
+        assert_eq!(logged, Err(NoteError::NoopUnavailable.into()));
     }
 }
@@ -1,5 +1,5 @@
 import * as anchor from "@coral-xyz/anchor"
-import { Program } from "@coral-xyz/anchor"
+import { Program, BorshAccountsCoder } from "@coral-xyz/anchor"
 import { AnchorCompressedNotes } from "../target/types/anchor_compressed_notes"
 import {
   Keypair,
@@ -8,16 +8,57 @@ import {
   sendAndConfirmTransaction,
   Connection,
   clusterApiUrl,
+  SystemProgram,
+  BPF_LOADER_UPGRADEABLE_PROGRAM_ID,
+  Ed25519Program,
+  SYSVAR_INSTRUCTIONS_PUBKEY,
+  LAMPORTS_PER_SOL,
+  ComputeBudgetProgram,
 } from "@solana/web3.js"
 import {
   ValidDepthSizePair,
+  ConcurrentMerkleTreeAccount,
+  MerkleTree,
   createAllocTreeIx,
+  createAppendIx,
+  createInitEmptyMerkleTreeIx,
+  createTransferAuthorityIx,
+  getConcurrentMerkleTreeAccountSize,
   SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
   SPL_NOOP_PROGRAM_ID,
 } from "@solana/spl-account-compression"
-import { getNoteLog } from "../utils/utils"
+import { deserialize } from "borsh"
+import {
+  APPEND_CU_ESTIMATE,
+  COMPRESSED_APPEND_CU_ESTIMATE,
+  CONTENT_TYPES,
+  DEFAULT_TREE_CONFIG_INIT_ARGS,
+  LEAF_TAG_BYTES,
+  LEAF_TAG_NAMESPACED,
+  LEAF_TAG_RAW,
+  LEAF_TAG_TITLED,
+  NOTE_LOG_DISCRIMINATOR,
+  NoteLog,
+  NoteLogBorshSchema,
+  STAGED_APPEND_CU_ESTIMATE,
+  batchAppendCuEstimate,
+  batchRoot,
+  computeUnitsConsumed,
+  decodeLogCompression,
+  delegatedMessage,
+  domainLeaf,
+  frameNamespaced,
+  getNoteLog,
+  getReturnData,
+  leafHash,
+  noteLogLeaf,
+  swapCuEstimate,
+  textLeaf,
+  updateCuEstimate,
+} from "../utils/utils"
 import { assert } from "chai"
 import { keccak256 } from "js-sha3"
+import * as fc from "fast-check"
 
 describe("anchor-compressed-notes", () => {
   const provider = anchor.AnchorProvider.env()
@@ -39,13 +80,81 @@ describe("anchor-compressed-notes", () => {
     program.programId
   )
 
-  it("Create Note Tree", async () => {
-    const maxDepthSizePair: ValidDepthSizePair = {
-      maxDepth: 3,
-      maxBufferSize: 8,
+  // Derive the PDA for the config account of the merkle tree
+  const [config] = PublicKey.findProgramAddressSync(
+    [Buffer.from("config"), merkleTree.publicKey.toBuffer()],
+    program.programId
+  )
+
+  // Derive the PDA for the allowlist account of the merkle tree
+  const [allowlist] = PublicKey.findProgramAddressSync(
+    [Buffer.from("allowlist"), merkleTree.publicKey.toBuffer()],
+    program.programId
+  )
+
+  // Derive the PDA for the author stats account of the wallet in the merkle tree
+  const [authorStats] = PublicKey.findProgramAddressSync(
+    [
+      Buffer.from("author"),
+      merkleTree.publicKey.toBuffer(),
+      wallet.publicKey.toBuffer(),
+    ],
+    program.programId
+  )
+
+  // Derive the PDA for the global config of the wallet, also used to lower the min depth of the test trees
+  const [globalConfig] = PublicKey.findProgramAddressSync(
+    [Buffer.from("global_config"), wallet.publicKey.toBuffer()],
+    program.programId
+  )
+  const globalConfigRemainingAccount = {
+    pubkey: globalConfig,
+    isSigner: false,
+    isWritable: false,
+  }
+
+  // Rebuild the tree off-chain from the appended leaves, to get the current root and the proof accounts for a leaf
+  const getProof = async (index: number) => {
+    const offChainTree = MerkleTree.sparseMerkleTreeFromLeaves(
+      appendedLeaves,
+      maxDepthSizePair.maxDepth
+    )
+    const { proof } = offChainTree.getProof(index)
+    const treeAccount = await ConcurrentMerkleTreeAccount.fromAccountAddress(
+      connection,
+      merkleTree.publicKey
+    )
+    return {
+      root: Array.from(treeAccount.getCurrentRoot()),
+      proofAccounts: proof.map((node) => ({
+        pubkey: new PublicKey(node),
+        isSigner: false,
+        isWritable: false,
+      })),
     }
+  }
+
+  const maxDepthSizePair: ValidDepthSizePair = {
+    maxDepth: 14,
+    maxBufferSize: 64,
+  }
+
+  // Leaf nodes appended to the tree by the tests, used to build proofs off-chain
+  const appendedLeaves: Buffer[] = []
+
+  it("Create Note Tree", async () => {
     const canopyDepth = 0
 
+    // The test trees are shallower than the default min depth, so lower it with the global config
+    await program.methods
+      .initGlobalConfig()
+      .accounts({ globalConfig: globalConfig })
+      .rpc()
+    await program.methods
+      .setMinDepth(3)
+      .accounts({ globalConfig: globalConfig })
+      .rpc()
+
     // instruction to create new account with required space for tree
     const allocTreeIx = await createAllocTreeIx(
       connection,
@@ -57,23 +166,47 @@ describe("anchor-compressed-notes", () => {
 
     // instruction to initialize the tree through the Note program
     const ix = await program.methods
-      .createNoteTree(maxDepthSizePair.maxDepth, maxDepthSizePair.maxBufferSize)
+      .createNoteTree({
+        ...DEFAULT_TREE_CONFIG_INIT_ARGS,
+        maxDepth: maxDepthSizePair.maxDepth,
+        maxBufferSize: maxDepthSizePair.maxBufferSize,
+        canopyDepth: canopyDepth,
+      })
       .accounts({
+        config: config,
         merkleTree: merkleTree.publicKey,
         treeAuthority: treeAuthority,
         logWrapper: SPL_NOOP_PROGRAM_ID,
         compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
       })
+      .remainingAccounts([globalConfigRemainingAccount])
       .instruction()
 
     const tx = new Transaction().add(allocTreeIx, ix)
 
+    // Listen for the TreeCreated event emitted by the instruction
+    let treeCreated = null
+    const listener = program.addEventListener("TreeCreated", (event) => {
+      treeCreated = event
+    })
+
     const txSignature = await sendAndConfirmTransaction(connection, tx, [
       wallet.payer,
       merkleTree,
     ])
 
     console.log("txSignature", txSignature)
+
+    // Wait for the event to arrive through the logs subscription
+    for (let i = 0; i < 20 && treeCreated === null; i++) {
+      await new Promise((resolve) => setTimeout(resolve, 100))
+    }
+    await program.removeEventListener(listener)
+
+    assert(treeCreated.tree.equals(merkleTree.publicKey))
+    assert(treeCreated.authority.equals(wallet.publicKey))
+    assert(treeCreated.maxDepth === maxDepthSizePair.maxDepth)
+    assert(treeCreated.maxBufferSize === maxDepthSizePair.maxBufferSize)
   })
 
   it("Append Leaf", async () => {
@@ -82,40 +215,3492 @@ describe("anchor-compressed-notes", () => {
     const txSignature = await program.methods
       .appendNote(note)
       .accounts({
+        config: config,
         merkleTree: merkleTree.publicKey,
         treeAuthority: treeAuthority,
         logWrapper: SPL_NOOP_PROGRAM_ID,
         compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+        allowlist: allowlist,
+        authorStats: authorStats,
       })
       .rpc()
 
     const noteLog = await getNoteLog(connection, txSignature)
-    const hash = keccak256(note)
-    assert(hash === Buffer.from(noteLog.leafNode).toString("hex"))
+    // The leaf node matches the one reconstructed from the note log, the same way clients do
+    assert(noteLogLeaf(noteLog, 1).equals(Buffer.from(noteLog.leafNode)))
+    appendedLeaves.push(Buffer.from(noteLog.leafNode))
+    assert(keccak256(note) === Buffer.from(noteLog.leafNode).toString("hex"))
     assert(note === noteLog.note)
+    assert(noteLog.priority === 0)
+    assert(noteLog.version === 13)
+    assert(noteLog.value.isZero())
+    assert(noteLog.genesis === 0)
+    assert(CONTENT_TYPES[noteLog.contentType] === "text")
+
+    // The returned note log is the same as the logged note log
+    const returnData = await getReturnData(
+      connection,
+      txSignature,
+      program.programId
+    )
+    const returnedNoteLog = deserialize(
+      NoteLogBorshSchema,
+      NoteLog,
+      returnData
+    )
+    assert(returnedNoteLog.note === noteLog.note)
+    assert(
+      Buffer.from(returnedNoteLog.leafNode).equals(
+        Buffer.from(noteLog.leafNode)
+      )
+    )
 
     console.log(note)
   })
 
   it("Append Another Leaf, Max Note Size", async () => {
     // Size of note is limited by max transaction size of 1232 bytes, minus additional data required for the instruction
-    const note = "0".repeat(917)
+    // Each account in the instruction takes 32 bytes, so the config, allowlist, author stats and system program accounts reduce it from 917
+    const note = "0".repeat(789)
 
     const txSignature = await program.methods
       .appendNote(note)
       .accounts({
+        config: config,
         merkleTree: merkleTree.publicKey,
         treeAuthority: treeAuthority,
         logWrapper: SPL_NOOP_PROGRAM_ID,
         compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+        allowlist: allowlist,
+        authorStats: authorStats,
       })
       .rpc()
 
     const noteLog = await getNoteLog(connection, txSignature)
-    const hash = keccak256(note)
-    assert(hash === Buffer.from(noteLog.leafNode).toString("hex"))
+    assert(noteLogLeaf(noteLog, 1).equals(Buffer.from(noteLog.leafNode)))
+    appendedLeaves.push(Buffer.from(noteLog.leafNode))
+    assert(keccak256(note) === Buffer.from(noteLog.leafNode).toString("hex"))
     assert(note === noteLog.note)
 
     console.log(note)
   })
+
+  it("Append Compressed Note", async () => {
+    const note = "compress me ".repeat(50)
+
+    const txSignature = await program.methods
+      .appendNoteCompressed(note)
+      .accounts({
+        config: config,
+        merkleTree: merkleTree.publicKey,
+        treeAuthority: treeAuthority,
+        logWrapper: SPL_NOOP_PROGRAM_ID,
+        compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+        allowlist: allowlist,
+        authorStats: authorStats,
+      })
+      .rpc()
+
+    // The note log is inflated by getNoteLog, and the leaf is the hash of the uncompressed note
+    const noteLog = await getNoteLog(connection, txSignature)
+    assert(noteLogLeaf(noteLog, 1).equals(Buffer.from(noteLog.leafNode)))
+    appendedLeaves.push(Buffer.from(noteLog.leafNode))
+    assert(keccak256(note) === Buffer.from(noteLog.leafNode).toString("hex"))
+    assert(note === noteLog.note)
+  })
+
+  it("Append Note With Priority", async () => {
+    const note = "important note"
+    const priority = 255
+
+    const txSignature = await program.methods
+      .appendNoteWithPriority(note, priority)
+      .accounts({
+        config: config,
+        merkleTree: merkleTree.publicKey,
+        treeAuthority: treeAuthority,
+        logWrapper: SPL_NOOP_PROGRAM_ID,
+        compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+        allowlist: allowlist,
+        authorStats: authorStats,
+      })
+      .rpc()
+
+    // The priority round trips through the note log, and isn't part of the leaf node
+    const noteLog = await getNoteLog(connection, txSignature)
+    assert(noteLogLeaf(noteLog, 1).equals(Buffer.from(noteLog.leafNode)))
+    appendedLeaves.push(Buffer.from(noteLog.leafNode))
+    assert(keccak256(note) === Buffer.from(noteLog.leafNode).toString("hex"))
+    assert(note === noteLog.note)
+    assert(noteLog.priority === priority)
+  })
+
+  it("Append Bytes", async () => {
+    // Raw bytes that are not valid UTF-8
+    const data = Buffer.from([0xff, 0xfe, 0x00, 0x01])
+
+    const txSignature = await program.methods
+      .appendBytes(data)
+      .accounts({
+        config: config,
+        merkleTree: merkleTree.publicKey,
+        treeAuthority: treeAuthority,
+        logWrapper: SPL_NOOP_PROGRAM_ID,
+        compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+        allowlist: allowlist,
+        authorStats: authorStats,
+      })
+      .rpc()
+
+    const noteLog = await getNoteLog(connection, txSignature)
+    assert(noteLogLeaf(noteLog, 1).equals(Buffer.from(noteLog.leafNode)))
+    appendedLeaves.push(Buffer.from(noteLog.leafNode))
+    assert(leafHash(LEAF_TAG_BYTES, data).equals(Buffer.from(noteLog.leafNode)))
+    assert(data.equals(Buffer.from(noteLog.data)))
+    assert(noteLog.note === "")
+  })
+
+  it("Append Rated Note", async () => {
+    const note = "rated note"
+    const value = -3
+
+    const before = await program.account.noteTreeConfig.fetch(config)
+    const txSignature = await program.methods
+      .appendRatedNote(note, new anchor.BN(value))
+      .accounts({
+        config: config,
+        merkleTree: merkleTree.publicKey,
+        treeAuthority: treeAuthority,
+        logWrapper: SPL_NOOP_PROGRAM_ID,
+        compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+        allowlist: allowlist,
+        authorStats: authorStats,
+      })
+      .rpc()
+
+    // The value round trips through the note log, and isn't part of the leaf node
+    const noteLog = await getNoteLog(connection, txSignature)
+    assert(noteLogLeaf(noteLog, 1).equals(Buffer.from(noteLog.leafNode)))
+    appendedLeaves.push(Buffer.from(noteLog.leafNode))
+    assert(keccak256(note) === Buffer.from(noteLog.leafNode).toString("hex"))
+    assert(noteLog.value.fromTwos(64).toNumber() === value)
+
+    // The value is added to the running aggregates in the config
+    const after = await program.account.noteTreeConfig.fetch(config)
+    assert(after.ratingSum.toNumber() === before.ratingSum.toNumber() + value)
+    assert(after.ratingCount.toNumber() === before.ratingCount.toNumber() + 1)
+  })
+
+  it("Pin Note", async () => {
+    const note = "hello world"
+    const index = 0
+
+    // Derive the PDA for the pinned note, seeded by the merkle tree and the leaf index
+    const [pinnedNote] = PublicKey.findProgramAddressSync(
+      [
+        merkleTree.publicKey.toBuffer(),
+        new anchor.BN(index).toArrayLike(Buffer, "le", 4),
+      ],
+      program.programId
+    )
+
+    await program.methods
+      .pinNote(index, note)
+      .accounts({
+        config: config,
+        pinnedNote: pinnedNote,
+        merkleTree: merkleTree.publicKey,
+      })
+      .rpc()
+
+    const pinnedNoteAccount = await program.account.note.fetch(pinnedNote)
+    assert(pinnedNoteAccount.author.equals(wallet.publicKey))
+    assert(pinnedNoteAccount.index === index)
+    assert(
+      keccak256(note) ===
+        Buffer.from(pinnedNoteAccount.leafNode).toString("hex")
+    )
+    assert(note === pinnedNoteAccount.note)
+  })
+
+  it("Get Config", async () => {
+    // Simulate the read-only instruction and decode the returned config
+    const { raw } = await program.methods
+      .getConfig()
+      .accounts({ config: config })
+      .simulate()
+
+    const returnLog = raw.find((log) => log.startsWith("Program return:"))
+    const returnData = Buffer.from(returnLog.split(" ")[3], "base64")
+    // The return data has no discriminator, so prepend it to reuse the account coder
+    const returnedConfig = program.coder.accounts.decode(
+      "NoteTreeConfig",
+      Buffer.concat([
+        BorshAccountsCoder.accountDiscriminator("NoteTreeConfig"),
+        returnData,
+      ])
+    )
+
+    const configAccount = await program.account.noteTreeConfig.fetch(config)
+    assert(returnedConfig.merkleTree.equals(merkleTree.publicKey))
+    assert(returnedConfig.authority.equals(configAccount.authority))
+    assert(returnedConfig.maxDepth === configAccount.maxDepth)
+    assert(returnedConfig.hashIterations === 1)
+  })
+
+  it("Supported Tree Sizes", async () => {
+    const { raw } = await program.methods.supportedTreeSizes().simulate()
+
+    // Decode the returned vec of (max_depth u32, max_buffer_size u32, space u64) entries
+    const returnLog = raw.find((log) => log.startsWith("Program return:"))
+    const returnData = Buffer.from(returnLog.split(" ")[3], "base64")
+    const treeSizes = []
+    for (let i = 0; i < returnData.readUInt32LE(0); i++) {
+      const offset = 4 + i * 16
+      treeSizes.push({
+        maxDepth: returnData.readUInt32LE(offset),
+        maxBufferSize: returnData.readUInt32LE(offset + 4),
+        space: Number(returnData.readBigUInt64LE(offset + 8)),
+      })
+    }
+
+    const testTreeSize = treeSizes.find(
+      (size) =>
+        size.maxDepth === maxDepthSizePair.maxDepth &&
+        size.maxBufferSize === maxDepthSizePair.maxBufferSize
+    )
+    assert(
+      testTreeSize.space ===
+        getConcurrentMerkleTreeAccountSize(
+          maxDepthSizePair.maxDepth,
+          maxDepthSizePair.maxBufferSize
+        )
+    )
+  })
+
+  it("Healthcheck", async () => {
+    const healthcheck = async (authority: PublicKey) => {
+      const { raw } = await program.methods
+        .healthcheck()
+        .accounts({
+          config: config,
+          authority: authority,
+          treeAuthority: treeAuthority,
+          merkleTree: merkleTree.publicKey,
+          logWrapper: SPL_NOOP_PROGRAM_ID,
+          compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+        })
+        .simulate()
+      const returnLog = raw.find((log) => log.startsWith("Program return:"))
+      return Buffer.from(returnLog.split(" ")[3], "base64")[0]
+    }
+
+    // Every check passes for the test tree
+    const allChecks = 0b111111
+    assert((await healthcheck(wallet.publicKey)) === allChecks)
+
+    // Only the authority check fails for another authority
+    const authorityCheck = 1 << 1
+    assert(
+      (await healthcheck(Keypair.generate().publicKey)) ===
+        (allChecks & ~authorityCheck)
+    )
+  })
+
+  it("Migrate Config", async () => {
+    // Migrating a config that already has the current layout leaves it unchanged
+    const before = await program.account.noteTreeConfig.fetch(config)
+
+    await program.methods
+      .migrateConfig()
+      .accounts({
+        authority: wallet.publicKey,
+        config: config,
+        merkleTree: merkleTree.publicKey,
+      })
+      .rpc()
+
+    const after = await program.account.noteTreeConfig.fetch(config)
+    assert(after.configVersion === before.configVersion)
+    assert(after.authority.equals(before.authority))
+    // The note count kept by appends isn't reset or recounted, and matches the tree
+    assert(after.noteCount.eq(before.noteCount))
+    assert(after.noteCount.toNumber() === appendedLeaves.length)
+  })
+
+  it("Verify And Pin Note", async () => {
+    const note = "verified note"
+    const index = appendedLeaves.length
+
+    await program.methods
+      .appendNote(note)
+      .accounts({
+        config: config,
+        merkleTree: merkleTree.publicKey,
+        treeAuthority: treeAuthority,
+        logWrapper: SPL_NOOP_PROGRAM_ID,
+        compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+        allowlist: allowlist,
+        authorStats: authorStats,
+      })
+      .rpc()
+    appendedLeaves.push(Buffer.from(keccak256.digest(note)))
+
+    const { root, proofAccounts } = await getProof(index)
+
+    // Verified pins are seeded with a "verified" prefix, apart from notes pinned without verification
+    const [pinnedNote] = PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("verified"),
+        merkleTree.publicKey.toBuffer(),
+        new anchor.BN(index).toArrayLike(Buffer, "le", 4),
+      ],
+      program.programId
+    )
+
+    // An unverified pin can't take the verified pin's address, even before it's pinned
+    try {
+      await program.methods
+        .pinNote(index, "not the note")
+        .accounts({
+          config: config,
+          pinnedNote: pinnedNote,
+          merkleTree: merkleTree.publicKey,
+        })
+        .rpc()
+      assert.fail("unverified pin at the verified address should fail")
+    } catch (error) {
+      assert(error.error.errorCode.code === "ConstraintSeeds")
+    }
+
+    await program.methods
+      .verifyAndPin(index, root, note)
+      .accounts({
+        config: config,
+        pinnedNote: pinnedNote,
+        merkleTree: merkleTree.publicKey,
+        compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+      })
+      .remainingAccounts(proofAccounts)
+      .rpc()
+
+    const pinnedNoteAccount = await program.account.note.fetch(pinnedNote)
+    assert(note === pinnedNoteAccount.note)
+    assert(pinnedNoteAccount.index === index)
+
+    // Nor does an unverified pin of the same index replace it
+    const [unverifiedNote] = PublicKey.findProgramAddressSync(
+      [
+        merkleTree.publicKey.toBuffer(),
+        new anchor.BN(index).toArrayLike(Buffer, "le", 4),
+      ],
+      program.programId
+    )
+    await program.methods
+      .pinNote(index, "not the note")
+      .accounts({
+        config: config,
+        pinnedNote: unverifiedNote,
+        merkleTree: merkleTree.publicKey,
+      })
+      .rpc()
+    const verified = await program.account.note.fetch(pinnedNote)
+    assert(verified.note === note)
+  })
+
+  // Known answer tests for leaf and seed construction, the reference for client implementations.
+  // Every integer folded into a seed or leaf hash is encoded as little-endian.
+  it("Leaf Known Answers", async () => {
+    // Text note: keccak256 over the UTF-8 bytes of the note, without a tag byte
+    const textKnownLeaf =
+      "47173285a8d7341e5e972fc677286384f802f8ef42a5ec5f03bbfa254cb01fad"
+    assert(keccak256("hello world") === textKnownLeaf)
+    assert(textLeaf("hello world").toString("hex") === textKnownLeaf)
+    assert(appendedLeaves[0].toString("hex") === textKnownLeaf)
+
+    // Raw bytes note: keccak256 over the bytes tag byte 0xf8 followed by the raw bytes
+    const bytesKnownLeaf =
+      "d8c434ada79be8d4180f6267a68dfa0e6e983d23d9e68540caf8c02695a5ee30"
+    assert(
+      keccak256(Buffer.from([0xf8, 0xff, 0xfe, 0x00, 0x01])) === bytesKnownLeaf
+    )
+    assert(appendedLeaves[4].toString("hex") === bytesKnownLeaf)
+
+    // Hash iterations: each further iteration is keccak256 over the previous 32 byte hash
+    const twoIterationLeaf =
+      "04cd40a3ea7972c6f30142d02fd5ddcac438fe6c59e634cecb827fbee9d385fc"
+    assert(keccak256(keccak256.digest("hello world")) === twoIterationLeaf)
+    assert(textLeaf("hello world", 2).toString("hex") === twoIterationLeaf)
+
+    // Leaf index in pinned note seeds: u32 little-endian
+    assert(
+      new anchor.BN(258)
+        .toArrayLike(Buffer, "le", 4)
+        .equals(Buffer.from([0x02, 0x01, 0x00, 0x00]))
+    )
+  })
+
+  it("Manage Allowlist", async () => {
+    const author = Keypair.generate().publicKey
+
+    await program.methods
+      .addAuthor(author)
+      .accounts({
+        authority: wallet.publicKey,
+        config: config,
+        allowlist: allowlist,
+        merkleTree: merkleTree.publicKey,
+      })
+      .rpc()
+
+    let allowlistAccount = await program.account.allowlist.fetch(allowlist)
+    assert(allowlistAccount.authors.some((key) => key.equals(author)))
+
+    await program.methods
+      .removeAuthor(author)
+      .accounts({
+        authority: wallet.publicKey,
+        config: config,
+        allowlist: allowlist,
+        merkleTree: merkleTree.publicKey,
+      })
+      .rpc()
+
+    allowlistAccount = await program.account.allowlist.fetch(allowlist)
+    assert(!allowlistAccount.authors.some((key) => key.equals(author)))
+  })
+
+  it("Append Staged Note", async () => {
+    // A note larger than fits in a single transaction
+    const note = "staged ".repeat(300)
+    const chunkSize = 800
+
+    const [staging] = PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("staging"),
+        merkleTree.publicKey.toBuffer(),
+        wallet.publicKey.toBuffer(),
+      ],
+      program.programId
+    )
+
+    await program.methods
+      .initStaging(note.length)
+      .accounts({
+        config: config,
+        staging: staging,
+        merkleTree: merkleTree.publicKey,
+      })
+      .rpc()
+
+    // Write the note in order, one chunk per transaction
+    const bytes = Buffer.from(note)
+    for (let offset = 0; offset < bytes.length; offset += chunkSize) {
+      await program.methods
+        .writeStaging(bytes.subarray(offset, offset + chunkSize))
+        .accounts({ staging: staging, merkleTree: merkleTree.publicKey })
+        .rpc()
+    }
+
+    const txSignature = await program.methods
+      .finalizeStagedNote()
+      .accounts({
+        noteAccounts: {
+          config: config,
+          merkleTree: merkleTree.publicKey,
+          treeAuthority: treeAuthority,
+          logWrapper: SPL_NOOP_PROGRAM_ID,
+          compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+          allowlist: allowlist,
+          authorStats: authorStats,
+        },
+        staging: staging,
+      })
+      .rpc()
+
+    const noteLog = await getNoteLog(connection, txSignature)
+    assert(noteLogLeaf(noteLog, 1).equals(Buffer.from(noteLog.leafNode)))
+    appendedLeaves.push(Buffer.from(noteLog.leafNode))
+    assert(keccak256(note) === Buffer.from(noteLog.leafNode).toString("hex"))
+    assert(note === noteLog.note)
+
+    // The note log exceeds the 1024 byte return data limit, so it is returned with the note truncated,
+    // flagged by the empty note, instead of failing the append
+    const returnData = await getReturnData(
+      connection,
+      txSignature,
+      program.programId
+    )
+    assert(returnData.length <= 1024)
+    const returnedNoteLog = deserialize(NoteLogBorshSchema, NoteLog, returnData)
+    assert(returnedNoteLog.note === "")
+    assert(
+      Buffer.from(returnedNoteLog.leafNode).equals(Buffer.from(noteLog.leafNode))
+    )
+
+    // The staging account is closed once the note is appended
+    assert((await connection.getAccountInfo(staging)) === null)
+  })
+
+  it("Author Stats", async () => {
+    // Every note appended by the wallet is counted in its author stats
+    const authorStatsAccount = await program.account.authorStats.fetch(
+      authorStats
+    )
+    assert(authorStatsAccount.author.equals(wallet.publicKey))
+    assert(authorStatsAccount.count.toNumber() === appendedLeaves.length)
+  })
+
+  it("Unpin Own Note", async () => {
+    // The note pinned at index 0 by the wallet
+    const index = 0
+    const [pinnedNote] = PublicKey.findProgramAddressSync(
+      [
+        merkleTree.publicKey.toBuffer(),
+        new anchor.BN(index).toArrayLike(Buffer, "le", 4),
+      ],
+      program.programId
+    )
+
+    // Another signer can't close a note pinned by the wallet
+    const otherAuthor = Keypair.generate()
+    try {
+      await program.methods
+        .unpinOwnNote(index)
+        .accounts({
+          author: otherAuthor.publicKey,
+          pinnedNote: pinnedNote,
+          merkleTree: merkleTree.publicKey,
+        })
+        .signers([otherAuthor])
+        .rpc()
+      assert.fail("unpin by another signer should fail")
+    } catch (error) {
+      assert(error.error.errorCode.code === "Unauthorized")
+    }
+
+    // The author of the pin can close it
+    await program.methods
+      .unpinOwnNote(index)
+      .accounts({
+        author: wallet.publicKey,
+        pinnedNote: pinnedNote,
+        merkleTree: merkleTree.publicKey,
+      })
+      .rpc()
+    assert((await connection.getAccountInfo(pinnedNote)) === null)
+  })
+
+  it("Verify Note", async () => {
+    const index = 0
+    const { root, proofAccounts } = await getProof(index)
+
+    await program.methods
+      .verifyNote(index, root, "hello world", false)
+      .accounts({
+        config: config,
+        merkleTree: merkleTree.publicKey,
+        compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+      })
+      .remainingAccounts(proofAccounts)
+      .rpc()
+
+    // The test tree has no canopy, so verifying with only the canopy isn't possible
+    try {
+      await program.methods
+        .verifyNote(index, root, "hello world", true)
+        .accounts({
+          config: config,
+          merkleTree: merkleTree.publicKey,
+          compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+        })
+        .rpc()
+      assert.fail("canopy only verification should fail")
+    } catch (error) {
+      assert(error.error.errorCode.code === "CanopyTooShallow")
+    }
+
+    // A depth 3 tree with a canopy of depth 3 caches every proof node, so the canopy alone verifies
+    const canopyTree = Keypair.generate()
+    const [canopyConfig] = PublicKey.findProgramAddressSync(
+      [Buffer.from("config"), canopyTree.publicKey.toBuffer()],
+      program.programId
+    )
+    const [canopyTreeAuthority] = PublicKey.findProgramAddressSync(
+      [canopyTree.publicKey.toBuffer()],
+      program.programId
+    )
+    const allocTreeIx = await createAllocTreeIx(
+      connection,
+      canopyTree.publicKey,
+      wallet.publicKey,
+      { maxDepth: 3, maxBufferSize: 8 },
+      3
+    )
+    await program.methods
+      .createNoteTree({
+        ...DEFAULT_TREE_CONFIG_INIT_ARGS,
+        maxDepth: 3,
+        maxBufferSize: 8,
+        canopyDepth: 3,
+      })
+      .accounts({
+        config: canopyConfig,
+        merkleTree: canopyTree.publicKey,
+        treeAuthority: canopyTreeAuthority,
+        logWrapper: SPL_NOOP_PROGRAM_ID,
+        compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+      })
+      .remainingAccounts([globalConfigRemainingAccount])
+      .preInstructions([allocTreeIx])
+      .signers([canopyTree])
+      .rpc()
+    const canopyNotes = ["canopy note 0", "canopy note 1"]
+    for (const note of canopyNotes) {
+      await program.methods
+        .appendNote(note)
+        .accounts({
+          config: canopyConfig,
+          merkleTree: canopyTree.publicKey,
+          treeAuthority: canopyTreeAuthority,
+          logWrapper: SPL_NOOP_PROGRAM_ID,
+          compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+          allowlist: PublicKey.findProgramAddressSync(
+            [Buffer.from("allowlist"), canopyTree.publicKey.toBuffer()],
+            program.programId
+          )[0],
+          authorStats: PublicKey.findProgramAddressSync(
+            [
+              Buffer.from("author"),
+              canopyTree.publicKey.toBuffer(),
+              wallet.publicKey.toBuffer(),
+            ],
+            program.programId
+          )[0],
+        })
+        .rpc()
+    }
+    const canopyRoot = MerkleTree.sparseMerkleTreeFromLeaves(
+      canopyNotes.map((note) => textLeaf(note)),
+      3
+    ).root
+    await program.methods
+      .verifyNote(1, Array.from(canopyRoot), "canopy note 1", true)
+      .accounts({
+        config: canopyConfig,
+        merkleTree: canopyTree.publicKey,
+        compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+      })
+      .rpc()
+  })
+
+  it("Claim For Notes", async () => {
+    const rewardPerNote = 1000
+    const [rewardVault] = PublicKey.findProgramAddressSync(
+      [Buffer.from("reward_vault"), merkleTree.publicKey.toBuffer()],
+      program.programId
+    )
+
+    await program.methods
+      .setRewardPerNote(new anchor.BN(rewardPerNote))
+      .accounts({
+        config: config,
+        merkleTree: merkleTree.publicKey,
+      })
+      .rpc()
+
+    // Fund the reward vault with enough lamports to stay rent exempt after paying out
+    const fundTx = new Transaction().add(
+      SystemProgram.transfer({
+        fromPubkey: wallet.publicKey,
+        toPubkey: rewardVault,
+        lamports: LAMPORTS_PER_SOL,
+      })
+    )
+    await sendAndConfirmTransaction(connection, fundTx, [wallet.payer])
+
+    // Only notes committing to their author can be claimed, so the wallet appends a delegated note
+    // authorizing itself as the delegate
+    const note = "claimable note"
+    const [delegateNonce] = PublicKey.findProgramAddressSync(
+      [Buffer.from("delegate_nonce"), wallet.publicKey.toBuffer()],
+      program.programId
+    )
+    const ed25519Ix = Ed25519Program.createInstructionWithPrivateKey({
+      privateKey: wallet.payer.secretKey,
+      message: delegatedMessage(
+        program.programId,
+        merkleTree.publicKey,
+        note,
+        wallet.publicKey,
+        0
+      ),
+    })
+    const txSignature = await program.methods
+      .appendDelegated(
+        note,
+        wallet.publicKey,
+        Array.from(ed25519Ix.data.subarray(48, 112))
+      )
+      .accounts({
+        config: config,
+        merkleTree: merkleTree.publicKey,
+        treeAuthority: treeAuthority,
+        logWrapper: SPL_NOOP_PROGRAM_ID,
+        compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+        allowlist: allowlist,
+        // The owner's author stats, here the wallet's
+        authorStats: authorStats,
+        delegateNonce: delegateNonce,
+        instructions: SYSVAR_INSTRUCTIONS_PUBKEY,
+      })
+      .preInstructions([ed25519Ix])
+      .rpc()
+    const noteLog = await getNoteLog(connection, txSignature)
+    const index = appendedLeaves.length
+    appendedLeaves.push(Buffer.from(noteLog.leafNode))
+
+    const [claimRecord] = PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("claimed"),
+        merkleTree.publicKey.toBuffer(),
+        new anchor.BN(index).toArrayLike(Buffer, "le", 4),
+      ],
+      program.programId
+    )
+    const claim = async (note: string) => {
+      const { root, proofAccounts } = await getProof(index)
+      return program.methods
+        .claimForNotes([{ index, root, note }])
+        .accounts({
+          config: config,
+          rewardVault: rewardVault,
+          merkleTree: merkleTree.publicKey,
+          compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+        })
+        .remainingAccounts([
+          ...proofAccounts,
+          { pubkey: claimRecord, isSigner: false, isWritable: true },
+        ])
+        .rpc()
+    }
+
+    // A note that isn't stored at the index fails verification and pays nothing
+    const before = await connection.getBalance(rewardVault)
+    try {
+      await claim("not the note")
+      assert.fail("claim for an unverified note should fail")
+    } catch (error) {
+      assert(!error.message.includes("claim for an unverified note should fail"))
+    }
+    assert((await connection.getBalance(rewardVault)) === before)
+
+    await claim(note)
+    assert(
+      (await connection.getBalance(rewardVault)) === before - rewardPerNote
+    )
+    const record = await program.account.claimRecord.fetch(claimRecord)
+    assert(record.index === index)
+    assert(record.author.equals(wallet.publicKey))
+
+    // The claim is recorded, so claiming the same note again fails and pays nothing
+    try {
+      await claim(note)
+      assert.fail("second claim should fail")
+    } catch (error) {
+      assert(error.error.errorCode.code === "AlreadyClaimed")
+    }
+    assert(
+      (await connection.getBalance(rewardVault)) === before - rewardPerNote
+    )
+  })
+
+  it("Duplicate Leaf Check", async () => {
+    const note = "double submit"
+    const setCheck = (duplicateLeafCheck) =>
+      program.methods
+        .setDuplicateLeafCheck(duplicateLeafCheck)
+        .accounts({
+          config: config,
+          merkleTree: merkleTree.publicKey,
+        })
+        .rpc()
+    const appendNote = () =>
+      program.methods
+        .appendNote(note)
+        .accounts({
+          config: config,
+          merkleTree: merkleTree.publicKey,
+          treeAuthority: treeAuthority,
+          logWrapper: SPL_NOOP_PROGRAM_ID,
+          compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+          allowlist: allowlist,
+          authorStats: authorStats,
+        })
+        .rpc()
+
+    await setCheck({ strict: {} })
+    await appendNote()
+    appendedLeaves.push(Buffer.from(keccak256.digest(note)))
+
+    // Appending the same note again is rejected in strict mode
+    try {
+      await appendNote()
+      assert.fail("duplicate append should fail")
+    } catch (error) {
+      assert(error.error.errorCode.code === "DuplicateLeaf")
+    }
+
+    // In warn mode the duplicate is appended with a warning log
+    await setCheck({ warn: {} })
+    const txSignature = await appendNote()
+    appendedLeaves.push(Buffer.from(keccak256.digest(note)))
+    const tx = await connection.getTransaction(txSignature, {
+      commitment: "confirmed",
+    })
+    assert(
+      tx.meta.logMessages.some((log) =>
+        log.includes("Warning: the note's leaf node equals the rightmost leaf")
+      )
+    )
+
+    await setCheck({ off: {} })
+  })
+
+  it("Create Shared Tree", async () => {
+    // The shared tree authority is derived from the global config instead of the merkle tree
+    const [sharedTreeAuthority] = PublicKey.findProgramAddressSync(
+      [globalConfig.toBuffer()],
+      program.programId
+    )
+
+    // Create two trees sharing the one tree authority
+    for (let i = 0; i < 2; i++) {
+      const sharedTree = Keypair.generate()
+      const [sharedConfig] = PublicKey.findProgramAddressSync(
+        [Buffer.from("config"), sharedTree.publicKey.toBuffer()],
+        program.programId
+      )
+
+      const allocTreeIx = await createAllocTreeIx(
+        connection,
+        sharedTree.publicKey,
+        wallet.publicKey,
+        { maxDepth: 3, maxBufferSize: 8 },
+        0
+      )
+      const ix = await program.methods
+        .createSharedTree({
+          ...DEFAULT_TREE_CONFIG_INIT_ARGS,
+          maxDepth: 3,
+          maxBufferSize: 8,
+        })
+        .accounts({
+          globalConfig: globalConfig,
+          config: sharedConfig,
+          treeAuthority: sharedTreeAuthority,
+          merkleTree: sharedTree.publicKey,
+          logWrapper: SPL_NOOP_PROGRAM_ID,
+          compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+        })
+        .instruction()
+      await sendAndConfirmTransaction(
+        connection,
+        new Transaction().add(allocTreeIx, ix),
+        [wallet.payer, sharedTree]
+      )
+
+      const configAccount = await program.account.noteTreeConfig.fetch(
+        sharedConfig
+      )
+      assert(configAccount.globalConfig.equals(globalConfig))
+
+      // Notes are appended to a shared tree signing with the shared tree authority
+      const note = "shared note"
+      const txSignature = await program.methods
+        .appendNote(note)
+        .accounts({
+          config: sharedConfig,
+          merkleTree: sharedTree.publicKey,
+          treeAuthority: sharedTreeAuthority,
+          logWrapper: SPL_NOOP_PROGRAM_ID,
+          compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+          allowlist: PublicKey.findProgramAddressSync(
+            [Buffer.from("allowlist"), sharedTree.publicKey.toBuffer()],
+            program.programId
+          )[0],
+          authorStats: PublicKey.findProgramAddressSync(
+            [
+              Buffer.from("author"),
+              sharedTree.publicKey.toBuffer(),
+              wallet.publicKey.toBuffer(),
+            ],
+            program.programId
+          )[0],
+        })
+        .rpc()
+      const noteLog = await getNoteLog(connection, txSignature)
+        assert(noteLogLeaf(noteLog, 1).equals(Buffer.from(noteLog.leafNode)))
+      assert(noteLog.note === note)
+    }
+  })
+
+  it("Append Note Mirrored", async () => {
+    const note = "mirrored note"
+    const [rootMirror] = PublicKey.findProgramAddressSync(
+      [Buffer.from("root_mirror"), merkleTree.publicKey.toBuffer()],
+      program.programId
+    )
+
+    await program.methods
+      .appendNoteMirrored(note)
+      .accounts({
+        noteAccounts: {
+          config: config,
+          merkleTree: merkleTree.publicKey,
+          treeAuthority: treeAuthority,
+          logWrapper: SPL_NOOP_PROGRAM_ID,
+          compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+          allowlist: allowlist,
+          authorStats: authorStats,
+        },
+        rootMirror: rootMirror,
+      })
+      .rpc()
+    appendedLeaves.push(Buffer.from(keccak256.digest(note)))
+
+    // The mirrored root is the current root of the tree, and of the tree rebuilt off-chain
+    const rootMirrorAccount = await program.account.rootMirror.fetch(rootMirror)
+    const treeAccount = await ConcurrentMerkleTreeAccount.fromAccountAddress(
+      connection,
+      merkleTree.publicKey
+    )
+    const offChainTree = MerkleTree.sparseMerkleTreeFromLeaves(
+      appendedLeaves,
+      maxDepthSizePair.maxDepth
+    )
+    assert(
+      Buffer.from(rootMirrorAccount.root).equals(treeAccount.getCurrentRoot())
+    )
+    assert(Buffer.from(rootMirrorAccount.root).equals(offChainTree.root))
+    assert(
+      rootMirrorAccount.seq.toString() ===
+        treeAccount.getCurrentSeq().toString()
+    )
+  })
+
+  it("Genesis Note", async () => {
+    const note = "genesis manifest"
+
+    // The test tree already has leaves, so a genesis note can't be appended to it
+    try {
+      await program.methods
+        .appendGenesisNote(note)
+        .accounts({
+          config: config,
+          merkleTree: merkleTree.publicKey,
+          treeAuthority: treeAuthority,
+          logWrapper: SPL_NOOP_PROGRAM_ID,
+          compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+          allowlist: allowlist,
+          authorStats: authorStats,
+        })
+        .rpc()
+      assert.fail("genesis note on a non-empty tree should fail")
+    } catch (error) {
+      assert(error.error.errorCode.code === "NotEmpty")
+    }
+
+    // Create a new tree with the genesis note as its first leaf in one step
+    const genesisTree = Keypair.generate()
+    const [genesisConfig] = PublicKey.findProgramAddressSync(
+      [Buffer.from("config"), genesisTree.publicKey.toBuffer()],
+      program.programId
+    )
+    const [genesisTreeAuthority] = PublicKey.findProgramAddressSync(
+      [genesisTree.publicKey.toBuffer()],
+      program.programId
+    )
+    const [genesisAuthorStats] = PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("author"),
+        genesisTree.publicKey.toBuffer(),
+        wallet.publicKey.toBuffer(),
+      ],
+      program.programId
+    )
+
+    const allocTreeIx = await createAllocTreeIx(
+      connection,
+      genesisTree.publicKey,
+      wallet.publicKey,
+      { maxDepth: 3, maxBufferSize: 8 },
+      0
+    )
+    const ix = await program.methods
+      .createNoteTreeWithNote(
+        { ...DEFAULT_TREE_CONFIG_INIT_ARGS, maxDepth: 3, maxBufferSize: 8 },
+        note
+      )
+      .accounts({
+        create: {
+          config: genesisConfig,
+          treeAuthority: genesisTreeAuthority,
+          merkleTree: genesisTree.publicKey,
+          logWrapper: SPL_NOOP_PROGRAM_ID,
+          compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+        },
+        authorStats: genesisAuthorStats,
+      })
+      .remainingAccounts([globalConfigRemainingAccount])
+      .instruction()
+    const txSignature = await sendAndConfirmTransaction(
+      connection,
+      new Transaction().add(allocTreeIx, ix),
+      [wallet.payer, genesisTree]
+    )
+
+    const noteLog = await getNoteLog(connection, txSignature)
+    assert(noteLogLeaf(noteLog, 1).equals(Buffer.from(noteLog.leafNode)))
+    assert(noteLog.note === note)
+    assert(noteLog.genesis === 1)
+
+    const treeAccount = await ConcurrentMerkleTreeAccount.fromAccountAddress(
+      connection,
+      genesisTree.publicKey
+    )
+    // The genesis note is the only leaf of the new tree
+    const offChainTree = MerkleTree.sparseMerkleTreeFromLeaves(
+      [Buffer.from(keccak256.digest(note))],
+      3
+    )
+    assert(treeAccount.getCurrentRoot().equals(offChainTree.root))
+  })
+
+  it("Compression Program Override", async () => {
+    const [sharedTreeAuthority] = PublicKey.findProgramAddressSync(
+      [globalConfig.toBuffer()],
+      program.programId
+    )
+
+    // Point new shared trees at a forked compression program
+    const fork = Keypair.generate().publicKey
+    await program.methods
+      .setCompressionProgram(fork)
+      .accounts({ globalConfig: globalConfig })
+      .rpc()
+    const globalConfigAccount = await program.account.globalConfig.fetch(
+      globalConfig
+    )
+    assert(globalConfigAccount.compressionProgram.equals(fork))
+
+    // Creating a shared tree with the canonical compression program now fails
+    const sharedTree = Keypair.generate()
+    try {
+      await program.methods
+        .createSharedTree({
+          ...DEFAULT_TREE_CONFIG_INIT_ARGS,
+          maxDepth: 3,
+          maxBufferSize: 8,
+        })
+        .accounts({
+          globalConfig: globalConfig,
+          config: PublicKey.findProgramAddressSync(
+            [Buffer.from("config"), sharedTree.publicKey.toBuffer()],
+            program.programId
+          )[0],
+          treeAuthority: sharedTreeAuthority,
+          merkleTree: sharedTree.publicKey,
+          logWrapper: SPL_NOOP_PROGRAM_ID,
+          compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+        })
+        .rpc()
+      assert.fail("canonical compression program should be rejected")
+    } catch (error) {
+      assert(error.error.errorCode.code === "ConstraintAddress")
+    }
+
+    // Restore the canonical compression program
+    await program.methods
+      .setCompressionProgram(null)
+      .accounts({ globalConfig: globalConfig })
+      .rpc()
+  })
+
+  it("Adopt Tree", async () => {
+    const [programData] = PublicKey.findProgramAddressSync(
+      [program.programId.toBuffer()],
+      BPF_LOADER_UPGRADEABLE_PROGRAM_ID
+    )
+
+    // The test tree already has a config, so it can't be adopted again
+    try {
+      await program.methods
+        .adoptTree(1, null, { open: {} })
+        .accounts({
+          program: program.programId,
+          programData: programData,
+          config: config,
+          treeAuthority: treeAuthority,
+          merkleTree: merkleTree.publicKey,
+          compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+        })
+        .rpc()
+      assert.fail("adopting a tree with a config should fail")
+    } catch (error) {
+      assert(!error.message.includes("adopting a tree with a config should fail"))
+    }
+
+    // A bare tree created with the compression program directly, with a note appended before it
+    // has a config, is adopted once its authority is handed to the tree authority pda
+    const bareTree = Keypair.generate()
+    const bareDepthSizePair: ValidDepthSizePair = {
+      maxDepth: 3,
+      maxBufferSize: 8,
+    }
+    const [bareConfig] = PublicKey.findProgramAddressSync(
+      [Buffer.from("config"), bareTree.publicKey.toBuffer()],
+      program.programId
+    )
+    const [bareTreeAuthority] = PublicKey.findProgramAddressSync(
+      [bareTree.publicKey.toBuffer()],
+      program.programId
+    )
+    const note = "note before adoption"
+    const leaf = Buffer.from(keccak256.digest(note))
+    const bareTreeTx = new Transaction().add(
+      await createAllocTreeIx(
+        connection,
+        bareTree.publicKey,
+        wallet.publicKey,
+        bareDepthSizePair,
+        0
+      ),
+      createInitEmptyMerkleTreeIx(
+        bareTree.publicKey,
+        wallet.publicKey,
+        bareDepthSizePair
+      ),
+      createAppendIx(bareTree.publicKey, wallet.publicKey, leaf),
+      createTransferAuthorityIx(
+        bareTree.publicKey,
+        wallet.publicKey,
+        bareTreeAuthority
+      )
+    )
+    await sendAndConfirmTransaction(connection, bareTreeTx, [
+      wallet.payer,
+      bareTree,
+    ])
+
+    await program.methods
+      .adoptTree(1, null, { open: {} })
+      .accounts({
+        program: program.programId,
+        programData: programData,
+        config: bareConfig,
+        treeAuthority: bareTreeAuthority,
+        merkleTree: bareTree.publicKey,
+        compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+      })
+      .rpc()
+
+    // The note count is read from the tree, and the canonical compression program isn't recorded
+    const bareConfigAccount = await program.account.noteTreeConfig.fetch(
+      bareConfig
+    )
+    assert(bareConfigAccount.noteCount.toNumber() === 1)
+    assert(bareConfigAccount.compressionProgram === null)
+
+    // The note appended before adoption verifies through the new config
+    const offChainTree = MerkleTree.sparseMerkleTreeFromLeaves(
+      [leaf],
+      bareDepthSizePair.maxDepth
+    )
+    await program.methods
+      .verifyNote(0, Array.from(offChainTree.root), note, false)
+      .accounts({
+        config: bareConfig,
+        merkleTree: bareTree.publicKey,
+        compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+      })
+      .remainingAccounts(
+        offChainTree.getProof(0).proof.map((node) => ({
+          pubkey: new PublicKey(node),
+          isSigner: false,
+          isWritable: false,
+        }))
+      )
+      .rpc()
+  })
+
+  it("Verify Note At Checkpoint", async () => {
+    const checkpointIndex = 0
+    const [checkpoint] = PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("checkpoint"),
+        merkleTree.publicKey.toBuffer(),
+        new anchor.BN(checkpointIndex).toArrayLike(Buffer, "le", 4),
+      ],
+      program.programId
+    )
+
+    await program.methods
+      .createCheckpoint(checkpointIndex, "before update")
+      .accounts({
+        config: config,
+        checkpoint: checkpoint,
+        merkleTree: merkleTree.publicKey,
+      })
+      .rpc()
+    const checkpointLeaves = [...appendedLeaves]
+
+    // Change the tree after the checkpoint
+    const note = "after checkpoint"
+    await program.methods
+      .appendNote(note)
+      .accounts({
+        config: config,
+        merkleTree: merkleTree.publicKey,
+        treeAuthority: treeAuthority,
+        logWrapper: SPL_NOOP_PROGRAM_ID,
+        compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+        allowlist: allowlist,
+        authorStats: authorStats,
+      })
+      .rpc()
+    appendedLeaves.push(Buffer.from(keccak256.digest(note)))
+
+    // The proof must be for the tree as of the checkpoint
+    const leafIndex = 0
+    const { proof } = MerkleTree.sparseMerkleTreeFromLeaves(
+      checkpointLeaves,
+      maxDepthSizePair.maxDepth
+    ).getProof(leafIndex)
+    const verify = (note: string) =>
+      program.methods
+        .verifyNoteAtCheckpoint(checkpointIndex, note, leafIndex)
+        .accounts({
+          config: config,
+          checkpoint: checkpoint,
+          merkleTree: merkleTree.publicKey,
+        })
+        .remainingAccounts(
+          proof.map((node) => ({
+            pubkey: new PublicKey(node),
+            isSigner: false,
+            isWritable: false,
+          }))
+        )
+        .rpc()
+
+    await verify("hello world")
+    try {
+      await verify("not the note")
+      assert.fail("unverified note should fail")
+    } catch (error) {
+      assert(error.error.errorCode.code === "LeafMismatch")
+    }
+  })
+
+  it("Append Namespaced Note", async () => {
+    const namespace = "tenant-a"
+    const note = "namespaced note"
+
+    const txSignature = await program.methods
+      .appendNamespacedNote(namespace, note)
+      .accounts({
+        config: config,
+        merkleTree: merkleTree.publicKey,
+        treeAuthority: treeAuthority,
+        logWrapper: SPL_NOOP_PROGRAM_ID,
+        compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+        allowlist: allowlist,
+        authorStats: authorStats,
+      })
+      .rpc()
+
+    // The leaf node is the hash of the length prefixed namespace followed by the note
+    const noteLog = await getNoteLog(connection, txSignature)
+    appendedLeaves.push(Buffer.from(noteLog.leafNode))
+    assert(noteLog.namespace === namespace)
+    assert(noteLog.note === note)
+    assert(
+      leafHash(LEAF_TAG_NAMESPACED, frameNamespaced(namespace, note)).equals(
+        Buffer.from(noteLog.leafNode)
+      )
+    )
+    assert(noteLogLeaf(noteLog, 1).equals(Buffer.from(noteLog.leafNode)))
+  })
+
+  it("Append Chained Note", async () => {
+    const appendChained = (note: string) =>
+      program.methods
+        .appendChainedNote(note)
+        .accounts({
+          config: config,
+          merkleTree: merkleTree.publicKey,
+          treeAuthority: treeAuthority,
+          logWrapper: SPL_NOOP_PROGRAM_ID,
+          compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+          allowlist: allowlist,
+          authorStats: authorStats,
+        })
+        .rpc()
+
+    // The first chained note is hashed with a zeroed chain tip
+    const first = await getNoteLog(connection, await appendChained("first"))
+    appendedLeaves.push(Buffer.from(first.leafNode))
+    assert(Buffer.from(first.prevLeaf).equals(Buffer.alloc(32)))
+    assert(noteLogLeaf(first, 1).equals(Buffer.from(first.leafNode)))
+
+    // Each later chained note commits to the leaf node of the previous one
+    const second = await getNoteLog(connection, await appendChained("second"))
+    appendedLeaves.push(Buffer.from(second.leafNode))
+    assert(Buffer.from(second.prevLeaf).equals(Buffer.from(first.leafNode)))
+    assert(noteLogLeaf(second, 1).equals(Buffer.from(second.leafNode)))
+
+    const configAccount = await program.account.noteTreeConfig.fetch(config)
+    assert(Buffer.from(configAccount.chainTip).equals(Buffer.from(second.leafNode)))
+  })
+
+  it("Get Checkpoint", async () => {
+    const checkpointIndex = 0
+    const [checkpoint] = PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("checkpoint"),
+        merkleTree.publicKey.toBuffer(),
+        new anchor.BN(checkpointIndex).toArrayLike(Buffer, "le", 4),
+      ],
+      program.programId
+    )
+
+    const { raw } = await program.methods
+      .getCheckpoint(checkpointIndex)
+      .accounts({ checkpoint: checkpoint, merkleTree: merkleTree.publicKey })
+      .simulate()
+
+    // Decode the returned root, slot u64, sequence u64 and label string
+    const returnLog = raw.find((log) => log.startsWith("Program return:"))
+    const returnData = Buffer.from(returnLog.split(" ")[3], "base64")
+    const root = returnData.subarray(0, 32)
+    const slot = returnData.readBigUInt64LE(32)
+    const sequence = returnData.readBigUInt64LE(40)
+    const labelLength = returnData.readUInt32LE(48)
+    const label = returnData.subarray(52, 52 + labelLength).toString()
+
+    const checkpointAccount = await program.account.checkpoint.fetch(checkpoint)
+    assert(root.equals(Buffer.from(checkpointAccount.root)))
+    assert(slot.toString() === checkpointAccount.slot.toString())
+    assert(sequence.toString() === checkpointAccount.seq.toString())
+    assert(label === "before update")
+  })
+
+  it("Append If Verified", async () => {
+    const index = 0
+    const appendIfVerified = async (refNote: string, newNote: string) => {
+      const { root, proofAccounts } = await getProof(index)
+      return program.methods
+        .appendIfVerified(index, root, refNote, newNote)
+        .accounts({
+          config: config,
+          merkleTree: merkleTree.publicKey,
+          treeAuthority: treeAuthority,
+          logWrapper: SPL_NOOP_PROGRAM_ID,
+          compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+          allowlist: allowlist,
+          authorStats: authorStats,
+        })
+        .remainingAccounts(proofAccounts)
+        .rpc()
+    }
+
+    // A reference note that isn't stored at the index fails the whole instruction
+    try {
+      await appendIfVerified("not the note", "dependent note")
+      assert.fail("append with an unverified reference should fail")
+    } catch (error) {
+      assert(
+        !error.message.includes("append with an unverified reference should fail")
+      )
+    }
+
+    // The new note is hashed with the leaf node of the referenced note
+    const txSignature = await appendIfVerified("hello world", "dependent note")
+    const noteLog = await getNoteLog(connection, txSignature)
+    appendedLeaves.push(Buffer.from(noteLog.leafNode))
+    assert(Buffer.from(noteLog.refLeaf).equals(appendedLeaves[index]))
+    assert(!noteLog.prevLeaf && !noteLog.parentLeaf)
+    assert(noteLogLeaf(noteLog, 1).equals(Buffer.from(noteLog.leafNode)))
+  })
+
+  it("Min Depth", async () => {
+    const createTree = async (maxDepth: number, maxBufferSize: number) => {
+      const tree = Keypair.generate()
+      const allocTreeIx = await createAllocTreeIx(
+        connection,
+        tree.publicKey,
+        wallet.publicKey,
+        { maxDepth, maxBufferSize },
+        0
+      )
+      return program.methods
+        .createNoteTree({
+          ...DEFAULT_TREE_CONFIG_INIT_ARGS,
+          maxDepth,
+          maxBufferSize,
+        })
+        .accounts({
+          config: PublicKey.findProgramAddressSync(
+            [Buffer.from("config"), tree.publicKey.toBuffer()],
+            program.programId
+          )[0],
+          merkleTree: tree.publicKey,
+          treeAuthority: PublicKey.findProgramAddressSync(
+            [tree.publicKey.toBuffer()],
+            program.programId
+          )[0],
+          logWrapper: SPL_NOOP_PROGRAM_ID,
+          compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+        })
+        .preInstructions([allocTreeIx])
+        .signers([tree])
+        .rpc()
+    }
+
+    // Without a global config, trees below the default min depth of 14 are rejected
+    try {
+      await createTree(5, 8)
+      assert.fail("tree below the min depth should fail")
+    } catch (error) {
+      assert(error.error.errorCode.code === "DepthTooShallow")
+    }
+
+    // Trees at the min depth are created
+    await createTree(14, 64)
+  })
+
+  it("Note Log Discriminator", async () => {
+    // Every note log decoded by getNoteLog in the tests above was logged with the discriminator,
+    // and data without it, such as another program's logs on the shared noop feed, is rejected
+    const body = Buffer.from("note log bytes")
+    const logged = Buffer.concat([NOTE_LOG_DISCRIMINATOR, Buffer.from([0]), body])
+    assert(decodeLogCompression(logged).equals(body))
+    try {
+      decodeLogCompression(Buffer.concat([Buffer.from([0]), body]))
+      assert.fail("data without the discriminator should fail")
+    } catch (error) {
+      assert(error.message === "Not a note log")
+    }
+  })
+
+  it("Update Title", async () => {
+    const body = "titled note body"
+    const txSignature = await program.methods
+      .appendTitledNote("old title", body)
+      .accounts({
+        config: config,
+        merkleTree: merkleTree.publicKey,
+        treeAuthority: treeAuthority,
+        logWrapper: SPL_NOOP_PROGRAM_ID,
+        compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+        allowlist: allowlist,
+        authorStats: authorStats,
+      })
+      .rpc()
+    const noteLog = await getNoteLog(connection, txSignature)
+    assert(noteLog.title === "old title")
+    assert(noteLogLeaf(noteLog, 1).equals(Buffer.from(noteLog.leafNode)))
+    const index = appendedLeaves.length
+    appendedLeaves.push(Buffer.from(noteLog.leafNode))
+
+    const updateTitle = async (oldTitle: string, newTitle: string) => {
+      const { root, proofAccounts } = await getProof(index)
+      return program.methods
+        .updateTitle(index, root, oldTitle, body, newTitle)
+        .accounts({
+          config: config,
+          treeAuthority: treeAuthority,
+          merkleTree: merkleTree.publicKey,
+          logWrapper: SPL_NOOP_PROGRAM_ID,
+          compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+        })
+        .remainingAccounts(proofAccounts)
+        .rpc()
+    }
+
+    // An old title that doesn't produce the stored leaf node is rejected
+    try {
+      await updateTitle("wrong title", "new title")
+      assert.fail("update with the wrong old title should fail")
+    } catch (error) {
+      assert(error.error.errorCode.code === "LeafMismatch")
+    }
+
+    // Listen for the NoteUpdated event emitted by the instruction
+    let noteUpdated = null
+    const listener = program.addEventListener("NoteUpdated", (event) => {
+      noteUpdated = event
+    })
+    await updateTitle("old title", "new title")
+    for (let i = 0; i < 20 && noteUpdated === null; i++) {
+      await new Promise((resolve) => setTimeout(resolve, 100))
+    }
+    await program.removeEventListener(listener)
+
+    // The leaf node is replaced with the hash of the new title and the same body
+    const newLeaf = leafHash(LEAF_TAG_TITLED, frameNamespaced("new title", body))
+    appendedLeaves[index] = newLeaf
+    assert(noteUpdated.index === index)
+    assert(noteUpdated.oldTitle === "old title")
+    assert(noteUpdated.newTitle === "new title")
+    assert(Buffer.from(noteUpdated.leafNode).equals(newLeaf))
+
+    const { root } = await getProof(index)
+    const offChainTree = MerkleTree.sparseMerkleTreeFromLeaves(
+      appendedLeaves,
+      maxDepthSizePair.maxDepth
+    )
+    assert(offChainTree.root.equals(Buffer.from(root)))
+  })
+
+  it("Append Batch Summarized", async () => {
+    const notes = ["batch note 1", "batch note 2", "batch note 3"]
+
+    // Listen for the one BatchAppended event emitted for the batch
+    let batchAppended = null
+    const listener = program.addEventListener("BatchAppended", (event) => {
+      batchAppended = event
+    })
+    await program.methods
+      .appendBatchSummarized(notes)
+      .accounts({
+        config: config,
+        merkleTree: merkleTree.publicKey,
+        treeAuthority: treeAuthority,
+        logWrapper: SPL_NOOP_PROGRAM_ID,
+        compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+        allowlist: allowlist,
+        authorStats: authorStats,
+      })
+      .rpc()
+    for (let i = 0; i < 20 && batchAppended === null; i++) {
+      await new Promise((resolve) => setTimeout(resolve, 100))
+    }
+    await program.removeEventListener(listener)
+
+    // The batch root is reconstructed from the leaf nodes of the notes
+    const leafNodes = notes.map((note) => Buffer.from(keccak256.digest(note)))
+    appendedLeaves.push(...leafNodes)
+    assert(batchAppended.count === notes.length)
+    assert(Buffer.from(batchAppended.batchRoot).equals(batchRoot(leafNodes)))
+    const { root } = await getProof(0)
+    assert(Buffer.from(batchAppended.root).equals(Buffer.from(root)))
+  })
+
+  it("Interleaved Appends And Updates", async () => {
+    const body = "titled note body"
+    let title = "new title"
+    const titleLeaf = (title: string) =>
+      leafHash(LEAF_TAG_TITLED, frameNamespaced(title, body))
+    const index = appendedLeaves.findIndex((leaf) => leaf.equals(titleLeaf(title)))
+    const getTreeAccount = () =>
+      ConcurrentMerkleTreeAccount.fromAccountAddress(
+        connection,
+        merkleTree.publicKey
+      )
+
+    let seq = BigInt((await getTreeAccount()).getCurrentSeq().toString())
+    for (let round = 0; round < 2; round++) {
+      // Build both instructions against the same root, then send them without waiting on each other,
+      // so they land in either order, possibly in the same block
+      const { root, proofAccounts } = await getProof(index)
+      const newTitle = `title ${round}`
+      const note = `interleaved note ${round}`
+      await Promise.all([
+        program.methods
+          .appendNote(note)
+          .accounts({
+            config: config,
+            merkleTree: merkleTree.publicKey,
+            treeAuthority: treeAuthority,
+            logWrapper: SPL_NOOP_PROGRAM_ID,
+            compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+            allowlist: allowlist,
+            authorStats: authorStats,
+          })
+          .rpc(),
+        program.methods
+          .updateTitle(index, root, title, body, newTitle)
+          .accounts({
+            config: config,
+            treeAuthority: treeAuthority,
+            merkleTree: merkleTree.publicKey,
+            logWrapper: SPL_NOOP_PROGRAM_ID,
+            compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+          })
+          .remainingAccounts(proofAccounts)
+          .rpc(),
+      ])
+      appendedLeaves.push(Buffer.from(keccak256.digest(note)))
+      appendedLeaves[index] = titleLeaf(newTitle)
+      title = newTitle
+
+      // Either order gives the same tree, and the sequence number advances once per change
+      const treeAccount = await getTreeAccount()
+      const offChainTree = MerkleTree.sparseMerkleTreeFromLeaves(
+        appendedLeaves,
+        maxDepthSizePair.maxDepth
+      )
+      assert(treeAccount.getCurrentRoot().equals(offChainTree.root))
+      const nextSeq = BigInt(treeAccount.getCurrentSeq().toString())
+      assert(nextSeq === seq + BigInt(2))
+      seq = nextSeq
+    }
+  })
+
+  it("Close Note Tree And Config", async () => {
+    const emptyTree = Keypair.generate()
+    const [emptyConfig] = PublicKey.findProgramAddressSync(
+      [Buffer.from("config"), emptyTree.publicKey.toBuffer()],
+      program.programId
+    )
+    const [emptyTreeAuthority] = PublicKey.findProgramAddressSync(
+      [emptyTree.publicKey.toBuffer()],
+      program.programId
+    )
+    const allocTreeIx = await createAllocTreeIx(
+      connection,
+      emptyTree.publicKey,
+      wallet.publicKey,
+      { maxDepth: 3, maxBufferSize: 8 },
+      0
+    )
+    await program.methods
+      .createNoteTree({
+        ...DEFAULT_TREE_CONFIG_INIT_ARGS,
+        maxDepth: 3,
+        maxBufferSize: 8,
+      })
+      .accounts({
+        config: emptyConfig,
+        merkleTree: emptyTree.publicKey,
+        treeAuthority: emptyTreeAuthority,
+        logWrapper: SPL_NOOP_PROGRAM_ID,
+        compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+      })
+      .remainingAccounts([globalConfigRemainingAccount])
+      .preInstructions([allocTreeIx])
+      .signers([emptyTree])
+      .rpc()
+
+    const closeConfig = () =>
+      program.methods
+        .closeConfig()
+        .accounts({ config: emptyConfig, merkleTree: emptyTree.publicKey })
+
+    // The config can't be closed while the tree still exists
+    try {
+      await closeConfig().rpc()
+      assert.fail("closing the config of an open tree should fail")
+    } catch (error) {
+      assert(error.error.errorCode.code === "TreeNotClosed")
+    }
+
+    // Close the tree and then its config in the same transaction
+    await program.methods
+      .closeNoteTree()
+      .accounts({
+        config: emptyConfig,
+        treeAuthority: emptyTreeAuthority,
+        merkleTree: emptyTree.publicKey,
+        compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+      })
+      .postInstructions([await closeConfig().instruction()])
+      .rpc()
+
+    assert((await connection.getAccountInfo(emptyTree.publicKey)) === null)
+    assert((await connection.getAccountInfo(emptyConfig)) === null)
+  })
+
+  it("Append Raw Hex Leaf", async () => {
+    const appendRawHexLeaf = (hex: string) =>
+      program.methods
+        .appendRawHexLeaf(hex)
+        .accounts({
+          config: config,
+          merkleTree: merkleTree.publicKey,
+          treeAuthority: treeAuthority,
+          logWrapper: SPL_NOOP_PROGRAM_ID,
+          compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+          allowlist: allowlist,
+          authorStats: authorStats,
+        })
+        .rpc()
+
+    // Invalid hex is rejected, including a sign that integer parsing would accept
+    for (const hex of ["ab", "zz".repeat(32), "+f".repeat(32)]) {
+      try {
+        await appendRawHexLeaf(hex)
+        assert.fail("invalid hex should fail")
+      } catch (error) {
+        assert(error.error.errorCode.code === "InvalidHex")
+      }
+    }
+
+    // The decoded hex is hashed once under the raw tag, so it can't be the leaf node of another
+    // variant, here the text note it is the hash of
+    const hex = keccak256("content addressed note").toUpperCase()
+    const noteLog = await getNoteLog(connection, await appendRawHexLeaf(hex))
+    appendedLeaves.push(Buffer.from(noteLog.leafNode))
+    assert(
+      Buffer.from(noteLog.leafNode).equals(
+        leafHash(LEAF_TAG_RAW, Buffer.from(hex, "hex"))
+      )
+    )
+    assert(
+      !Buffer.from(noteLog.leafNode).equals(textLeaf("content addressed note"))
+    )
+    assert(noteLog.rawLeaf === 1)
+    assert(noteLogLeaf(noteLog, 1).equals(Buffer.from(noteLog.leafNode)))
+  })
+
+  it("Recent Leaves", async () => {
+    // The config caches the latest leaf nodes by leaf index modulo the ring size
+    const configAccount = await program.account.noteTreeConfig.fetch(config)
+    const ringSize = configAccount.recentLeaves.length
+    assert(ringSize === 8)
+    for (let i = appendedLeaves.length - 4; i < appendedLeaves.length; i++) {
+      const recentLeaf = configAccount.recentLeaves[i % ringSize]
+      assert(recentLeaf.index === i)
+      assert(Buffer.from(recentLeaf.leafNode).equals(appendedLeaves[i]))
+    }
+    // Every appended leaf is counted, so the filled entries don't depend on their leaf nodes
+    assert(configAccount.recentLeafCount.toNumber() === appendedLeaves.length)
+  })
+
+  it("Append Note At Root", async () => {
+    const appendAtRoot = (root: number[], note: string) =>
+      program.methods
+        .appendNoteAtRoot(root, note)
+        .accounts({
+          config: config,
+          merkleTree: merkleTree.publicKey,
+          treeAuthority: treeAuthority,
+          logWrapper: SPL_NOOP_PROGRAM_ID,
+          compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+          allowlist: allowlist,
+          authorStats: authorStats,
+        })
+        .rpc()
+
+    // A root that isn't in the change log buffer, here the root the tree would have with a note that was never appended
+    const unknownRoot = MerkleTree.sparseMerkleTreeFromLeaves(
+      [...appendedLeaves, Buffer.from(keccak256.digest("never appended"))],
+      maxDepthSizePair.maxDepth
+    ).root
+    try {
+      await appendAtRoot(Array.from(unknownRoot), "unknown root note")
+      assert.fail("append at a root the tree never had should fail")
+    } catch (error) {
+      assert(error.error.errorCode.code === "ConcurrentWriteConflict")
+    }
+
+    // A root the tree had, pushed out of the buffer by later appends. A depth 3 tree with a buffer
+    // of 8 drops its empty tree root on the 8th append
+    const staleTree = Keypair.generate()
+    const [staleConfig] = PublicKey.findProgramAddressSync(
+      [Buffer.from("config"), staleTree.publicKey.toBuffer()],
+      program.programId
+    )
+    const [staleTreeAuthority] = PublicKey.findProgramAddressSync(
+      [staleTree.publicKey.toBuffer()],
+      program.programId
+    )
+    const allocTreeIx = await createAllocTreeIx(
+      connection,
+      staleTree.publicKey,
+      wallet.publicKey,
+      { maxDepth: 3, maxBufferSize: 8 },
+      0
+    )
+    await program.methods
+      .createNoteTree({
+        ...DEFAULT_TREE_CONFIG_INIT_ARGS,
+        maxDepth: 3,
+        maxBufferSize: 8,
+      })
+      .accounts({
+        config: staleConfig,
+        merkleTree: staleTree.publicKey,
+        treeAuthority: staleTreeAuthority,
+        logWrapper: SPL_NOOP_PROGRAM_ID,
+        compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+      })
+      .remainingAccounts([globalConfigRemainingAccount])
+      .preInstructions([allocTreeIx])
+      .signers([staleTree])
+      .rpc()
+    const staleTreeAccounts = {
+      config: staleConfig,
+      merkleTree: staleTree.publicKey,
+      treeAuthority: staleTreeAuthority,
+      logWrapper: SPL_NOOP_PROGRAM_ID,
+      compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+      allowlist: PublicKey.findProgramAddressSync(
+        [Buffer.from("allowlist"), staleTree.publicKey.toBuffer()],
+        program.programId
+      )[0],
+      authorStats: PublicKey.findProgramAddressSync(
+        [
+          Buffer.from("author"),
+          staleTree.publicKey.toBuffer(),
+          wallet.publicKey.toBuffer(),
+        ],
+        program.programId
+      )[0],
+    }
+    const staleRoot = (
+      await ConcurrentMerkleTreeAccount.fromAccountAddress(
+        connection,
+        staleTree.publicKey
+      )
+    ).getCurrentRoot()
+
+    // The root is accepted while it's in the buffer, up to the 7th append
+    const staleNotes = Array.from({ length: 8 }, (_, i) => `filler note ${i}`)
+    for (const note of staleNotes.slice(0, 7)) {
+      await program.methods
+        .appendNoteAtRoot(Array.from(staleRoot), note)
+        .accounts(staleTreeAccounts)
+        .rpc()
+    }
+    await program.methods
+      .appendNote(staleNotes[7])
+      .accounts(staleTreeAccounts)
+      .rpc()
+    try {
+      await program.methods
+        .appendNoteAtRoot(Array.from(staleRoot), "stale note")
+        .accounts(staleTreeAccounts)
+        .rpc()
+      assert.fail("append at a stale root should fail")
+    } catch (error) {
+      assert(error.error.errorCode.code === "ConcurrentWriteConflict")
+    }
+
+    // The current root is accepted
+    const note = "note at root"
+    const { root } = await getProof(0)
+    await appendAtRoot(root, note)
+    appendedLeaves.push(Buffer.from(keccak256.digest(note)))
+  })
+
+  it("Append Typed Note", async () => {
+    type ContentType = Parameters<typeof program.methods.appendTypedNote>[0]
+    const appendTyped = (contentType: ContentType, note: string) =>
+      program.methods
+        .appendTypedNote(contentType, note)
+        .accounts({
+          config: config,
+          merkleTree: merkleTree.publicKey,
+          treeAuthority: treeAuthority,
+          logWrapper: SPL_NOOP_PROGRAM_ID,
+          compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+          allowlist: allowlist,
+          authorStats: authorStats,
+        })
+        .rpc()
+
+    // Notes without the basic shape of their content type are rejected
+    const invalidNotes: [ContentType, string][] = [
+      [{ json: {} }, "not json"],
+      [{ link: {} }, "ftp://example.com"],
+      [{ link: {} }, "https://example.com/a b"],
+    ]
+    for (const [contentType, note] of invalidNotes) {
+      try {
+        await appendTyped(contentType, note)
+        assert.fail("note with the wrong shape should fail")
+      } catch (error) {
+        assert(error.error.errorCode.code === "InvalidContent")
+      }
+    }
+
+    // The content type is logged, and the leaf node is still the hash of the note
+    const note = '{"board": "typed"}'
+    const noteLog = await getNoteLog(connection, await appendTyped({ json: {} }, note))
+    appendedLeaves.push(Buffer.from(noteLog.leafNode))
+    assert(CONTENT_TYPES[noteLog.contentType] === "json")
+    assert(keccak256(note) === Buffer.from(noteLog.leafNode).toString("hex"))
+  })
+
+  it("Log Budget", async () => {
+    const appendNote = (note: string) =>
+      program.methods
+        .appendNote(note)
+        .accounts({
+          config: config,
+          merkleTree: merkleTree.publicKey,
+          treeAuthority: treeAuthority,
+          logWrapper: SPL_NOOP_PROGRAM_ID,
+          compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+          allowlist: allowlist,
+          authorStats: authorStats,
+        })
+        .rpc()
+    const setMaxLogBytes = (maxLogBytes: anchor.BN) =>
+      program.methods
+        .setMaxLogBytes(maxLogBytes)
+        .accounts({ config: config, merkleTree: merkleTree.publicKey })
+        .rpc()
+
+    // The total counts each logged note log, the compression header and the serialized note log
+    const before = await program.account.noteTreeConfig.fetch(config)
+    const returnData = await getReturnData(
+      connection,
+      await appendNote("budget note 1"),
+      program.programId
+    )
+    appendedLeaves.push(Buffer.from(keccak256.digest("budget note 1")))
+    const after = await program.account.noteTreeConfig.fetch(config)
+    const logBytes = after.totalLogBytes.sub(before.totalLogBytes)
+    assert(logBytes.eqn(1 + returnData.length))
+
+    // An append reaching the budget exactly succeeds, and the next one crossing it fails
+    await setMaxLogBytes(after.totalLogBytes.add(logBytes))
+    await appendNote("budget note 2")
+    appendedLeaves.push(Buffer.from(keccak256.digest("budget note 2")))
+    try {
+      await appendNote("budget note 3")
+      assert.fail("append over the log budget should fail")
+    } catch (error) {
+      assert(error.error.errorCode.code === "LogBudgetExceeded")
+    }
+
+    // A budget of 0 means no limit
+    await setMaxLogBytes(new anchor.BN(0))
+  })
+
+  it("Verify Empty", async () => {
+    const verifyEmpty = async (
+      index: number,
+      root?: number[],
+      badProof = false
+    ) => {
+      const proof = await getProof(index)
+      if (badProof) {
+        proof.proofAccounts[0].pubkey = Keypair.generate().publicKey
+      }
+      return program.methods
+        .verifyEmpty(index, root ?? proof.root)
+        .accounts({
+          config: config,
+          merkleTree: merkleTree.publicKey,
+          compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+        })
+        .remainingAccounts(proof.proofAccounts)
+        .rpc()
+    }
+
+    // The index after the last appended leaf is empty
+    await verifyEmpty(appendedLeaves.length)
+
+    // The last appended index isn't, its leaf is known from the change logs
+    const lastIndex = appendedLeaves.length - 1
+    try {
+      await verifyEmpty(lastIndex)
+      assert.fail("verifying an occupied index as empty should fail")
+    } catch (error) {
+      assert(error.error.errorCode.code === "NotEmpty")
+    }
+
+    // A wrong proof for it is a mismatch the compression program reports,
+    // not an occupied index
+    try {
+      await verifyEmpty(lastIndex, undefined, true)
+      assert.fail("verifying with a wrong proof should fail")
+    } catch (error) {
+      assert(!error.message.includes("with a wrong proof should fail"))
+      assert(error.error?.errorCode?.code !== "NotEmpty")
+    }
+
+    // So is an occupied index whose leaf is in neither the change logs nor
+    // the recent leaves, the newest RECENT_LEAVES_LEN appended leaves
+    const treeAccount = await ConcurrentMerkleTreeAccount.fromAccountAddress(
+      connection,
+      merkleTree.publicKey
+    )
+    const changed = treeAccount.tree.changeLogs.map(({ index }) => index)
+    const unknownIndex = [...Array(Math.max(lastIndex - 8, 0)).keys()].find(
+      (index) => !changed.includes(index)
+    )
+    if (unknownIndex !== undefined) {
+      try {
+        await verifyEmpty(unknownIndex)
+        assert.fail("verifying an occupied index as empty should fail")
+      } catch (error) {
+        assert(!error.message.includes("an occupied index as empty should"))
+        assert(error.error?.errorCode?.code !== "NotEmpty")
+      }
+    }
+
+    // A root the tree never had is a mismatch the compression program reports, not an occupied index
+    const unknownRoot = MerkleTree.sparseMerkleTreeFromLeaves(
+      [...appendedLeaves, Buffer.from(keccak256.digest("never appended"))],
+      maxDepthSizePair.maxDepth
+    ).root
+    try {
+      await verifyEmpty(0, Array.from(unknownRoot))
+      assert.fail("verifying against an unknown root should fail")
+    } catch (error) {
+      assert(!error.message.includes("verifying against an unknown root should fail"))
+      assert(error.error?.errorCode?.code !== "NotEmpty")
+    }
+  })
+
+  it("Append With Metadata", async () => {
+    const appendWithMetadata = (note: string, metadata: { key: string; value: string }[]) =>
+      program.methods
+        .appendWithMetadata(note, metadata)
+        .accounts({
+          config: config,
+          merkleTree: merkleTree.publicKey,
+          treeAuthority: treeAuthority,
+          logWrapper: SPL_NOOP_PROGRAM_ID,
+          compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+          allowlist: allowlist,
+          authorStats: authorStats,
+        })
+        .rpc()
+
+    // Too many entries, or entries too large in total, are rejected
+    const oversized = [
+      Array.from({ length: 9 }, (_, i) => ({ key: `k${i}`, value: "v" })),
+      [{ key: "big", value: "x".repeat(256) }],
+    ]
+    for (const metadata of oversized) {
+      try {
+        await appendWithMetadata("note with metadata", metadata)
+        assert.fail("oversized metadata should fail")
+      } catch (error) {
+        assert(error.error.errorCode.code === "MetadataTooLarge")
+      }
+    }
+
+    // The metadata is logged, and only the note is hashed into the leaf node
+    const note = "note with metadata"
+    const metadata = [
+      { key: "author", value: "alice" },
+      { key: "lang", value: "en" },
+    ]
+    const noteLog = await getNoteLog(
+      connection,
+      await appendWithMetadata(note, metadata)
+    )
+    appendedLeaves.push(Buffer.from(noteLog.leafNode))
+    assert.deepEqual(
+      noteLog.metadata.map(({ key, value }) => ({ key, value })),
+      metadata
+    )
+    assert(keccak256(note) === Buffer.from(noteLog.leafNode).toString("hex"))
+  })
+
+  it("Append Note Indexed", async () => {
+    const note = "indexed note"
+    const txSignature = await program.methods
+      .appendNoteIndexed(note)
+      .accounts({
+        config: config,
+        merkleTree: merkleTree.publicKey,
+        treeAuthority: treeAuthority,
+        logWrapper: SPL_NOOP_PROGRAM_ID,
+        compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+        allowlist: allowlist,
+        authorStats: authorStats,
+      })
+      .rpc()
+    const returnData = await getReturnData(
+      connection,
+      txSignature,
+      program.programId
+    )
+    const index = Number(returnData.readBigUInt64LE(0))
+    appendedLeaves.push(Buffer.from(keccak256.digest(note)))
+
+    // The returned index, the note count and the tree's leaf count agree
+    const configAccount = await program.account.noteTreeConfig.fetch(config)
+    const treeAccount = await ConcurrentMerkleTreeAccount.fromAccountAddress(
+      connection,
+      merkleTree.publicKey
+    )
+    assert(index === appendedLeaves.length - 1)
+    assert(configAccount.noteCount.toNumber() === index + 1)
+    assert(treeAccount.tree.rightMostPath.index === index + 1)
+  })
+
+  it("Append Empty Note", async () => {
+    // The leaf of every note is computed by compute_leaf or NoteLog::new_bytes, which reject an empty note
+    try {
+      await program.methods
+        .appendNote("")
+        .accounts({
+          config: config,
+          merkleTree: merkleTree.publicKey,
+          treeAuthority: treeAuthority,
+          logWrapper: SPL_NOOP_PROGRAM_ID,
+          compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+          allowlist: allowlist,
+          authorStats: authorStats,
+        })
+        .rpc()
+      assert.fail("empty note should fail")
+    } catch (error) {
+      assert(error.error.errorCode.code === "NoteEmpty")
+    }
+
+    // Raw bytes notes get the same checks
+    try {
+      await program.methods
+        .appendBytes(Buffer.alloc(0))
+        .accounts({
+          config: config,
+          merkleTree: merkleTree.publicKey,
+          treeAuthority: treeAuthority,
+          logWrapper: SPL_NOOP_PROGRAM_ID,
+          compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+          allowlist: allowlist,
+          authorStats: authorStats,
+        })
+        .rpc()
+      assert.fail("empty bytes note should fail")
+    } catch (error) {
+      assert(error.error.errorCode.code === "NoteEmpty")
+    }
+  })
+
+  it("Append Delegated", async () => {
+    // The owner signs offline, the wallet appends as the delegate
+    const owner = Keypair.generate()
+    const note = "delegated note"
+    const [delegateNonce] = PublicKey.findProgramAddressSync(
+      [Buffer.from("delegate_nonce"), owner.publicKey.toBuffer()],
+      program.programId
+    )
+    // The note counts towards the owner, so the author stats are the owner's
+    const [ownerStats] = PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("author"),
+        merkleTree.publicKey.toBuffer(),
+        owner.publicKey.toBuffer(),
+      ],
+      program.programId
+    )
+    const appendDelegated = (
+      nonce: number,
+      signedTree = merkleTree.publicKey
+    ) => {
+      const message = delegatedMessage(
+        program.programId,
+        signedTree,
+        note,
+        wallet.publicKey,
+        nonce
+      )
+      const ed25519Ix = Ed25519Program.createInstructionWithPrivateKey({
+        privateKey: owner.secretKey,
+        message,
+      })
+      // The signature sits after the 2 byte header, 14 bytes of offsets and the 32 byte public key
+      const signature = Array.from(ed25519Ix.data.subarray(48, 112))
+      return program.methods
+        .appendDelegated(note, owner.publicKey, signature)
+        .accounts({
+          config: config,
+          merkleTree: merkleTree.publicKey,
+          treeAuthority: treeAuthority,
+          logWrapper: SPL_NOOP_PROGRAM_ID,
+          compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+          allowlist: allowlist,
+          authorStats: ownerStats,
+          delegateNonce: delegateNonce,
+          instructions: SYSVAR_INSTRUCTIONS_PUBKEY,
+        })
+        .preInstructions([ed25519Ix])
+        .rpc()
+    }
+
+    // An authorization over the wrong nonce fails
+    try {
+      await appendDelegated(1)
+      assert.fail("authorization over the wrong nonce should fail")
+    } catch (error) {
+      assert(error.error.errorCode.code === "InvalidSignature")
+    }
+
+    // So does an authorization signed for another tree, even over the current nonce
+    try {
+      await appendDelegated(0, Keypair.generate().publicKey)
+      assert.fail("authorization for another tree should fail")
+    } catch (error) {
+      assert(error.error.errorCode.code === "InvalidSignature")
+    }
+
+    // The leaf node commits to the owner, not the delegate, and the note
+    // counts towards the owner
+    const delegateStatsBefore = await program.account.authorStats.fetch(
+      authorStats
+    )
+    const noteLog = await getNoteLog(connection, await appendDelegated(0))
+    appendedLeaves.push(Buffer.from(noteLog.leafNode))
+    assert(new PublicKey(noteLog.owner).equals(owner.publicKey))
+    assert(noteLogLeaf(noteLog, 1).equals(Buffer.from(noteLog.leafNode)))
+    const ownerStatsAccount = await program.account.authorStats.fetch(
+      ownerStats
+    )
+    assert(ownerStatsAccount.author.equals(owner.publicKey))
+    assert(ownerStatsAccount.count.toNumber() === 1)
+    const delegateStatsAfter = await program.account.authorStats.fetch(
+      authorStats
+    )
+    assert(delegateStatsAfter.count.eq(delegateStatsBefore.count))
+    const nonceAccount = await program.account.delegateNonce.fetch(
+      delegateNonce
+    )
+    assert(nonceAccount.nonce.toNumber() === 1)
+
+    // Replaying the used authorization fails
+    try {
+      await appendDelegated(0)
+      assert.fail("replayed authorization should fail")
+    } catch (error) {
+      assert(error.error.errorCode.code === "NonceReused")
+    }
+  })
+
+  it("List Recent", async () => {
+    const { raw } = await program.methods
+      .listRecent()
+      .accounts({ config: config })
+      .simulate()
+
+    // Decode the returned vec of (index u32, leaf_node [u8; 32]) entries, newest first
+    const returnLog = raw.find((log) => log.startsWith("Program return:"))
+    const returnData = Buffer.from(returnLog.split(" ")[3], "base64")
+    const count = returnData.readUInt32LE(0)
+    assert(count === 8)
+    for (let i = 0; i < count; i++) {
+      const offset = 4 + i * 36
+      const index = appendedLeaves.length - 1 - i
+      assert(returnData.readUInt32LE(offset) === index)
+      assert(
+        returnData.subarray(offset + 4, offset + 36).equals(appendedLeaves[index])
+      )
+    }
+  })
+
+  it("Set Leaf Domain", async () => {
+    const setLeafDomain = (tag: number[]) =>
+      program.methods
+        .setLeafDomain(tag)
+        .accounts({ config: config, merkleTree: merkleTree.publicKey })
+        .rpc()
+
+    // Notes appended after the rotation have the tag mixed into their leaf node
+    const tag = [1, 2, 3, 4]
+    await setLeafDomain(tag)
+    const configAccount = await program.account.noteTreeConfig.fetch(config)
+    assert.deepEqual(configAccount.leafDomain, tag)
+    assert.deepEqual(
+      Array.from(
+        configAccount.leafDomains[configAccount.leafDomainCount - 1].tag
+      ),
+      tag
+    )
+
+    const note = "tagged note"
+    const txSignature = await program.methods
+      .appendNote(note)
+      .accounts({
+        config: config,
+        merkleTree: merkleTree.publicKey,
+        treeAuthority: treeAuthority,
+        logWrapper: SPL_NOOP_PROGRAM_ID,
+        compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+        allowlist: allowlist,
+        authorStats: authorStats,
+      })
+      .rpc()
+    const noteLog = await getNoteLog(connection, txSignature)
+    appendedLeaves.push(Buffer.from(noteLog.leafNode))
+    assert.deepEqual(Array.from(noteLog.leafDomain), tag)
+    assert(
+      Buffer.from(noteLog.leafNode).equals(
+        domainLeaf(Buffer.from(tag), textLeaf(note))
+      )
+    )
+    assert(noteLogLeaf(noteLog, 1).equals(Buffer.from(noteLog.leafNode)))
+    const taggedIndex = appendedLeaves.length - 1
+
+    const verifyTagged = async () => {
+      const { root, proofAccounts } = await getProof(taggedIndex)
+      await program.methods
+        .verifyNote(taggedIndex, root, note, false)
+        .accounts({
+          config: config,
+          merkleTree: merkleTree.publicKey,
+          compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+        })
+        .remainingAccounts(proofAccounts)
+        .rpc()
+    }
+    await verifyTagged()
+
+    // Replacing a tagged leaf recomputes both the old and the new leaf with the tag
+    const body = "tagged titled body"
+    const titledSignature = await program.methods
+      .appendTitledNote("tagged title", body)
+      .accounts({
+        config: config,
+        merkleTree: merkleTree.publicKey,
+        treeAuthority: treeAuthority,
+        logWrapper: SPL_NOOP_PROGRAM_ID,
+        compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+        allowlist: allowlist,
+        authorStats: authorStats,
+      })
+      .rpc()
+    const titledLog = await getNoteLog(connection, titledSignature)
+    const titledIndex = appendedLeaves.length
+    appendedLeaves.push(Buffer.from(titledLog.leafNode))
+    const titled = await getProof(titledIndex)
+    await program.methods
+      .updateTitle(titledIndex, titled.root, "tagged title", body, "retitled")
+      .accounts({
+        config: config,
+        treeAuthority: treeAuthority,
+        merkleTree: merkleTree.publicKey,
+        logWrapper: SPL_NOOP_PROGRAM_ID,
+        compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+      })
+      .remainingAccounts(titled.proofAccounts)
+      .rpc()
+    appendedLeaves[titledIndex] = domainLeaf(
+      Buffer.from(tag),
+      leafHash(LEAF_TAG_TITLED, frameNamespaced("retitled", body))
+    )
+    const { root } = await getProof(titledIndex)
+    const offChainTree = MerkleTree.sparseMerkleTreeFromLeaves(
+      appendedLeaves,
+      maxDepthSizePair.maxDepth
+    )
+    assert(offChainTree.root.equals(Buffer.from(root)))
+
+    // A second rotation keeps the earlier tag for the leaves appended under it
+    const secondTag = [5, 6, 7, 8]
+    await setLeafDomain(secondTag)
+    const second = await program.account.noteTreeConfig.fetch(config)
+    assert(second.leafDomainCount === configAccount.leafDomainCount + 1)
+    await verifyTagged()
+    const secondNote = "second tagged note"
+    const secondSignature = await program.methods
+      .appendNote(secondNote)
+      .accounts({
+        config: config,
+        merkleTree: merkleTree.publicKey,
+        treeAuthority: treeAuthority,
+        logWrapper: SPL_NOOP_PROGRAM_ID,
+        compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+        allowlist: allowlist,
+        authorStats: authorStats,
+      })
+      .rpc()
+    const secondLog = await getNoteLog(connection, secondSignature)
+    appendedLeaves.push(Buffer.from(secondLog.leafNode))
+    assert(
+      Buffer.from(secondLog.leafNode).equals(
+        domainLeaf(Buffer.from(secondTag), textLeaf(secondNote))
+      )
+    )
+
+    // Rotating back to untagged keeps the tags being replaced in the history
+    await setLeafDomain([0, 0, 0, 0])
+    const rotated = await program.account.noteTreeConfig.fetch(config)
+    assert.deepEqual(rotated.leafDomain, [0, 0, 0, 0])
+    await verifyTagged()
+
+    // The history has one entry per rotation, starting at the first leaf appended under it
+    const count = rotated.leafDomainCount
+    const history = rotated.leafDomains.slice(count - 3, count)
+    assert.deepEqual(
+      history.map((entry) => Array.from(entry.tag)),
+      [tag, secondTag, [0, 0, 0, 0]]
+    )
+    assert(history[1].startIndex === history[0].startIndex + 2)
+    assert(history[2].startIndex === history[1].startIndex + 1)
+  })
+
+  it("Compute Estimates", async () => {
+    // Simulated, so the tree isn't changed. The largest note that fits in a transaction is the most expensive append
+    const note = "0".repeat(789)
+    const append = await program.methods
+      .appendNote(note)
+      .accounts({
+        config: config,
+        merkleTree: merkleTree.publicKey,
+        treeAuthority: treeAuthority,
+        logWrapper: SPL_NOOP_PROGRAM_ID,
+        compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+        allowlist: allowlist,
+        authorStats: authorStats,
+      })
+      .simulate()
+    assert(
+      computeUnitsConsumed(append.raw, program.programId) <= APPEND_CU_ESTIMATE
+    )
+
+    // Replace the title of the note updated by the Update Title test, with a full proof
+    const body = "titled note body"
+    const index = appendedLeaves.findIndex((leaf) =>
+      leaf.equals(leafHash(LEAF_TAG_TITLED, frameNamespaced("new title", body)))
+    )
+    const { root, proofAccounts } = await getProof(index)
+    const update = await program.methods
+      .updateTitle(index, root, "new title", body, "estimate title")
+      .accounts({
+        config: config,
+        treeAuthority: treeAuthority,
+        merkleTree: merkleTree.publicKey,
+        logWrapper: SPL_NOOP_PROGRAM_ID,
+        compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+      })
+      .remainingAccounts(proofAccounts)
+      .simulate()
+    assert(
+      computeUnitsConsumed(update.raw, program.programId) <=
+        updateCuEstimate(proofAccounts.length)
+    )
+
+    // The other estimates are simulated with the estimate as the compute limit, as clients would set it
+    const noteAccounts = {
+      config: config,
+      merkleTree: merkleTree.publicKey,
+      treeAuthority: treeAuthority,
+      logWrapper: SPL_NOOP_PROGRAM_ID,
+      compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+      allowlist: allowlist,
+      authorStats: authorStats,
+    }
+    const unitLimit = (units: number) =>
+      ComputeBudgetProgram.setComputeUnitLimit({ units })
+
+    // A batch of 8 appends
+    const batchNotes = Array.from({ length: 8 }, (_, i) => `estimate batch ${i}`)
+    const batch = await program.methods
+      .appendBatchSummarized(batchNotes)
+      .accounts(noteAccounts)
+      .preInstructions([unitLimit(batchAppendCuEstimate(batchNotes.length))])
+      .simulate()
+    assert(
+      computeUnitsConsumed(batch.raw, program.programId) <=
+        batchAppendCuEstimate(batchNotes.length)
+    )
+
+    // Deflating the longest note, varied so it doesn't compress away
+    const varied = Array.from({ length: 789 }, (_, i) =>
+      String.fromCharCode(33 + ((i * 7919) % 94))
+    ).join("")
+    const compressed = await program.methods
+      .appendNoteCompressed(varied)
+      .accounts(noteAccounts)
+      .preInstructions([unitLimit(COMPRESSED_APPEND_CU_ESTIMATE)])
+      .simulate()
+    assert(
+      computeUnitsConsumed(compressed.raw, program.programId) <=
+        COMPRESSED_APPEND_CU_ESTIMATE
+    )
+
+    // The longest note hashed MAX_HASH_ITERATIONS times, on a small tree hashing 64 times
+    const hashedTree = Keypair.generate()
+    const [hashedConfig] = PublicKey.findProgramAddressSync(
+      [Buffer.from("config"), hashedTree.publicKey.toBuffer()],
+      program.programId
+    )
+    const [hashedTreeAuthority] = PublicKey.findProgramAddressSync(
+      [hashedTree.publicKey.toBuffer()],
+      program.programId
+    )
+    const allocTreeIx = await createAllocTreeIx(
+      connection,
+      hashedTree.publicKey,
+      wallet.publicKey,
+      { maxDepth: 3, maxBufferSize: 8 },
+      0
+    )
+    await program.methods
+      .createNoteTree({
+        ...DEFAULT_TREE_CONFIG_INIT_ARGS,
+        maxDepth: 3,
+        maxBufferSize: 8,
+        hashIterations: 64,
+      })
+      .accounts({
+        config: hashedConfig,
+        merkleTree: hashedTree.publicKey,
+        treeAuthority: hashedTreeAuthority,
+        logWrapper: SPL_NOOP_PROGRAM_ID,
+        compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+      })
+      .remainingAccounts([globalConfigRemainingAccount])
+      .preInstructions([allocTreeIx])
+      .signers([hashedTree])
+      .rpc()
+    const hashed = await program.methods
+      .appendNote(note)
+      .accounts({
+        config: hashedConfig,
+        merkleTree: hashedTree.publicKey,
+        treeAuthority: hashedTreeAuthority,
+        logWrapper: SPL_NOOP_PROGRAM_ID,
+        compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+        allowlist: PublicKey.findProgramAddressSync(
+          [Buffer.from("allowlist"), hashedTree.publicKey.toBuffer()],
+          program.programId
+        )[0],
+        authorStats: PublicKey.findProgramAddressSync(
+          [
+            Buffer.from("author"),
+            hashedTree.publicKey.toBuffer(),
+            wallet.publicKey.toBuffer(),
+          ],
+          program.programId
+        )[0],
+      })
+      .simulate()
+    assert(
+      computeUnitsConsumed(hashed.raw, program.programId) <= APPEND_CU_ESTIMATE
+    )
+
+    // Finalizing a staged note of the max staged length, 8KB. Finalized for real, since the
+    // staging account is written over several transactions
+    const stagedNote = "8".repeat(8192)
+    const [staging] = PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("staging"),
+        merkleTree.publicKey.toBuffer(),
+        wallet.publicKey.toBuffer(),
+      ],
+      program.programId
+    )
+    await program.methods
+      .initStaging(stagedNote.length)
+      .accounts({
+        config: config,
+        staging: staging,
+        merkleTree: merkleTree.publicKey,
+      })
+      .rpc()
+    const stagedBytes = Buffer.from(stagedNote)
+    for (let offset = 0; offset < stagedBytes.length; offset += 800) {
+      await program.methods
+        .writeStaging(stagedBytes.subarray(offset, offset + 800))
+        .accounts({ staging: staging, merkleTree: merkleTree.publicKey })
+        .rpc()
+    }
+    const stagedSignature = await program.methods
+      .finalizeStagedNote()
+      .accounts({ noteAccounts: noteAccounts, staging: staging })
+      .preInstructions([unitLimit(STAGED_APPEND_CU_ESTIMATE)])
+      .rpc()
+    appendedLeaves.push(textLeaf(stagedNote))
+    const stagedTx = await connection.getTransaction(stagedSignature, {
+      commitment: "confirmed",
+    })
+    assert(
+      computeUnitsConsumed(stagedTx.meta.logMessages, program.programId) <=
+        STAGED_APPEND_CU_ESTIMATE
+    )
+  })
+
+  it("Strict Text", async () => {
+    const setStrictText = (strictText: boolean) =>
+      program.methods
+        .setStrictText(strictText)
+        .accounts({ config: config, merkleTree: merkleTree.publicKey })
+        .rpc()
+    const appendNote = (note: string) =>
+      program.methods
+        .appendNote(note)
+        .accounts({
+          config: config,
+          merkleTree: merkleTree.publicKey,
+          treeAuthority: treeAuthority,
+          logWrapper: SPL_NOOP_PROGRAM_ID,
+          compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+          allowlist: allowlist,
+          authorStats: authorStats,
+        })
+        .rpc()
+
+    // Strict text mode is off by default
+    const configAccount = await program.account.noteTreeConfig.fetch(config)
+    assert(configAccount.strictText === false)
+
+    // A null byte and an escape sequence are rejected in strict text mode
+    await setStrictText(true)
+    for (const note of ["null\0byte", "\x1b[31mred\x1b[0m"]) {
+      try {
+        await appendNote(note)
+        assert.fail("note with control characters should fail")
+      } catch (error) {
+        assert(error.error.errorCode.code === "InvalidCharacters")
+      }
+    }
+
+    // Every other logged text field is checked too: a title, a namespace, metadata, and a new title
+    const noteAccounts = {
+      config: config,
+      merkleTree: merkleTree.publicKey,
+      treeAuthority: treeAuthority,
+      logWrapper: SPL_NOOP_PROGRAM_ID,
+      compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+      allowlist: allowlist,
+      authorStats: authorStats,
+    }
+    const dirtyText = "dirty\x1b[31m"
+    const { root } = await getProof(0)
+    for (const method of [
+      program.methods.appendTitledNote(dirtyText, "clean body"),
+      program.methods.appendNamespacedNote(dirtyText, "clean note"),
+      program.methods.appendWithMetadata("clean note", [
+        { key: dirtyText, value: "clean value" },
+      ]),
+    ]) {
+      try {
+        await method.accounts(noteAccounts).rpc()
+        assert.fail("note with control characters should fail")
+      } catch (error) {
+        assert(error.error.errorCode.code === "InvalidCharacters")
+      }
+    }
+    try {
+      await program.methods
+        .updateTitle(0, root, "old title", "clean body", dirtyText)
+        .accounts({
+          config: config,
+          treeAuthority: treeAuthority,
+          merkleTree: merkleTree.publicKey,
+          logWrapper: SPL_NOOP_PROGRAM_ID,
+          compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+        })
+        .rpc()
+      assert.fail("new title with control characters should fail")
+    } catch (error) {
+      assert(error.error.errorCode.code === "InvalidCharacters")
+    }
+
+    // Tabs and newlines are allowed, checked by simulating so the tree isn't changed
+    await program.methods
+      .appendNote("line one\n\tline two")
+      .accounts({
+        config: config,
+        merkleTree: merkleTree.publicKey,
+        treeAuthority: treeAuthority,
+        logWrapper: SPL_NOOP_PROGRAM_ID,
+        compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+        allowlist: allowlist,
+        authorStats: authorStats,
+      })
+      .simulate()
+    await setStrictText(false)
+  })
+
+  it("Debug Tree Authority", async () => {
+    const { raw } = await program.methods
+      .debugTreeAuthority()
+      .accounts({ config: config, merkleTree: merkleTree.publicKey })
+      .simulate()
+
+    // Decode the returned seed, bump u8 and tree authority, and cross-check the client derivation
+    const returnLog = raw.find((log) => log.startsWith("Program return:"))
+    const returnData = Buffer.from(returnLog.split(" ")[3], "base64")
+    const seed = new PublicKey(returnData.subarray(0, 32))
+    const bump = returnData[32]
+    const derived = new PublicKey(returnData.subarray(33, 65))
+    const [clientTreeAuthority, clientBump] = PublicKey.findProgramAddressSync(
+      [merkleTree.publicKey.toBuffer()],
+      program.programId
+    )
+    assert(seed.equals(merkleTree.publicKey))
+    assert(bump === clientBump)
+    assert(derived.equals(clientTreeAuthority))
+    assert(derived.equals(treeAuthority))
+  })
+
+  it("Append Reply", async () => {
+    const parentLeaf = appendedLeaves[0]
+    const [replyCount] = PublicKey.findProgramAddressSync(
+      [Buffer.from("replies"), merkleTree.publicKey.toBuffer(), parentLeaf],
+      program.programId
+    )
+    const getReplyCount = async () => {
+      const { raw } = await program.methods
+        .getReplyCount(Array.from(parentLeaf))
+        .accounts({ replyCount: replyCount, merkleTree: merkleTree.publicKey })
+        .simulate()
+      const returnLog = raw.find((log) => log.startsWith("Program return:"))
+      return Number(
+        Buffer.from(returnLog.split(" ")[3], "base64").readBigUInt64LE(0)
+      )
+    }
+
+    // The parent has no replies, and no reply count account, until the first reply
+    assert((await getReplyCount()) === 0)
+    assert((await connection.getAccountInfo(replyCount)) === null)
+
+    for (const note of ["first reply", "second reply"]) {
+      const txSignature = await program.methods
+        .appendReply(Array.from(parentLeaf), note)
+        .accounts({
+          noteAccounts: {
+            config: config,
+            merkleTree: merkleTree.publicKey,
+            treeAuthority: treeAuthority,
+            logWrapper: SPL_NOOP_PROGRAM_ID,
+            compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+            allowlist: allowlist,
+            authorStats: authorStats,
+          },
+          replyCount: replyCount,
+        })
+        .rpc()
+      const noteLog = await getNoteLog(connection, txSignature)
+      appendedLeaves.push(Buffer.from(noteLog.leafNode))
+      // The reply is hashed with the parent leaf node
+      assert(Buffer.from(noteLog.parentLeaf).equals(parentLeaf))
+      assert(!noteLog.prevLeaf && !noteLog.refLeaf)
+      assert(noteLogLeaf(noteLog, 1).equals(Buffer.from(noteLog.leafNode)))
+      // A chained or dependent note of the same leaf and note has a different leaf node
+      for (const linked of [{ prevLeaf: parentLeaf }, { refLeaf: parentLeaf }]) {
+        const other = new NoteLog({ ...noteLog, parentLeaf: undefined, ...linked })
+        assert(!noteLogLeaf(other, 1).equals(Buffer.from(noteLog.leafNode)))
+      }
+    }
+    assert((await getReplyCount()) === 2)
+
+    // The parent isn't verified, so a reply to a leaf that isn't in the tree is appended and counted too
+    const unknownParent = textLeaf("never appended parent")
+    const [unknownReplyCount] = PublicKey.findProgramAddressSync(
+      [Buffer.from("replies"), merkleTree.publicKey.toBuffer(), unknownParent],
+      program.programId
+    )
+    assert(!appendedLeaves.some((leaf) => leaf.equals(unknownParent)))
+    const txSignature = await program.methods
+      .appendReply(Array.from(unknownParent), "reply to nothing")
+      .accounts({
+        noteAccounts: {
+          config: config,
+          merkleTree: merkleTree.publicKey,
+          treeAuthority: treeAuthority,
+          logWrapper: SPL_NOOP_PROGRAM_ID,
+          compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+          allowlist: allowlist,
+          authorStats: authorStats,
+        },
+        replyCount: unknownReplyCount,
+      })
+      .rpc()
+    const noteLog = await getNoteLog(connection, txSignature)
+    appendedLeaves.push(Buffer.from(noteLog.leafNode))
+    const unknownCount = await program.account.replyCount.fetch(
+      unknownReplyCount
+    )
+    assert(unknownCount.count.toNumber() === 1)
+  })
+
+  it("Append Timestamped Note", async () => {
+    const appendTimestamped = (note: string) =>
+      program.methods
+        .appendTimestampedNote(note)
+        .accounts({
+          config: config,
+          merkleTree: merkleTree.publicKey,
+          treeAuthority: treeAuthority,
+          logWrapper: SPL_NOOP_PROGRAM_ID,
+          compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+          allowlist: allowlist,
+          authorStats: authorStats,
+        })
+        .rpc()
+
+    // Each note is stamped with the wall clock time, never before the previous one
+    let lastTimestamp = 0
+    for (const note of ["audit entry 1", "audit entry 2"]) {
+      const noteLog = await getNoteLog(connection, await appendTimestamped(note))
+      appendedLeaves.push(Buffer.from(noteLog.leafNode))
+      assert(keccak256(note) === Buffer.from(noteLog.leafNode).toString("hex"))
+      const createdAt = noteLog.createdAt.toNumber()
+      assert(createdAt >= lastTimestamp && createdAt > 0)
+      const configAccount = await program.account.noteTreeConfig.fetch(config)
+      assert(configAccount.lastTimestamp.toNumber() === createdAt)
+      lastTimestamp = createdAt
+    }
+  })
+
+  it("Verify Note Inline", async () => {
+    // The proof is passed as an argument, built from the leaves appended by the tests
+    const index = 0
+    const { proof } = MerkleTree.sparseMerkleTreeFromLeaves(
+      appendedLeaves,
+      maxDepthSizePair.maxDepth
+    ).getProof(index)
+    const { root } = await getProof(index)
+    const verifyInline = (note: string, proofNodes: Buffer[]) =>
+      program.methods
+        .verifyNoteInline(
+          index,
+          root,
+          note,
+          proofNodes.map((node) => Array.from(node))
+        )
+        .accounts({
+          config: config,
+          merkleTree: merkleTree.publicKey,
+          compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+        })
+        .rpc()
+
+    await verifyInline("hello world", proof)
+
+    // A proof shorter than the depth of the tree is rejected
+    try {
+      await verifyInline("hello world", proof.slice(1))
+      assert.fail("short proof should fail")
+    } catch (error) {
+      assert(error.error.errorCode.code === "InvalidProofLength")
+    }
+
+    // A note that isn't stored at the index doesn't recompute the root
+    try {
+      await verifyInline("not the note", proof)
+      assert.fail("unverified note should fail")
+    } catch (error) {
+      assert(error.error.errorCode.code === "LeafMismatch")
+    }
+  })
+
+  it("Auto Pin Interval", async () => {
+    const setAutoPinInterval = (interval: number) =>
+      program.methods
+        .setAutoPinInterval(interval)
+        .accounts({ config: config, merkleTree: merkleTree.publicKey })
+        .rpc()
+    const pinnedNoteAt = (index: number) =>
+      PublicKey.findProgramAddressSync(
+        [
+          Buffer.from("verified"),
+          merkleTree.publicKey.toBuffer(),
+          new anchor.BN(index).toArrayLike(Buffer, "le", 4),
+        ],
+        program.programId
+      )[0]
+    const appendNote = (note: string, remainingAccounts = []) =>
+      program.methods
+        .appendNote(note)
+        .accounts({
+          config: config,
+          merkleTree: merkleTree.publicKey,
+          treeAuthority: treeAuthority,
+          logWrapper: SPL_NOOP_PROGRAM_ID,
+          compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+          allowlist: allowlist,
+          authorStats: authorStats,
+        })
+        .remainingAccounts(remainingAccounts)
+        .rpc()
+
+    // Pin once the note count reaches the next multiple of the interval, the very next note
+    const { noteCount } = await program.account.noteTreeConfig.fetch(config)
+    await setAutoPinInterval(noteCount.toNumber() + 1)
+
+    // The pinned note account is required when the note is pinned
+    const pinnedIndex = appendedLeaves.length
+    const pinnedNote = pinnedNoteAt(pinnedIndex)
+    try {
+      await appendNote("auto pinned note")
+      assert.fail("auto pin without the pinned note account should fail")
+    } catch (error) {
+      assert(error.error.errorCode.code === "InvalidPinAccount")
+    }
+
+    // The Nth note is pinned, with the payer as the author
+    await appendNote("auto pinned note", [
+      { pubkey: pinnedNote, isSigner: false, isWritable: true },
+    ])
+    appendedLeaves.push(textLeaf("auto pinned note"))
+    const pinnedNoteAccount = await program.account.note.fetch(pinnedNote)
+    assert(pinnedNoteAccount.note === "auto pinned note")
+    assert(pinnedNoteAccount.index === pinnedIndex)
+    assert(pinnedNoteAccount.author.equals(wallet.publicKey))
+    assert(
+      Buffer.from(pinnedNoteAccount.leafNode).equals(appendedLeaves[pinnedIndex])
+    )
+
+    // The next note isn't pinned
+    await appendNote("unpinned note")
+    appendedLeaves.push(textLeaf("unpinned note"))
+    assert(
+      (await connection.getAccountInfo(pinnedNoteAt(pinnedIndex + 1))) === null
+    )
+
+    // A pda pre-funded with lamports before the Nth append doesn't block the pin
+    const next = await program.account.noteTreeConfig.fetch(config)
+    await setAutoPinInterval(next.noteCount.toNumber() + 1)
+    const fundedIndex = appendedLeaves.length
+    const fundedNote = pinnedNoteAt(fundedIndex)
+    const fundTx = new Transaction().add(
+      SystemProgram.transfer({
+        fromPubkey: wallet.publicKey,
+        toPubkey: fundedNote,
+        lamports: 1000,
+      })
+    )
+    await sendAndConfirmTransaction(connection, fundTx, [wallet.payer])
+    await appendNote("pre-funded pin", [
+      { pubkey: fundedNote, isSigner: false, isWritable: true },
+    ])
+    appendedLeaves.push(textLeaf("pre-funded pin"))
+    const fundedNoteAccount = await program.account.note.fetch(fundedNote)
+    assert(fundedNoteAccount.note === "pre-funded pin")
+    assert(fundedNoteAccount.index === fundedIndex)
+    await setAutoPinInterval(0)
+  })
+
+  it("Try Append Note", async () => {
+    const tryAppend = (note: string) =>
+      program.methods.tryAppendNote(note).accounts({
+        config: config,
+        merkleTree: merkleTree.publicKey,
+        treeAuthority: treeAuthority,
+        logWrapper: SPL_NOOP_PROGRAM_ID,
+        compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+        allowlist: allowlist,
+        authorStats: authorStats,
+      })
+    const status = async (txSignature: string) =>
+      (await getReturnData(connection, txSignature, program.programId))[0]
+
+    // A rejected note returns its status without failing the transaction or appending
+    const before = await program.account.noteTreeConfig.fetch(config)
+    assert((await status(await tryAppend("").rpc())) === 1) // NoteEmpty
+    await program.methods
+      .setStrictText(true)
+      .accounts({ config: config, merkleTree: merkleTree.publicKey })
+      .rpc()
+    assert((await status(await tryAppend("bell\x07").rpc())) === 3) // InvalidCharacters
+    await program.methods
+      .setStrictText(false)
+      .accounts({ config: config, merkleTree: merkleTree.publicKey })
+      .rpc()
+    const after = await program.account.noteTreeConfig.fetch(config)
+    assert(after.noteCount.eq(before.noteCount))
+
+    // In a batch of mixed-validity notes in one transaction, the valid note is still appended
+    const note = "soft fail batch note"
+    await tryAppend(note)
+      .preInstructions([await tryAppend("").instruction()])
+      .rpc()
+    appendedLeaves.push(Buffer.from(keccak256.digest(note)))
+    const treeAccount = await ConcurrentMerkleTreeAccount.fromAccountAddress(
+      connection,
+      merkleTree.publicKey
+    )
+    assert(
+      treeAccount.tree.rightMostPath.leaf
+        .toBuffer()
+        .equals(appendedLeaves[appendedLeaves.length - 1])
+    )
+  })
+
+  it("Fuzz Leaf Construction", async () => {
+    // Seeded, so a failing case reproduces. Change the seed to explore other inputs
+    const seed = 0x5eed
+    // Short strings over an alphabet with multibyte characters, so byte and character boundaries differ
+    const alphabet = ["a", "b", " ", "|", "\n", "é", "→", "0"]
+    const textArbitrary = (maxLength: number) =>
+      fc
+        .array(fc.constantFrom(...alphabet), { minLength: 1, maxLength })
+        .map((characters) => characters.join(""))
+    const leafArbitrary = fc
+      .uint8Array({ minLength: 32, maxLength: 32 })
+      .map((bytes) => Buffer.from(bytes))
+
+    // A note log with every field at its default, like `NoteLog::base`
+    const noteLogOf = (fields: Partial<NoteLog>) =>
+      new NoteLog({
+        version: 13,
+        leafNode: new Uint8Array(32),
+        note: "",
+        data: new Uint8Array(0),
+        priority: 0,
+        value: new anchor.BN(0),
+        genesis: 0,
+        namespace: "",
+        prevLeaf: undefined,
+        title: "",
+        rawLeaf: 0,
+        contentType: 0,
+        metadata: [],
+        owner: undefined,
+        leafDomain: new Uint8Array(4),
+        createdAt: new anchor.BN(0),
+        parentLeaf: undefined,
+        refLeaf: undefined,
+        ...fields,
+      })
+
+    // Every variant's leaves share one map keyed by leaf node, so distinct inputs never give the
+    // same leaf node within a variant or across variants. Each run builds every variant from the
+    // same strings and leaf, and the bytes and text variants also from the encodings the other
+    // variants hash, such as the framed namespace and note, the previous leaf and note, or the
+    // bytes of a raw leaf. The chained, reply and dependent variants share the leaf and note.
+    // Framed inputs are also split at every boundary of the same characters, such as
+    // ("ab", "c") and ("a", "bc")
+    const leaves = new Map<string, string>()
+    const record = (variant: string, fields: Partial<NoteLog>) => {
+      const leaf = noteLogLeaf(noteLogOf(fields), 1).toString("hex")
+      const key = JSON.stringify([
+        variant,
+        ...Object.values(fields).map((field) =>
+          typeof field === "string" || typeof field === "number"
+            ? String(field)
+            : Buffer.from(field as Uint8Array).toString("hex")
+        ),
+      ])
+      assert(
+        (leaves.get(leaf) ?? key) === key,
+        `inputs ${leaves.get(leaf)} and ${key} collide`
+      )
+      leaves.set(leaf, key)
+    }
+    fc.assert(
+      fc.property(
+        textArbitrary(12),
+        textArbitrary(12),
+        leafArbitrary,
+        (prefix, note, leaf) => {
+          const joined = prefix + note
+          record("text", { note })
+          record("text", { note: joined })
+          record("bytes", { data: Buffer.from(note) })
+          record("bytes", { data: Buffer.from(joined) })
+          record("bytes", { data: frameNamespaced(prefix, note) })
+          record("bytes", { data: Buffer.concat([leaf, Buffer.from(note)]) })
+          record("bytes", { data: leaf })
+          record("chained", { prevLeaf: leaf, note })
+          record("reply", { parentLeaf: leaf, note })
+          record("dependent", { refLeaf: leaf, note })
+          record("raw", { rawLeaf: 1, note: leaf.toString("hex") })
+          record("delegated", { owner: leaf, note })
+          const characters = Array.from(joined)
+          for (let split = 1; split < characters.length; split++) {
+            const framedPrefix = characters.slice(0, split).join("")
+            const framedNote = characters.slice(split).join("")
+            record("namespaced", { namespace: framedPrefix, note: framedNote })
+            record("titled", { title: framedPrefix, note: framedNote })
+          }
+        }
+      ),
+      { seed, numRuns: 200 }
+    )
+
+    // Random notes appended through each variant log the leaf node reconstructed off-chain
+    const noteAccounts = {
+      config: config,
+      merkleTree: merkleTree.publicKey,
+      treeAuthority: treeAuthority,
+      logWrapper: SPL_NOOP_PROGRAM_ID,
+      compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+      allowlist: allowlist,
+      authorStats: authorStats,
+    }
+    const textIndices: [number, string][] = []
+    const [texts] = fc.sample(
+      fc.array(textArbitrary(16), { minLength: 16, maxLength: 16 }),
+      { seed, numRuns: 1 }
+    )
+    const randomText = () => texts.pop()
+    for (let i = 0; i < 2; i++) {
+      // The reply's parent is the latest leaf, its reply count pda derived from it
+      const parentLeaf = appendedLeaves[appendedLeaves.length - 1]
+      const [replyCount] = PublicKey.findProgramAddressSync(
+        [Buffer.from("replies"), merkleTree.publicKey.toBuffer(), parentLeaf],
+        program.programId
+      )
+      const appends = [
+        {
+          text: true,
+          method: program.methods
+            .appendNote(randomText())
+            .accounts(noteAccounts),
+        },
+        {
+          text: false,
+          method: program.methods
+            .appendNamespacedNote(randomText(), randomText())
+            .accounts(noteAccounts),
+        },
+        {
+          text: false,
+          method: program.methods
+            .appendTitledNote(randomText(), randomText())
+            .accounts(noteAccounts),
+        },
+        {
+          text: false,
+          method: program.methods
+            .appendChainedNote(randomText())
+            .accounts(noteAccounts),
+        },
+        {
+          text: false,
+          method: program.methods
+            .appendBytes(Buffer.from(randomText()))
+            .accounts(noteAccounts),
+        },
+        {
+          text: false,
+          method: program.methods
+            .appendReply(Array.from(parentLeaf), randomText())
+            .accounts({ noteAccounts: noteAccounts, replyCount: replyCount }),
+        },
+      ]
+      for (const { text, method } of appends) {
+        const txSignature = await method.rpc()
+        const noteLog = await getNoteLog(connection, txSignature)
+        assert(noteLogLeaf(noteLog, 1).equals(Buffer.from(noteLog.leafNode)))
+        if (text) {
+          textIndices.push([appendedLeaves.length, noteLog.note])
+        }
+        appendedLeaves.push(Buffer.from(noteLog.leafNode))
+      }
+    }
+
+    // The tree rebuilt from the reconstructed leaves has the on-chain root, so every proof verifies
+    const offChainTree = MerkleTree.sparseMerkleTreeFromLeaves(
+      appendedLeaves,
+      maxDepthSizePair.maxDepth
+    )
+    const { root } = await getProof(0)
+    assert(offChainTree.root.equals(Buffer.from(root)))
+    for (let index = 0; index < appendedLeaves.length; index++) {
+      assert(MerkleTree.verify(offChainTree.root, offChainTree.getProof(index)))
+    }
+
+    // And the text notes verify on-chain
+    for (const [index, note] of textIndices) {
+      await program.methods
+        .verifyNoteInline(
+          index,
+          root,
+          note,
+          offChainTree.getProof(index).proof.map((node) => Array.from(node))
+        )
+        .accounts({
+          config: config,
+          merkleTree: merkleTree.publicKey,
+          compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+        })
+        .rpc()
+    }
+  })
+
+  it("Swap Notes", async () => {
+    // A depth 3 tree keeps both full proofs within the transaction size limit
+    const swapTree = Keypair.generate()
+    const [swapConfig] = PublicKey.findProgramAddressSync(
+      [Buffer.from("config"), swapTree.publicKey.toBuffer()],
+      program.programId
+    )
+    const [swapTreeAuthority] = PublicKey.findProgramAddressSync(
+      [swapTree.publicKey.toBuffer()],
+      program.programId
+    )
+    const allocTreeIx = await createAllocTreeIx(
+      connection,
+      swapTree.publicKey,
+      wallet.publicKey,
+      { maxDepth: 3, maxBufferSize: 8 },
+      0
+    )
+    await program.methods
+      .createNoteTree({
+        ...DEFAULT_TREE_CONFIG_INIT_ARGS,
+        maxDepth: 3,
+        maxBufferSize: 8,
+      })
+      .accounts({
+        config: swapConfig,
+        merkleTree: swapTree.publicKey,
+        treeAuthority: swapTreeAuthority,
+        logWrapper: SPL_NOOP_PROGRAM_ID,
+        compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+      })
+      .remainingAccounts([globalConfigRemainingAccount])
+      .preInstructions([allocTreeIx])
+      .signers([swapTree])
+      .rpc()
+
+    const notes = ["first", "second", "third", "fourth", "fifth"]
+    for (const note of notes) {
+      await program.methods
+        .appendNote(note)
+        .accounts({
+          config: swapConfig,
+          merkleTree: swapTree.publicKey,
+          treeAuthority: swapTreeAuthority,
+          logWrapper: SPL_NOOP_PROGRAM_ID,
+          compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+          allowlist: PublicKey.findProgramAddressSync(
+            [Buffer.from("allowlist"), swapTree.publicKey.toBuffer()],
+            program.programId
+          )[0],
+          authorStats: PublicKey.findProgramAddressSync(
+            [
+              Buffer.from("author"),
+              swapTree.publicKey.toBuffer(),
+              wallet.publicKey.toBuffer(),
+            ],
+            program.programId
+          )[0],
+        })
+        .rpc()
+    }
+
+    // Both proofs are against the same root, the proof of index a followed by the proof of index b
+    const swapNotes = (
+      indexA: number,
+      indexB: number,
+      noteA: string,
+      noteB: string
+    ) => {
+      const offChainTree = MerkleTree.sparseMerkleTreeFromLeaves(
+        notes.map((note) => textLeaf(note)),
+        3
+      )
+      const proofAccounts = [indexA, indexB].flatMap((index) =>
+        offChainTree.getProof(index).proof.map((node) => ({
+          pubkey: new PublicKey(node),
+          isSigner: false,
+          isWritable: false,
+        }))
+      )
+      return program.methods
+        .swapNotes(indexA, indexB, Array.from(offChainTree.root), noteA, noteB)
+        .accounts({
+          authority: wallet.publicKey,
+          config: swapConfig,
+          treeAuthority: swapTreeAuthority,
+          merkleTree: swapTree.publicKey,
+          logWrapper: SPL_NOOP_PROGRAM_ID,
+          compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+        })
+        .remainingAccounts(proofAccounts)
+    }
+
+    // Listen for the NotesSwapped events emitted by the swaps
+    const events = []
+    const listener = program.addEventListener("NotesSwapped", (event) => {
+      events.push(event)
+    })
+
+    // Swap notes on both sides of the root, so the second proof is fast-forwarded over the first replace.
+    // Simulated first to check the swap stays within its compute estimate, with full depth 3 proofs
+    const simulated = await swapNotes(1, 4, "second", "fifth").simulate()
+    assert(
+      computeUnitsConsumed(simulated.raw, program.programId) <= swapCuEstimate(3)
+    )
+    await swapNotes(1, 4, "second", "fifth").rpc()
+    ;[notes[1], notes[4]] = [notes[4], notes[1]]
+    const treeAccount = await ConcurrentMerkleTreeAccount.fromAccountAddress(
+      connection,
+      swapTree.publicKey
+    )
+    assert(
+      treeAccount
+        .getCurrentRoot()
+        .equals(
+          MerkleTree.sparseMerkleTreeFromLeaves(
+            notes.map((note) => textLeaf(note)),
+            3
+          ).root
+        )
+    )
+
+    // Sibling leaves swap too, where the proofs differ at the leaf level
+    await swapNotes(2, 3, "third", "fourth").rpc()
+    ;[notes[2], notes[3]] = [notes[3], notes[2]]
+
+    for (let i = 0; i < 20 && events.length < 2; i++) {
+      await new Promise((resolve) => setTimeout(resolve, 100))
+    }
+    await program.removeEventListener(listener)
+    assert(events.length === 2)
+    // One event per swap, with the note moved to each index
+    assert(events[0].tree.equals(swapTree.publicKey))
+    assert(events[0].indexA === 1 && events[0].indexB === 4)
+    assert(Buffer.from(events[0].leafA).equals(textLeaf("fifth")))
+    assert(Buffer.from(events[0].leafB).equals(textLeaf("second")))
+    assert(events[1].indexA === 2 && events[1].indexB === 3)
+    assert(Buffer.from(events[1].leafA).equals(textLeaf("fourth")))
+
+    // If either note doesn't match, nothing is swapped
+    try {
+      await swapNotes(0, 1, "first", "second").rpc()
+      assert.fail("mismatched note should fail")
+    } catch (error) {
+      assert(error.error.errorCode.code === "LeafMismatch")
+    }
+    try {
+      await swapNotes(0, 0, "first", "first").rpc()
+      assert.fail("same index should fail")
+    } catch (error) {
+      assert(error.error.errorCode.code === "SameIndex")
+    }
+
+    // Inline verification needs the current root, so a replaced note doesn't verify against the
+    // root from before the replace, even though that root is still in the change log buffer
+    const verifyInline = (index: number, note: string, leaves: string[]) => {
+      const offChainTree = MerkleTree.sparseMerkleTreeFromLeaves(
+        leaves.map((note) => textLeaf(note)),
+        3
+      )
+      return program.methods
+        .verifyNoteInline(
+          index,
+          Array.from(offChainTree.root),
+          note,
+          offChainTree.getProof(index).proof.map((node) => Array.from(node))
+        )
+        .accounts({
+          config: swapConfig,
+          merkleTree: swapTree.publicKey,
+          compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+        })
+        .rpc()
+    }
+    const beforeSwap = [...notes]
+    ;[beforeSwap[2], beforeSwap[3]] = [beforeSwap[3], beforeSwap[2]]
+    try {
+      await verifyInline(2, "third", beforeSwap)
+      assert.fail("replaced note against the old root should fail")
+    } catch (error) {
+      assert(error.error.errorCode.code === "RootNotCurrent")
+    }
+    await verifyInline(2, "fourth", notes)
+  })
+
+  it("Wrong Tree Account Size", async () => {
+    // Allocate a depth 3 tree account with a canopy of depth 1
+    const createTree = async (canopyDepth: number) => {
+      const tree = Keypair.generate()
+      const allocTreeIx = await createAllocTreeIx(
+        connection,
+        tree.publicKey,
+        wallet.publicKey,
+        { maxDepth: 3, maxBufferSize: 8 },
+        1
+      )
+      return program.methods
+        .createNoteTree({
+          ...DEFAULT_TREE_CONFIG_INIT_ARGS,
+          maxDepth: 3,
+          maxBufferSize: 8,
+          canopyDepth,
+        })
+        .accounts({
+          config: PublicKey.findProgramAddressSync(
+            [Buffer.from("config"), tree.publicKey.toBuffer()],
+            program.programId
+          )[0],
+          merkleTree: tree.publicKey,
+          treeAuthority: PublicKey.findProgramAddressSync(
+            [tree.publicKey.toBuffer()],
+            program.programId
+          )[0],
+          logWrapper: SPL_NOOP_PROGRAM_ID,
+          compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+        })
+        .remainingAccounts([globalConfigRemainingAccount])
+        .preInstructions([allocTreeIx])
+        .signers([tree])
+        .rpc()
+    }
+
+    // Creating the tree without the canopy fails before the compression program is called,
+    // logging the expected size without the canopy and the actual size with it
+    try {
+      await createTree(0)
+      assert.fail("wrongly sized tree account should fail")
+    } catch (error) {
+      assert(error.error.errorCode.code === "WrongTreeAccountSize")
+      assert(
+        error.error.comparedValues[0] ===
+          getConcurrentMerkleTreeAccountSize(3, 8, 0).toString()
+      )
+      assert(
+        error.error.comparedValues[1] ===
+          getConcurrentMerkleTreeAccountSize(3, 8, 1).toString()
+      )
+    }
+
+    // With the canopy depth the account was allocated with, the tree is created
+    await createTree(1)
+  })
+
+  it("Leaf Variants Don't Collide", async () => {
+    const noteAccounts = {
+      config: config,
+      merkleTree: merkleTree.publicKey,
+      treeAuthority: treeAuthority,
+      logWrapper: SPL_NOOP_PROGRAM_ID,
+      compressionProgram: SPL_ACCOUNT_COMPRESSION_PROGRAM_ID,
+      allowlist: allowlist,
+      authorStats: authorStats,
+    }
+    const appendedLeaf = async (method) => {
+      const noteLog = await getNoteLog(
+        connection,
+        await method.accounts(noteAccounts).rpc()
+      )
+      assert(noteLogLeaf(noteLog, 1).equals(Buffer.from(noteLog.leafNode)))
+      appendedLeaves.push(Buffer.from(noteLog.leafNode))
+      return Buffer.from(noteLog.leafNode)
+    }
+
+    // Each pair hashes the same payload bytes through two variants, which the tag byte tells apart
+    const framed = frameNamespaced("ns", "note")
+    const namespaced = await appendedLeaf(
+      program.methods.appendNamespacedNote("ns", "note")
+    )
+    const framedText = await appendedLeaf(
+      program.methods.appendNote(framed.toString())
+    )
+    assert(!namespaced.equals(framedText))
+    const titled = await appendedLeaf(program.methods.appendTitledNote("ns", "note"))
+    assert(!namespaced.equals(titled))
+
+    const bytes = await appendedLeaf(program.methods.appendBytes(Buffer.from("note")))
+    const text = await appendedLeaf(program.methods.appendNote("note"))
+    assert(!bytes.equals(text))
+
+    const { chainTip } = await program.account.noteTreeConfig.fetch(config)
+    const chained = await appendedLeaf(program.methods.appendChainedNote("note"))
+    const prefixedBytes = await appendedLeaf(
+      program.methods.appendBytes(
+        Buffer.concat([Buffer.from(chainTip), Buffer.from("note")])
+      )
+    )
+    assert(!chained.equals(prefixedBytes))
+  })
 })
@@ -2,34 +2,337 @@ import {
   SPL_NOOP_PROGRAM_ID,
   deserializeApplicationDataEvent,
 } from "@solana/spl-account-compression"
-import { Connection } from "@solana/web3.js"
+import { Connection, PublicKey } from "@solana/web3.js"
+import { BN } from "@coral-xyz/anchor"
 import { bs58 } from "@coral-xyz/anchor/dist/cjs/utils/bytes"
 import { deserialize } from "borsh"
+import { inflateRawSync } from "zlib"
+import { keccak256 } from "js-sha3"
 
-class NoteLog {
+export class NoteLog {
+  version: number
   leafNode: Uint8Array
   note: string
+  data: Uint8Array
+  priority: number
+  value: BN
+  genesis: number
+  namespace: string
+  prevLeaf?: Uint8Array
+  title: string
+  rawLeaf: number
+  contentType: number
+  metadata: MetadataEntry[]
+  owner?: Uint8Array
+  leafDomain: Uint8Array
+  createdAt: BN
+  parentLeaf?: Uint8Array
+  refLeaf?: Uint8Array
 
-  constructor(properties: { leafNode: Uint8Array; note: string }) {
+  constructor(properties: {
+    version: number
+    leafNode: Uint8Array
+    note: string
+    data: Uint8Array
+    priority: number
+    value: BN
+    genesis: number
+    namespace: string
+    prevLeaf?: Uint8Array
+    title: string
+    rawLeaf: number
+    contentType: number
+    metadata: MetadataEntry[]
+    owner?: Uint8Array
+    leafDomain: Uint8Array
+    createdAt: BN
+    parentLeaf?: Uint8Array
+    refLeaf?: Uint8Array
+  }) {
+    this.version = properties.version
     this.leafNode = properties.leafNode
     this.note = properties.note
+    this.data = properties.data
+    this.priority = properties.priority
+    this.value = properties.value
+    this.genesis = properties.genesis
+    this.namespace = properties.namespace
+    this.prevLeaf = properties.prevLeaf
+    this.title = properties.title
+    this.rawLeaf = properties.rawLeaf
+    this.contentType = properties.contentType
+    this.metadata = properties.metadata
+    this.owner = properties.owner
+    this.leafDomain = properties.leafDomain
+    this.createdAt = properties.createdAt
+    this.parentLeaf = properties.parentLeaf
+    this.refLeaf = properties.refLeaf
   }
 }
 
+export class MetadataEntry {
+  key: string
+  value: string
+
+  constructor(properties: { key: string; value: string }) {
+    this.key = properties.key
+    this.value = properties.value
+  }
+}
+
+// The defaults of the create_note_tree and create_shared_tree args, matching `TreeConfigInitArgs::default()`.
+// Spread it and override only the settings to change, e.g. `{ ...DEFAULT_TREE_CONFIG_INIT_ARGS, maxDepth: 3 }`
+export const DEFAULT_TREE_CONFIG_INIT_ARGS = {
+  maxDepth: 14,
+  maxBufferSize: 64,
+  hashIterations: 1, // hash each note once
+  gateMint: null, // no gate mint
+  accessMode: { open: {} }, // any payer can append
+  canopyDepth: 0, // the canopy depth the tree account is allocated with
+}
+
+// The compute unit estimates of the program, matching `APPEND_CU_ESTIMATE`, `COMPRESSED_APPEND_CU_ESTIMATE`,
+// `STAGED_APPEND_CU_ESTIMATE`, `UPDATE_CU_ESTIMATE_BASE` and `UPDATE_CU_ESTIMATE_PER_PROOF_NODE`.
+// Pass them to `ComputeBudgetProgram.setComputeUnitLimit`
+export const APPEND_CU_ESTIMATE = 60_000
+export const COMPRESSED_APPEND_CU_ESTIMATE = 400_000
+export const STAGED_APPEND_CU_ESTIMATE = 120_000
+export const UPDATE_CU_ESTIMATE_BASE = 40_000
+export const UPDATE_CU_ESTIMATE_PER_PROOF_NODE = 1_500
+
+// The compute unit estimate of a batch append, mirroring `batch_append_cu_estimate`
+export function batchAppendCuEstimate(noteCount: number) {
+  return APPEND_CU_ESTIMATE * noteCount
+}
+
+// The compute unit estimate of an instruction replacing a leaf, mirroring `update_cu_estimate`
+export function updateCuEstimate(proofLength: number) {
+  return UPDATE_CU_ESTIMATE_BASE + UPDATE_CU_ESTIMATE_PER_PROOF_NODE * proofLength
+}
+
+// The compute unit estimate of swapping two notes, mirroring `swap_cu_estimate`
+export function swapCuEstimate(proofLength: number) {
+  return updateCuEstimate(proofLength) * 2
+}
+
+// Get the compute units consumed by a program from transaction logs, including its CPIs
+export function computeUnitsConsumed(logs: string[], programId: PublicKey) {
+  // The runtime logs "Program <program id> consumed <units> of <limit> compute units" when it returns
+  const prefix = `Program ${programId.toBase58()} consumed `
+  const consumedLog = logs.filter((log) => log.startsWith(prefix)).pop()
+  return Number(consumedLog.slice(prefix.length).split(" ")[0])
+}
+
+// The variants of the program's `ContentType` enum, in order, indexed by a note log's contentType
+export const CONTENT_TYPES = ["text", "markdown", "json", "link"]
+
 // A map that describes the Note structure for Borsh deserialization
-const NoteLogBorshSchema = new Map([
+export const NoteLogBorshSchema = new Map([
   [
     NoteLog,
     {
       kind: "struct",
       fields: [
+        ["version", "u8"], // Layout version of the note log
         ["leafNode", [32]], // Array of 32 `u8`
         ["note", "string"],
+        ["data", ["u8"]], // Vec of `u8`, empty for text notes
+        ["priority", "u8"],
+        ["value", "u64"], // An i64, borsh-js only reads unsigned so convert with `fromTwos(64)`
+        ["genesis", "u8"], // A bool, 1 for the genesis note of a tree
+        ["namespace", "string"], // Empty for notes without a namespace
+        ["prevLeaf", { kind: "option", type: [32] }], // The chain tip a chained note was hashed with
+        ["title", "string"], // Empty for notes without a title
+        ["rawLeaf", "u8"], // A bool, 1 if the note is hex, decoded before hashing it
+        ["contentType", "u8"], // The ContentType variant index, see CONTENT_TYPES
+        ["metadata", [MetadataEntry]], // Vec of key-value entries, empty by default
+        ["owner", { kind: "option", type: [32] }], // The owner a delegated note was authorized by
+        ["leafDomain", [4]], // The domain tag mixed into the leaf node, zero for untagged
+        ["createdAt", "u64"], // An i64 unix timestamp, 0 for notes without one
+        ["parentLeaf", { kind: "option", type: [32] }], // The parent leaf a reply was hashed with
+        ["refLeaf", { kind: "option", type: [32] }], // The referenced leaf a dependent note was hashed with
+      ],
+    },
+  ],
+  [
+    MetadataEntry,
+    {
+      kind: "struct",
+      fields: [
+        ["key", "string"],
+        ["value", "string"],
       ],
     },
   ],
 ])
 
+// Frame a namespace and a note the way the program does for namespaced notes, mirroring `frame_namespaced`
+export function frameNamespaced(namespace: string, note: string) {
+  const namespaceBytes = Buffer.from(namespace)
+  const length = Buffer.alloc(4)
+  length.writeUInt32LE(namespaceBytes.length)
+  return Buffer.concat([length, namespaceBytes, Buffer.from(note)])
+}
+
+// The tag byte hashed before the payload of a leaf node, one per note variant other than text, matching `LEAF_TAG_BYTES` and the rest.
+// None of them can start valid UTF-8, so no text note hashes the payload of a tagged variant
+export const LEAF_TAG_BYTES = 0xf8
+export const LEAF_TAG_NAMESPACED = 0xf9
+export const LEAF_TAG_CHAINED = 0xfa
+export const LEAF_TAG_DELEGATED = 0xfb
+export const LEAF_TAG_TITLED = 0xfc
+export const LEAF_TAG_REPLY = 0xfd
+export const LEAF_TAG_DEPENDENT = 0xfe
+export const LEAF_TAG_RAW = 0xff
+
+// Hash the payload of a note variant into a leaf node, mirroring `compute_bytes_leaf`
+export function leafHash(leafTag: number, payload: Uint8Array, hashIterations = 1) {
+  return iterateLeaf(
+    Buffer.concat([Buffer.from([leafTag]), payload]),
+    hashIterations
+  )
+}
+
+// The leaf node of a text note, mirroring `compute_leaf`. Text notes are hashed without a tag byte
+export function textLeaf(note: string, hashIterations = 1) {
+  return iterateLeaf(Buffer.from(note), hashIterations)
+}
+
+function iterateLeaf(preimage: Uint8Array, hashIterations: number) {
+  let leaf = Buffer.from(keccak256.digest(preimage))
+  for (let i = 1; i < hashIterations; i++) {
+    leaf = Buffer.from(keccak256.digest(leaf))
+  }
+  return leaf
+}
+
+// Reconstruct the leaf node of a note log, mirroring `NoteLog::leaf` in the program.
+// Only the note, or the data of a raw bytes note, is hashed, framed with the namespace or title if it has one,
+// then rehashed for each further hash iteration. Chained notes, replies and dependent notes are hashed once, prefixed with the chain tip, parent leaf or referenced leaf.
+// Delegated notes are prefixed with the owner. Each payload but a text note's is prefixed with the tag byte of its variant, see leafHash
+// The note of a raw leaf note is hex, hashed once after decoding and never domain tagged. The domain tag, if any, is mixed in last
+export function noteLogLeaf(noteLog: NoteLog, hashIterations: number) {
+  if (noteLog.rawLeaf) {
+    return leafHash(LEAF_TAG_RAW, Buffer.from(noteLog.note, "hex"))
+  }
+  return domainLeaf(noteLog.leafDomain, untaggedLeaf(noteLog, hashIterations))
+}
+
+// Mix a domain tag into a leaf node, mirroring `domain_leaf`. The zero tag leaves the leaf node untagged
+export function domainLeaf(leafDomain: Uint8Array, leafNode: Buffer) {
+  if (leafDomain.every((byte) => byte === 0)) {
+    return leafNode
+  }
+  return Buffer.from(
+    keccak256.digest(Buffer.concat([Buffer.from(leafDomain), leafNode]))
+  )
+}
+
+function untaggedLeaf(noteLog: NoteLog, hashIterations: number) {
+  if (noteLog.prevLeaf) {
+    return leafHash(
+      LEAF_TAG_CHAINED,
+      Buffer.concat([Buffer.from(noteLog.prevLeaf), Buffer.from(noteLog.note)])
+    )
+  }
+  if (noteLog.parentLeaf) {
+    return leafHash(
+      LEAF_TAG_REPLY,
+      Buffer.concat([Buffer.from(noteLog.parentLeaf), Buffer.from(noteLog.note)])
+    )
+  }
+  if (noteLog.refLeaf) {
+    return leafHash(
+      LEAF_TAG_DEPENDENT,
+      Buffer.concat([Buffer.from(noteLog.refLeaf), Buffer.from(noteLog.note)])
+    )
+  }
+  if (noteLog.owner) {
+    return leafHash(
+      LEAF_TAG_DELEGATED,
+      Buffer.concat([Buffer.from(noteLog.owner), Buffer.from(noteLog.note)]),
+      hashIterations
+    )
+  } else if (noteLog.namespace.length > 0) {
+    return leafHash(
+      LEAF_TAG_NAMESPACED,
+      frameNamespaced(noteLog.namespace, noteLog.note),
+      hashIterations
+    )
+  } else if (noteLog.title.length > 0) {
+    return leafHash(
+      LEAF_TAG_TITLED,
+      frameNamespaced(noteLog.title, noteLog.note),
+      hashIterations
+    )
+  } else if (noteLog.data.length > 0) {
+    return leafHash(LEAF_TAG_BYTES, noteLog.data, hashIterations)
+  }
+  return textLeaf(noteLog.note, hashIterations)
+}
+
+// Prefix of every note log logged by the program, matching `NOTE_LOG_DISCRIMINATOR`
+export const NOTE_LOG_DISCRIMINATOR = Buffer.from([63, 224, 6, 164])
+
+// The message an owner signs to authorize a delegate's append, mirroring `delegated_message`
+export function delegatedMessage(
+  programId: PublicKey,
+  merkleTree: PublicKey,
+  note: string,
+  delegate: PublicKey,
+  nonce: number
+) {
+  const nonceBytes = Buffer.alloc(8)
+  nonceBytes.writeBigUInt64LE(BigInt(nonce))
+  return Buffer.from(
+    keccak256.digest(
+      Buffer.concat([
+        programId.toBuffer(),
+        merkleTree.toBuffer(),
+        Buffer.from(note),
+        delegate.toBuffer(),
+        nonceBytes,
+      ])
+    )
+  )
+}
+
+// Compute the root of a batch of leaf nodes, mirroring `batch_root` in the program.
+// Pairs are hashed in order level by level, and an odd last node is carried up unhashed
+export function batchRoot(leafNodes: Buffer[]) {
+  let level = leafNodes
+  while (level.length > 1) {
+    const next: Buffer[] = []
+    for (let i = 0; i < level.length; i += 2) {
+      next.push(
+        i + 1 < level.length
+          ? Buffer.from(keccak256.digest(Buffer.concat([level[i], level[i + 1]])))
+          : level[i]
+      )
+    }
+    level = next
+  }
+  return level[0]
+}
+
+// Strip the 4 byte discriminator and the 1 byte compression header from logged application data,
+// inflating the note log if compressed
+export function decodeLogCompression(data: Buffer) {
+  if (!data.subarray(0, 4).equals(NOTE_LOG_DISCRIMINATOR)) {
+    throw new Error("Not a note log")
+  }
+  const compression = data[4]
+  const body = data.subarray(5)
+  switch (compression) {
+    case 0: // None
+      return body
+    case 1: // Deflate
+      return inflateRawSync(body)
+    default:
+      throw new Error(`Unknown note log compression ${compression}`)
+  }
+}
+
 export async function getNoteLog(connection: Connection, txSignature: string) {
   // Confirm the transaction, otherwise the getTransaction sometimes returns null
   const latestBlockHash = await connection.getLatestBlockhash()
@@ -71,7 +374,7 @@ export async function getNoteLog(connection: Connection, txSignature: string) {
       noteLog = deserialize(
         NoteLogBorshSchema,
         NoteLog,
-        Buffer.from(applicationData)
+        decodeLogCompression(Buffer.from(applicationData))
       )
 
       if (noteLog !== undefined) {
@@ -82,3 +385,22 @@ export async function getNoteLog(connection: Connection, txSignature: string) {
 
   return noteLog
 }
+
+// Get the return data set by a program in a confirmed transaction, from the transaction logs
+export async function getReturnData(
+  connection: Connection,
+  txSignature: string,
+  programId: PublicKey
+) {
+  const txInfo = await connection.getTransaction(txSignature, {
+    commitment: "confirmed",
+    maxSupportedTransactionVersion: 0,
+  })
+
+  // The runtime logs return data as "Program return: <program id> <base64 data>"
+  const prefix = `Program return: ${programId.toBase58()} `
+  const returnLog = txInfo!.meta?.logMessages?.find((log) =>
+    log.startsWith(prefix)
+  )
+  return returnLog ? Buffer.from(returnLog.slice(prefix.length), "base64") : null
+}